@@ -76,6 +76,23 @@ class Api {
     return Api(token, baseUrl, me);
   }
 
+  // Загружает все страницы списка, следуя за next_cursor
+  Future<List<dynamic>> getAllPages(String url) async {
+    final headers = {'Authorization': 'Bearer $token'};
+    final List<dynamic> items = [];
+    String? cursor;
+    do {
+      final pageUrl = cursor == null ? url : '$url?after=$cursor';
+      final response = throwResponse(await http.get(Uri.parse(pageUrl), headers: headers));
+      final codeUnits = response.body.codeUnits;
+      final body = Utf8Decoder().convert(codeUnits);
+      final dynamic page = jsonDecode(body);
+      items.addAll(page['items']);
+      cursor = page['next_cursor'];
+    } while (cursor != null);
+    return items;
+  }
+
   Future<UserInfo> getMe() async {
     final url = '$apiUrl/users/me';
     final headers = {'Authorization': 'Bearer $token'};
@@ -91,11 +108,7 @@ class Api {
 
   Future<List<MemberInfo>> getMembers(String queueId) async {
     final url = '$apiUrl/queues/$queueId/members';
-    final headers = {'Authorization': 'Bearer $token'};
-    final response = throwResponse(await http.get(Uri.parse(url), headers: headers));
-    final codeUnits = response.body.codeUnits;
-    final body = Utf8Decoder().convert(codeUnits);
-    final List<dynamic> responseJson = jsonDecode(body);
+    final responseJson = await getAllPages(url);
     return responseJson.map((m) =>
         MemberInfo(
           m['id'],
@@ -124,11 +137,7 @@ class Api {
   // То есть очередей где текущий пользователь участник или администратор
   Future<List<QueueInfo>> getMyQueues() async {
     final url = '$apiUrl/queues';
-    final headers = {'Authorization': 'Bearer $token'};
-    final response = throwResponse(await http.get(Uri.parse(url), headers: headers));
-    final codeUnits = response.body.codeUnits;
-    final body = Utf8Decoder().convert(codeUnits);
-    final List<dynamic> responseJson = jsonDecode(body);
+    final responseJson = await getAllPages(url);
     return responseJson.map((e) =>
         QueueInfo(
           e['id'],
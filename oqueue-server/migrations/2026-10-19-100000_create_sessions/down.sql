@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+drop table "refresh_tokens";
+drop table "sessions";
@@ -0,0 +1,47 @@
+-- Your SQL goes here
+
+create table "sessions" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "created_at" timestamp not null,
+    "last_used_at" timestamp not null,
+    "user_agent" text,
+    "ip" varchar(64),
+    "revoked_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "fk_sessions_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "sessions_user_id_idx" on "sessions" ("user_id");
+
+create table "refresh_tokens" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "session_id" uuid not null,
+    "token_hash" char(64) not null,
+    "created_at" timestamp not null,
+    "expires_at" timestamp not null,
+    "used_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "refresh_tokens_token_hash_unique"
+        unique ("token_hash"),
+
+    constraint "fk_refresh_tokens_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade,
+
+    constraint "fk_refresh_tokens_session_id"
+        foreign key("session_id")
+            references "sessions"("id")
+            on delete cascade
+);
+
+create index "refresh_tokens_session_id_idx" on "refresh_tokens" ("session_id");
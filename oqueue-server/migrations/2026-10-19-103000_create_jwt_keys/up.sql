@@ -0,0 +1,13 @@
+-- Your SQL goes here
+
+create table "jwt_keys" (
+    "kid" varchar(64) not null,
+    "algorithm" varchar(16) not null,
+    -- Base64 of the nonce and the AES-256-GCM sealed HMAC secret, the
+    -- encryption key is JWT_KEY_ENCRYPTION_KEY.
+    "encrypted_secret" text not null,
+    "created_at" timestamp not null,
+    "expires_at" timestamp,
+
+    primary key ("kid")
+);
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+
+create table "password_reset_tokens" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "token_hash" char(64) not null,
+    "created_at" timestamp not null,
+    "expires_at" timestamp not null,
+    "used_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "password_reset_tokens_token_hash_unique"
+        unique ("token_hash"),
+
+    constraint "fk_password_reset_tokens_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "password_reset_tokens_user_id_idx" on "password_reset_tokens" ("user_id");
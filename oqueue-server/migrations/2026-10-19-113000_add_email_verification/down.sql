@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+
+drop table "email_verification_tokens";
+
+ALTER TABLE "queues" DROP COLUMN "require_verified_email";
+
+ALTER TABLE "users" DROP COLUMN "email_verified_at";
@@ -0,0 +1,27 @@
+-- Your SQL goes here
+
+ALTER TABLE "users" ADD COLUMN "email_verified_at" timestamp;
+
+ALTER TABLE "queues" ADD COLUMN "require_verified_email" boolean not null default false;
+
+create table "email_verification_tokens" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "email" varchar not null,
+    "token_hash" char(64) not null,
+    "created_at" timestamp not null,
+    "expires_at" timestamp not null,
+    "used_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "email_verification_tokens_token_hash_unique"
+        unique ("token_hash"),
+
+    constraint "fk_email_verification_tokens_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "email_verification_tokens_user_id_idx" on "email_verification_tokens" ("user_id");
@@ -0,0 +1,19 @@
+-- This file should undo anything in `up.sql`
+
+-- Argon2 hashes don't fit into char(60), the migration can't be reverted
+-- once any of them is stored.
+DO $$
+DECLARE
+    emails text;
+BEGIN
+    SELECT string_agg("email", ', ' ORDER BY "email") INTO emails
+    FROM "users"
+    WHERE length("pwhash") > 60;
+
+    IF emails IS NOT NULL THEN
+        RAISE EXCEPTION 'Password hashes longer than 60 characters of: %', emails
+            USING HINT = 'Restore a backup taken before the upgrade instead.';
+    END IF;
+END $$;
+
+ALTER TABLE "users" ALTER COLUMN "pwhash" TYPE char(60);
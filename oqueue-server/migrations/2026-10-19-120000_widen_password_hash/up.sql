@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Room for argon2 hashes, bcrypt ones are exactly 60 characters
+ALTER TABLE "users" ALTER COLUMN "pwhash" TYPE varchar(255);
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX "users_email_lower_unique";
+
+ALTER TABLE "users"
+    ADD CONSTRAINT "users_email_unique" UNIQUE ("email");
+
+DROP INDEX "users_username_lower_unique";
+
+ALTER TABLE "users" DROP COLUMN "username";
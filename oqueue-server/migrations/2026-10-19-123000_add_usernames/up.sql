@@ -0,0 +1,33 @@
+-- Your SQL goes here
+
+ALTER TABLE "users" ADD COLUMN "username" varchar(32);
+
+CREATE UNIQUE INDEX "users_username_lower_unique" ON "users" (lower("username"));
+
+-- Emails are stored normalized since sign up validates them, older rows
+-- may still be mixed case. Accounts whose emails differ only in case can't
+-- be merged automatically, they have to be resolved by hand first, see the
+-- README.
+DO $$
+DECLARE
+    duplicates text;
+BEGIN
+    SELECT string_agg("emails", '; ') INTO duplicates
+    FROM (
+        SELECT string_agg("email", ', ' ORDER BY "email") AS "emails"
+        FROM "users"
+        GROUP BY lower(trim("email"))
+        HAVING count(*) > 1
+    ) AS "d";
+
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Accounts with emails differing only in case: %', duplicates
+            USING HINT = 'Change the email of all but one account of each group or delete them, then run the migrations again.';
+    END IF;
+END $$;
+
+UPDATE "users" SET "email" = lower(trim("email")) WHERE "email" <> lower(trim("email"));
+
+ALTER TABLE "users" DROP CONSTRAINT "users_email_unique";
+
+CREATE UNIQUE INDEX "users_email_lower_unique" ON "users" (lower("email"));
@@ -0,0 +1,25 @@
+-- Your SQL goes here
+
+create table "api_tokens" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "name" varchar(64) not null,
+    "token_hash" char(64) not null,
+    "scope" varchar(32) not null,
+    "created_at" timestamp not null,
+    "last_used_at" timestamp,
+    "expires_at" timestamp,
+    "revoked_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "api_tokens_token_hash_unique"
+        unique ("token_hash"),
+
+    constraint "fk_api_tokens_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "api_tokens_user_id_idx" on "api_tokens" ("user_id");
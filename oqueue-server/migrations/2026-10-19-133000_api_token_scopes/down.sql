@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+
+alter table "api_tokens" add column "scope" varchar(32) not null default 'read-only';
+
+update "api_tokens" set "scope" = 'queue-manage'
+where "scopes" like '%queues:write%';
+
+alter table "api_tokens" alter column "scope" drop default;
+alter table "api_tokens" drop column "scopes";
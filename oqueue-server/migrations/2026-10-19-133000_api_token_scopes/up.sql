@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+alter table "api_tokens" add column "scopes" varchar(255) not null default '';
+
+update "api_tokens" set "scopes" = case "scope"
+    when 'queue-manage' then 'queues:read queues:write members:manage profile:read'
+    else 'queues:read profile:read'
+end;
+
+alter table "api_tokens" alter column "scopes" drop default;
+alter table "api_tokens" drop column "scope";
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+
+create table "user_identities" (
+    "id" uuid not null,
+    "user_id" uuid not null,
+    "provider" varchar(64) not null,
+    "subject" varchar(255) not null,
+    "email" varchar(255),
+    "created_at" timestamp not null,
+
+    primary key ("id"),
+
+    constraint "user_identities_provider_subject_unique"
+        unique ("provider", "subject"),
+
+    constraint "fk_user_identities_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "user_identities_user_id_idx" on "user_identities" ("user_id");
@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+
+delete from "queue_entries" where "user_id" is null;
+
+alter table "queue_entries" drop constraint "queue_entries_member_check";
+alter table "queue_entries" drop constraint "fk_guest_id";
+alter table "queue_entries" drop constraint "queue_entries_guest_id_unique";
+alter table "queue_entries" drop constraint "queue_entries_queue_id_user_id_unique";
+alter table "queue_entries" drop column "guest_id";
+alter table "queue_entries" alter column "user_id" set not null;
+alter table "queue_entries" drop constraint "queue_entries_pkey";
+alter table "queue_entries" drop column "id";
+alter table "queue_entries" add primary key ("queue_id", "user_id");
+
+alter table "queues" drop column "allow_guests";
+
+drop table "guests";
@@ -0,0 +1,48 @@
+-- Your SQL goes here
+
+-- gen_random_uuid() is built in since PostgreSQL 13
+create extension if not exists "pgcrypto";
+
+create table "guests" (
+    "id" uuid not null,
+    "queue_id" uuid not null,
+    "name" varchar(64) not null,
+    "created_at" timestamp not null,
+
+    primary key ("id"),
+
+    constraint "fk_guests_queue_id"
+        foreign key("queue_id")
+            references "queues"("id")
+            on delete cascade
+);
+
+create index "guests_queue_id_idx" on "guests" ("queue_id");
+
+alter table "queues" add column "allow_guests" boolean not null default false;
+
+-- Entries belong either to a user or to a guest
+alter table "queue_entries" drop constraint "queue_entries_pkey";
+alter table "queue_entries" add column "id" uuid not null default gen_random_uuid();
+alter table "queue_entries" alter column "id" drop default;
+alter table "queue_entries" add primary key ("id");
+alter table "queue_entries" alter column "user_id" drop not null;
+alter table "queue_entries" add column "guest_id" uuid;
+
+alter table "queue_entries"
+    add constraint "queue_entries_queue_id_user_id_unique"
+        unique ("queue_id", "user_id");
+
+alter table "queue_entries"
+    add constraint "queue_entries_guest_id_unique"
+        unique ("guest_id");
+
+alter table "queue_entries"
+    add constraint "fk_guest_id"
+        foreign key("guest_id")
+            references "guests"("id")
+            on delete cascade;
+
+alter table "queue_entries"
+    add constraint "queue_entries_member_check"
+        check (("user_id" is null) <> ("guest_id" is null));
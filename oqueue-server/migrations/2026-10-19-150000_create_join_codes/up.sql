@@ -0,0 +1,24 @@
+-- Your SQL goes here
+
+create table "join_codes" (
+    "id" uuid not null,
+    "queue_id" uuid not null,
+    "code" char(6) not null,
+    "created_at" timestamp not null,
+    "expires_at" timestamp,
+    "max_uses" int,
+    "uses" int not null default 0,
+    "revoked_at" timestamp,
+
+    primary key ("id"),
+
+    constraint "join_codes_code_unique"
+        unique ("code"),
+
+    constraint "fk_join_codes_queue_id"
+        foreign key("queue_id")
+            references "queues"("id")
+            on delete cascade
+);
+
+create index "join_codes_queue_id_idx" on "join_codes" ("queue_id");
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+drop index "queues_search_idx";
+
+alter table "queues" drop column "is_public";
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+alter table "queues" add column "is_public" boolean not null default false;
+
+-- Same expression as the search in `db::actions`, otherwise the index is
+-- not used. The `simple` configuration doesn't stem, names are in any
+-- language.
+create index "queues_search_idx" on "queues" using gin ((
+    setweight(to_tsvector('simple', "name"), 'A') ||
+    setweight(to_tsvector('simple', "description"), 'B')
+));
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+
+drop trigger "queue_entries_touch_queue" on "queue_entries";
+drop function "queue_entries_touch_queue"();
+
+drop trigger "queues_touch" on "queues";
+drop function "queues_touch"();
+
+alter table "queues" drop column "updated_at";
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+
+alter table "queues" add column "updated_at" timestamp;
+update "queues" set "updated_at" = "created_at";
+alter table "queues" alter column "updated_at" set not null;
+
+-- Timestamps are UTC without a time zone, as written by the server.
+create function "queues_touch"() returns trigger as $$
+begin
+    new."updated_at" := now() at time zone 'utc';
+    return new;
+end;
+$$ language plpgsql;
+
+create trigger "queues_touch"
+    before update on "queues"
+    for each row execute function "queues_touch"();
+
+-- Joining, leaving and moving in the queue change it too.
+create function "queue_entries_touch_queue"() returns trigger as $$
+begin
+    update "queues" set "updated_at" = now() at time zone 'utc'
+        where "id" = coalesce(new."queue_id", old."queue_id");
+    return null;
+end;
+$$ language plpgsql;
+
+create trigger "queue_entries_touch_queue"
+    after insert or update or delete on "queue_entries"
+    for each row execute function "queue_entries_touch_queue"();
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+drop table "join_requests";
+
+alter table "queues" drop column "require_approval";
@@ -0,0 +1,27 @@
+-- Your SQL goes here
+
+alter table "queues" add column "require_approval" boolean not null default false;
+
+create table "join_requests" (
+    "id" uuid not null,
+    "queue_id" uuid not null,
+    "user_id" uuid not null,
+    "requested_at" timestamp not null,
+
+    primary key ("id"),
+
+    constraint "join_requests_queue_id_user_id_unique"
+        unique ("queue_id", "user_id"),
+
+    constraint "fk_queue_id"
+        foreign key("queue_id")
+            references "queues"("id")
+            on delete cascade,
+
+    constraint "fk_user_id"
+        foreign key("user_id")
+            references "users"("id")
+            on delete cascade
+);
+
+create index "join_requests_user_id_idx" on "join_requests" ("user_id");
@@ -0,0 +1,55 @@
+use chrono::{Duration, NaiveDateTime};
+
+use crate::auth::scope::Scope;
+use crate::auth::token::{generate_token, hash_token};
+use crate::auth::{Auth, Claims};
+use crate::db::DbService;
+use crate::error::ApiError;
+
+/// Tells API tokens apart from JWTs and makes them easy to find by secret
+/// scanners.
+pub const PREFIX: &str = "oq_";
+
+/// `last_used_at` is not updated more often than this.
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(PREFIX)
+}
+
+/// Generates a new token and the hash to store.
+pub fn generate() -> (String, String) {
+    let token = format!("{}{}", PREFIX, generate_token());
+    let token_hash = hash_token(&token);
+    (token, token_hash)
+}
+
+/// Finds the user of an active API token.
+pub fn authenticate(token: &str, db: &DbService, now: NaiveDateTime) -> Result<Auth, ApiError> {
+    let dao = db
+        .api_token_by_hash(&hash_token(token))?
+        .ok_or(ApiError::InvalidToken)?;
+    if dao.revoked_at.is_some() {
+        return Err(ApiError::InvalidToken);
+    }
+    if dao.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApiError::TokenExpired);
+    }
+
+    let is_stale = dao
+        .last_used_at
+        .is_none_or(|used| now - used >= Duration::seconds(TOUCH_INTERVAL_SECS));
+    if is_stale {
+        db.touch_api_token(&dao.id, now)?;
+    }
+
+    Ok(Auth {
+        typ: Auth::TYP.to_string(),
+        id: dao.user_id,
+        jti: dao.id,
+        exp: dao
+            .expires_at
+            .map_or(u64::MAX, |expires_at| expires_at.and_utc().timestamp().max(0) as u64),
+        scopes: Scope::parse_list(&dao.scopes),
+    })
+}
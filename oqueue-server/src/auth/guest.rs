@@ -0,0 +1,89 @@
+use std::future::Ready;
+use std::ops::Add;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::{Payload, PayloadStream, ServiceRequest};
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use jsonwebtoken::errors::ErrorKind;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::auth::{decode_claims, Claims, JwtConfig};
+use crate::db::DbService;
+use crate::error::ApiError;
+
+/// Signed ticket of a guest, given on joining a queue without an account.
+/// Its `typ` differs from [`Auth`](crate::auth::Auth), so it is not accepted
+/// as an access token and access tokens are not accepted as tickets.
+#[derive(Eq, PartialEq, Clone, Hash, Serialize, Deserialize, Debug)]
+pub struct GuestTicket {
+    /// Always [`GuestTicket::TYP`].
+    pub typ: String,
+    pub guest: Uuid,
+    pub queue: Uuid,
+    pub exp: u64,
+}
+
+impl GuestTicket {
+    pub fn new(guest: Uuid, queue: Uuid, ttl: Duration) -> Self {
+        let exp = SystemTime::now()
+            .add(ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        GuestTicket {
+            typ: Self::TYP.to_string(),
+            guest,
+            queue,
+            exp,
+        }
+    }
+}
+
+impl Claims for GuestTicket {
+    const TYP: &'static str = "guest";
+
+    fn typ(&self) -> &str {
+        &self.typ
+    }
+}
+
+impl FromRequest for GuestTicket {
+    type Config = ();
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
+        let ticket = req
+            .extensions()
+            .get::<GuestTicket>()
+            .cloned()
+            .ok_or(ApiError::Unauthorized);
+        std::future::ready(ticket)
+    }
+}
+
+pub async fn ticket_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error> {
+    let config = req.app_data::<Data<JwtConfig>>().unwrap().get_ref();
+    let db = req.app_data::<Data<DbService>>().unwrap().get_ref();
+
+    if let Ok(Some(kid)) = jsonwebtoken::decode_header(credentials.token()).map(|h| h.kid) {
+        config.keyring.reload_for_unknown_kid(&kid, db);
+    }
+
+    let ticket = decode_claims::<GuestTicket>(credentials.token(), config).map_err(|e| {
+        log::debug!("guest ticket rejected: {:?}", e);
+        match e.kind() {
+            ErrorKind::ExpiredSignature => ApiError::TokenExpired,
+            _ => ApiError::InvalidToken,
+        }
+    })?;
+
+    req.extensions_mut().insert(ticket);
+    Ok(req)
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use serde::Serialize;
+use simple_asn1::{oid, ASN1Block};
+
+// ---
+// Jwk
+// ---
+
+/// Public part of an asymmetric signing key in the JSON Web Key format
+/// (RFC 7517). Symmetric keys are never published.
+#[derive(Clone, Debug, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// The file is neither a PEM nor a DER encoded key.
+    InvalidFormat,
+    /// The key can't be used with the algorithm.
+    WrongKeyType(Algorithm),
+    /// HMAC algorithms use secrets, not key files.
+    Symmetric(Algorithm),
+    /// The private key was rejected or doesn't match the public one.
+    InvalidKeyPair,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::InvalidFormat => f.write_str("key is not PEM or DER encoded"),
+            KeyError::WrongKeyType(alg) => write!(f, "key can't be used with {:?}", alg),
+            KeyError::Symmetric(alg) => write!(f, "{:?} doesn't use key files", alg),
+            KeyError::InvalidKeyPair => f.write_str("private and public keys don't match"),
+        }
+    }
+}
+
+enum Family {
+    Rsa,
+    Ec { crv: &'static str, size: usize },
+    Ed,
+}
+
+fn family(algorithm: Algorithm) -> Result<Family, KeyError> {
+    match algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => Ok(Family::Rsa),
+        Algorithm::ES256 => Ok(Family::Ec { crv: "P-256", size: 32 }),
+        Algorithm::ES384 => Ok(Family::Ec { crv: "P-384", size: 48 }),
+        Algorithm::EdDSA => Ok(Family::Ed),
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            Err(KeyError::Symmetric(algorithm))
+        }
+    }
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// -----------
+// Private key
+// -----------
+
+/// Reads a PEM (PKCS#1 or PKCS#8) or DER (PKCS#1 for RSA, PKCS#8 otherwise)
+/// encoded private key.
+pub fn encoding_key(algorithm: Algorithm, data: &[u8]) -> Result<EncodingKey, KeyError> {
+    let key = match (family(algorithm)?, is_pem(data)) {
+        (Family::Rsa, true) => EncodingKey::from_rsa_pem(data),
+        (Family::Ec { .. }, true) => EncodingKey::from_ec_pem(data),
+        (Family::Ed, true) => EncodingKey::from_ed_pem(data),
+        (Family::Rsa, false) => Ok(EncodingKey::from_rsa_der(data)),
+        (Family::Ec { .. }, false) => Ok(EncodingKey::from_ec_der(data)),
+        (Family::Ed, false) => Ok(EncodingKey::from_ed_der(data)),
+    };
+    key.map_err(|_| KeyError::WrongKeyType(algorithm))
+}
+
+// ----------
+// Public key
+// ----------
+
+/// Reads a PEM or DER encoded public key, either SubjectPublicKeyInfo or
+/// PKCS#1 for RSA, and returns it together with its JWK.
+pub fn public_key(
+    kid: &str,
+    algorithm: Algorithm,
+    data: &[u8],
+) -> Result<(DecodingKey, Jwk), KeyError> {
+    let der = if is_pem(data) {
+        pem::parse(data).map_err(|_| KeyError::InvalidFormat)?.contents
+    } else {
+        data.to_vec()
+    };
+    let blocks = simple_asn1::from_der(&der).map_err(|_| KeyError::InvalidFormat)?;
+    let fields = match blocks.as_slice() {
+        [ASN1Block::Sequence(_, fields)] => fields,
+        _ => return Err(KeyError::InvalidFormat),
+    };
+
+    let mut jwk = Jwk {
+        kty: "",
+        kid: kid.to_string(),
+        alg: format!("{:?}", algorithm),
+        key_use: "sig",
+        crv: None,
+        n: None,
+        e: None,
+        x: None,
+        y: None,
+    };
+
+    // PKCS#1 RSAPublicKey
+    if let [ASN1Block::Integer(..), ASN1Block::Integer(..)] = fields.as_slice() {
+        return match family(algorithm)? {
+            Family::Rsa => rsa_public_key(fields, jwk),
+            _ => Err(KeyError::WrongKeyType(algorithm)),
+        };
+    }
+
+    let (key_oid, key) = match fields.as_slice() {
+        [ASN1Block::Sequence(_, id), ASN1Block::BitString(_, _, key)] => match id.first() {
+            Some(ASN1Block::ObjectIdentifier(_, key_oid)) => (key_oid, key),
+            _ => return Err(KeyError::InvalidFormat),
+        },
+        _ => return Err(KeyError::InvalidFormat),
+    };
+
+    match family(algorithm)? {
+        Family::Rsa if *key_oid == oid!(1, 2, 840, 113_549, 1, 1, 1) => {
+            match simple_asn1::from_der(key).as_deref() {
+                Ok([ASN1Block::Sequence(_, fields)]) => rsa_public_key(fields, jwk),
+                _ => Err(KeyError::InvalidFormat),
+            }
+        }
+        // Uncompressed point: 0x04 || x || y
+        Family::Ec { crv, size }
+            if *key_oid == oid!(1, 2, 840, 10_045, 2, 1)
+                && key.len() == 1 + 2 * size
+                && key[0] == 4 =>
+        {
+            jwk.kty = "EC";
+            jwk.crv = Some(crv);
+            jwk.x = Some(b64(&key[1..=size]));
+            jwk.y = Some(b64(&key[1 + size..]));
+            Ok((DecodingKey::from_ec_der(key), jwk))
+        }
+        Family::Ed if *key_oid == oid!(1, 3, 101, 112) && key.len() == 32 => {
+            jwk.kty = "OKP";
+            jwk.crv = Some("Ed25519");
+            jwk.x = Some(b64(key));
+            Ok((DecodingKey::from_ed_der(key), jwk))
+        }
+        _ => Err(KeyError::WrongKeyType(algorithm)),
+    }
+}
+
+fn rsa_public_key(fields: &[ASN1Block], mut jwk: Jwk) -> Result<(DecodingKey, Jwk), KeyError> {
+    let (n, e) = match fields {
+        [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => {
+            (n.to_bytes_be().1, e.to_bytes_be().1)
+        }
+        _ => return Err(KeyError::InvalidFormat),
+    };
+    jwk.kty = "RSA";
+    jwk.n = Some(b64(&n));
+    jwk.e = Some(b64(&e));
+    Ok((DecodingKey::from_rsa_raw_components(&n, &e), jwk))
+}
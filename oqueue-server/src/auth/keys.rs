@@ -0,0 +1,422 @@
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use log::{error, info};
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use uuid::Uuid;
+
+use crate::auth::jwk::{self, Jwk, JwkSet, KeyError};
+use crate::db::models::JwtKeyDao;
+use crate::db::DbService;
+
+/// Minimal interval between reloads caused by tokens with an unknown `kid`.
+const UNKNOWN_KID_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+const GENERATED_SECRET_BYTES: usize = 64;
+
+// ------
+// JwtKey
+// ------
+
+#[derive(Clone)]
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    pub created_at: NaiveDateTime,
+    /// Set once the key is retired. Tokens signed with it are accepted until then.
+    pub expires_at: Option<NaiveDateTime>,
+    /// Published public key, `None` for HMAC secrets.
+    pub jwk: Option<Jwk>,
+}
+
+impl JwtKey {
+    fn from_dao(dao: &JwtKeyDao, cipher: &SecretCipher) -> Option<JwtKey> {
+        let algorithm = Algorithm::from_str(&dao.algorithm).ok()?;
+        let secret = cipher.open(&dao.kid, &dao.encrypted_secret)?;
+        let encoding_key = EncodingKey::from_secret(&secret);
+        let decoding_key = DecodingKey::from_secret(&secret);
+        Some(JwtKey {
+            kid: dao.kid.clone(),
+            algorithm,
+            encoding_key,
+            decoding_key,
+            created_at: dao.created_at,
+            expires_at: dao.expires_at,
+            jwk: None,
+        })
+    }
+
+    /// Builds an asymmetric key from the contents of its private and public
+    /// key files.
+    pub fn from_key_files(
+        kid: String,
+        algorithm: Algorithm,
+        private_key: &[u8],
+        public_key: &[u8],
+    ) -> Result<JwtKey, KeyError> {
+        let encoding_key = jwk::encoding_key(algorithm, private_key)?;
+        let (decoding_key, jwk) = jwk::public_key(&kid, algorithm, public_key)?;
+
+        // DER private keys are only parsed when signing, so sign once here
+        // which also checks that both keys belong together.
+        let probe = kid.as_bytes();
+        let signature = jsonwebtoken::crypto::sign(probe, &encoding_key, algorithm)
+            .map_err(|_| KeyError::InvalidKeyPair)?;
+        let is_valid = jsonwebtoken::crypto::verify(&signature, probe, &decoding_key, algorithm)
+            .map_err(|_| KeyError::InvalidKeyPair)?;
+        if !is_valid {
+            return Err(KeyError::InvalidKeyPair);
+        }
+
+        Ok(JwtKey {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            created_at: Utc::now().naive_utc(),
+            expires_at: None,
+            jwk: Some(jwk),
+        })
+    }
+
+    fn is_valid_at(&self, now: NaiveDateTime) -> bool {
+        self.expires_at.is_none_or(|exp| exp > now)
+    }
+}
+
+#[derive(Debug)]
+pub enum RotationError {
+    UnsupportedAlgorithm(Algorithm),
+    /// Generated secrets are not stored without `JWT_KEY_ENCRYPTION_KEY`.
+    NoEncryptionKey,
+    Db(crate::db::Error),
+}
+
+impl From<crate::db::Error> for RotationError {
+    fn from(e: crate::db::Error) -> Self {
+        RotationError::Db(e)
+    }
+}
+
+// ------------
+// SecretCipher
+// ------------
+
+/// Encrypts generated HMAC secrets before they are stored in the database.
+/// The `kid` is authenticated along, so a secret can't be moved to another
+/// key.
+pub struct SecretCipher {
+    key: LessSafeKey,
+}
+
+impl SecretCipher {
+    /// `key` is 32 bytes in base64.
+    pub fn from_base64(key: &str) -> Option<SecretCipher> {
+        let bytes = base64::decode(key).ok()?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).ok()?;
+        Some(SecretCipher {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// Base64 of the random nonce followed by the ciphertext and its tag.
+    fn seal(&self, kid: &str, secret: &[u8]) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = secret.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(kid.as_bytes()),
+                &mut sealed,
+            )
+            .expect("Can not encrypt JWT secret");
+        base64::encode([&nonce[..], &sealed].concat())
+    }
+
+    fn open(&self, kid: &str, sealed: &str) -> Option<Vec<u8>> {
+        let bytes = base64::decode(sealed).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut in_out = sealed.to_vec();
+        let secret = self
+            .key
+            .open_in_place(nonce, Aad::from(kid.as_bytes()), &mut in_out)
+            .ok()?;
+        Some(secret.to_vec())
+    }
+}
+
+// -------
+// Keyring
+// -------
+
+/// Keys used to sign and verify access tokens.
+///
+/// The configured key never expires by itself. Keys created by [`Keyring::rotate`]
+/// are stored in the database so every instance sees them, and the newest one
+/// is used for signing. A retired key stays valid for verification until the
+/// tokens signed with it expire.
+pub struct Keyring {
+    configured: JwtKey,
+    /// Rotation is off without it.
+    cipher: Option<SecretCipher>,
+    rotated: RwLock<Vec<JwtKey>>,
+    last_reload: Mutex<Option<Instant>>,
+}
+
+impl Keyring {
+    pub fn new(configured: JwtKey, cipher: Option<SecretCipher>) -> Self {
+        Keyring {
+            configured,
+            cipher,
+            rotated: RwLock::new(Vec::new()),
+            last_reload: Mutex::new(None),
+        }
+    }
+
+    /// Key for new tokens: the newest active rotated key or the configured one.
+    pub fn signing_key(&self) -> JwtKey {
+        let rotated = self.rotated.read().unwrap();
+        rotated
+            .iter()
+            .filter(|k| k.expires_at.is_none())
+            .max_by_key(|k| k.created_at)
+            .unwrap_or(&self.configured)
+            .clone()
+    }
+
+    /// Key for a token with the given `kid` header. Tokens without `kid` were
+    /// signed with the configured key.
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<JwtKey> {
+        let kid = match kid {
+            None => return Some(self.configured.clone()),
+            Some(kid) => kid,
+        };
+        if kid == self.configured.kid {
+            return Some(self.configured.clone());
+        }
+        let now = Utc::now().naive_utc();
+        let rotated = self.rotated.read().unwrap();
+        rotated
+            .iter()
+            .find(|k| k.kid == kid && k.is_valid_at(now))
+            .cloned()
+    }
+
+    pub fn keys(&self) -> Vec<JwtKey> {
+        let mut keys = vec![self.configured.clone()];
+        keys.extend(self.rotated.read().unwrap().iter().cloned());
+        keys
+    }
+
+    /// Public keys that tokens can currently be verified with.
+    pub fn jwks(&self) -> JwkSet {
+        let now = Utc::now().naive_utc();
+        let keys = self
+            .keys()
+            .into_iter()
+            .filter(|k| k.is_valid_at(now))
+            .filter_map(|k| k.jwk)
+            .collect();
+        JwkSet { keys }
+    }
+
+    /// Loads rotated keys from the database.
+    pub fn reload(&self, db: &DbService) -> crate::db::Result<()> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(()),
+        };
+        let now = Utc::now().naive_utc();
+        let keys = db
+            .jwt_keys_valid_at(now)?
+            .iter()
+            .filter_map(|dao| {
+                let key = JwtKey::from_dao(dao, cipher);
+                if key.is_none() {
+                    error!("JWT key {} can not be loaded", dao.kid);
+                }
+                key
+            })
+            .collect();
+        *self.rotated.write().unwrap() = keys;
+        *self.last_reload.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Reloads keys when a token refers to an unknown `kid`, e.g. after
+    /// another instance has rotated them. Reloads are throttled.
+    pub fn reload_for_unknown_kid(&self, kid: &str, db: &DbService) {
+        if self.verification_key(Some(kid)).is_some() {
+            return;
+        }
+        let is_stale = self
+            .last_reload
+            .lock()
+            .unwrap()
+            .is_none_or(|t| t.elapsed() >= UNKNOWN_KID_RELOAD_INTERVAL);
+        if is_stale {
+            if let Err(e) = self.reload(db) {
+                error!("{:?}", e);
+            }
+        }
+    }
+
+    /// Creates a new signing key. The previous ones stay valid for `grace`.
+    pub fn rotate(
+        &self,
+        db: &DbService,
+        algorithm: Algorithm,
+        grace: Duration,
+    ) -> Result<String, RotationError> {
+        let kid = self.rotate_unless_created_after(db, algorithm, grace, None)?;
+        Ok(kid.expect("Rotation is unconditional"))
+    }
+
+    /// Rotates the signing key once it is older than `interval` and removes
+    /// keys that are no longer valid. Safe to run on every instance, the
+    /// key is only rotated by the first one.
+    pub fn maintain(
+        &self,
+        db: &DbService,
+        algorithm: Algorithm,
+        interval: Duration,
+        grace: Duration,
+    ) -> Result<(), RotationError> {
+        self.reload(db)?;
+        let now = Utc::now().naive_utc();
+        db.delete_expired_jwt_keys(now)?;
+
+        let signing_key = self.signing_key();
+        let is_configured = signing_key.kid == self.configured.kid;
+        let age = (now - signing_key.created_at).to_std().unwrap_or_default();
+        if is_configured || age >= interval {
+            let rotate_before = now - chrono::Duration::from_std(interval).unwrap();
+            self.rotate_unless_created_after(db, algorithm, grace, Some(rotate_before))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the new `kid`, or `None` when another instance has created a
+    /// key after `unless_created_after`.
+    fn rotate_unless_created_after(
+        &self,
+        db: &DbService,
+        algorithm: Algorithm,
+        grace: Duration,
+        unless_created_after: Option<NaiveDateTime>,
+    ) -> Result<Option<String>, RotationError> {
+        let cipher = self.cipher.as_ref().ok_or(RotationError::NoEncryptionKey)?;
+        let secret = match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let mut bytes = [0u8; GENERATED_SECRET_BYTES];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                bytes
+            }
+            _ => return Err(RotationError::UnsupportedAlgorithm(algorithm)),
+        };
+
+        let now = Utc::now().naive_utc();
+        let kid = Uuid::new_v4().to_simple().to_string();
+        let dao = JwtKeyDao {
+            encrypted_secret: cipher.seal(&kid, &secret),
+            kid,
+            algorithm: format!("{:?}", algorithm),
+            created_at: now,
+            expires_at: None,
+        };
+        let retire_at = now + chrono::Duration::from_std(grace).unwrap();
+        let is_added = db.add_jwt_key(&dao, retire_at, unless_created_after)?;
+        self.reload(db)?;
+
+        if !is_added {
+            return Ok(None);
+        }
+        info!("JWT signing key rotated, new kid {}", dao.kid);
+        Ok(Some(dao.kid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{decode_token, encode_token, Auth, JwtConfig};
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn cipher(key: &str) -> SecretCipher {
+        SecretCipher::from_base64(key).unwrap()
+    }
+
+    fn hmac_key(kid: &str, secret: &[u8], expires_at: Option<NaiveDateTime>) -> JwtKey {
+        JwtKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            created_at: Utc::now().naive_utc(),
+            expires_at,
+            jwk: None,
+        }
+    }
+
+    #[test]
+    fn secret_round_trips() {
+        let cipher = cipher(KEY);
+        let sealed = cipher.seal("kid", b"secret");
+        assert_eq!(cipher.open("kid", &sealed).unwrap(), b"secret");
+        // Fresh nonce each time
+        assert_ne!(cipher.seal("kid", b"secret"), sealed);
+    }
+
+    #[test]
+    fn secret_opens_only_with_same_key_and_kid() {
+        let sealed = cipher(KEY).seal("kid", b"secret");
+        let other = base64::encode([7u8; 32]);
+        assert!(cipher(&other).open("kid", &sealed).is_none());
+        assert!(cipher(KEY).open("other-kid", &sealed).is_none());
+        assert!(cipher(KEY).open("kid", &sealed[4..]).is_none());
+        assert!(cipher(KEY).open("kid", "").is_none());
+    }
+
+    #[test]
+    fn cipher_key_has_32_bytes() {
+        assert!(SecretCipher::from_base64(&base64::encode([7u8; 16])).is_none());
+        assert!(SecretCipher::from_base64("not base64!").is_none());
+    }
+
+    #[test]
+    fn retired_key_verifies_during_grace() {
+        let config = JwtConfig {
+            keyring: Keyring::new(hmac_key("configured", b"configured", None), Some(cipher(KEY))),
+            ..crate::auth::tests::config()
+        };
+        let auth = Auth::new(Uuid::new_v4(), Uuid::new_v4(), Duration::from_secs(300));
+        config.keyring.rotated.write().unwrap().push(hmac_key("old", b"old", None));
+        let token = encode_token(&auth, &config).unwrap();
+        assert_eq!(config.keyring.signing_key().kid, "old");
+
+        // Rotated, the old key is retired in a minute
+        let now = Utc::now().naive_utc();
+        *config.keyring.rotated.write().unwrap() = vec![
+            hmac_key("old", b"old", Some(now + chrono::Duration::minutes(1))),
+            hmac_key("new", b"new", None),
+        ];
+        assert_eq!(config.keyring.signing_key().kid, "new");
+        assert_eq!(decode_token(&token, &config).unwrap().id, auth.id);
+
+        // The grace period is over
+        config.keyring.rotated.write().unwrap()[0].expires_at = Some(now);
+        assert!(decode_token(&token, &config).is_err());
+    }
+}
@@ -1,63 +1,160 @@
+use std::fmt::Debug;
 use std::future::Ready;
-use std::marker::PhantomData;
+use std::ops::Add;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use actix_web::dev::{Payload, PayloadStream, ServiceRequest};
-use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
-use actix_web::web::Data;
-use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::web::{self, Data};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use chrono::Utc;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, Header, Validation};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use log;
+
+use crate::auth::scope::Scope;
+use crate::auth::keys::Keyring;
+use crate::auth::session::SessionCache;
+use crate::auth::token::hash_token;
+use crate::db::DbService;
+use crate::error::ApiError;
+
+pub mod api_token;
+pub mod guest;
+pub mod jwk;
+pub mod keys;
+pub mod oidc;
+pub mod password;
+pub mod scope;
+pub mod session;
+pub mod throttle;
+pub mod token;
+
+/// Claims of the tokens signed with our keys. They all say what they are in
+/// `typ`, so one kind is never accepted as another.
+pub trait Claims: DeserializeOwned + Debug {
+    const TYP: &'static str;
+
+    fn typ(&self) -> &str;
+}
 
 #[derive(Eq, PartialEq, Clone, Hash, Serialize, Deserialize, Debug)]
 pub struct Auth {
+    /// Always [`Auth::TYP`].
+    pub typ: String,
     pub id: Uuid,
+    /// Id of the session the token was issued for, or of the API token.
+    pub jti: Uuid,
     pub exp: u64,
+    /// Tokens issued before scopes were introduced can do everything.
+    #[serde(default = "Scope::all")]
+    pub scopes: Vec<Scope>,
+}
+
+impl Auth {
+    pub fn new(id: Uuid, jti: Uuid, ttl: Duration) -> Self {
+        let exp = SystemTime::now()
+            .add(ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Auth {
+            typ: Self::TYP.to_string(),
+            id,
+            jti,
+            exp,
+            scopes: Scope::all(),
+        }
+    }
+}
+
+impl Claims for Auth {
+    const TYP: &'static str = "access";
+
+    fn typ(&self) -> &str {
+        &self.typ
+    }
 }
 
 impl FromRequest for Auth {
     type Config = ();
-    type Error = Error;
+    type Error = ApiError;
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload<PayloadStream>) -> Self::Future {
         let auth = req
-            .extensions_mut()
-            .remove::<Auth>()
-            .ok_or_else(|| ErrorUnauthorized(""));
+            .extensions()
+            .get::<Auth>()
+            .cloned()
+            .ok_or(ApiError::Unauthorized);
         std::future::ready(auth)
     }
 }
 
 pub struct JwtConfig {
-    pub encoding_key: EncodingKey,
-    pub decoding_key: DecodingKey,
+    pub keyring: Keyring,
+    /// Algorithm of keys created by rotation.
     pub algorithm: Algorithm,
+    /// Allowed clock skew in seconds when checking `exp`.
+    pub leeway: u64,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    pub guest_ticket_ttl: Duration,
+    /// Signing key is rotated automatically when set.
+    pub rotation_interval: Option<Duration>,
+}
+
+impl JwtConfig {
+    /// How long a retired key must stay valid for its tokens to expire.
+    pub fn key_grace_period(&self) -> Duration {
+        self.access_token_ttl.max(self.guest_ticket_ttl) + Duration::from_secs(self.leeway)
+    }
 }
 
 pub fn decode_token(token: &str, config: &JwtConfig) -> jsonwebtoken::errors::Result<Auth> {
-    let JwtConfig { decoding_key, algorithm, .. } = config;
+    decode_claims(token, config)
+}
 
-    let mut validation = {
-        let mut v = Validation::new(*algorithm);
-        // TODO: use exp
-        v.validate_exp = false;
+/// Verifies a token signed with our keys and returns its claims, if they are
+/// of the expected kind.
+pub fn decode_claims<T: Claims>(
+    token: &str,
+    config: &JwtConfig,
+) -> jsonwebtoken::errors::Result<T> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let key = config
+        .keyring
+        .verification_key(header.kid.as_deref())
+        .ok_or(ErrorKind::InvalidSignature)?;
+
+    let validation = {
+        let mut v = Validation::new(key.algorithm);
+        v.validate_exp = true;
+        v.leeway = config.leeway;
         v
     };
 
     log::trace!("Decoging token {}...", token);
-    let token_data = jsonwebtoken::decode::<Auth>(token, decoding_key, &validation)?;
+    let token_data = jsonwebtoken::decode::<T>(token, &key.decoding_key, &validation)?;
     log::trace!("Decoging token {:?}... OK", token_data);
 
+    if token_data.claims.typ() != T::TYP {
+        return Err(ErrorKind::InvalidToken.into());
+    }
     Ok(token_data.claims)
 }
 
-pub fn encode_token(auth: &Auth, config: &JwtConfig) -> jsonwebtoken::errors::Result<String> {
-    log::trace!("Encoding token {:?}...", auth);
-    let JwtConfig { encoding_key, algorithm, .. } = config;
-    let token = jsonwebtoken::encode(&Header::new(*algorithm), auth, encoding_key);
+pub fn encode_token<T: Serialize + Debug>(
+    claims: &T,
+    config: &JwtConfig,
+) -> jsonwebtoken::errors::Result<String> {
+    log::trace!("Encoding token {:?}...", claims);
+    let key = config.keyring.signing_key();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid);
+    let token = jsonwebtoken::encode(&header, claims, &key.encoding_key);
     log::trace!("Encoding token OK: {:?}...", &token);
     token
 }
@@ -67,13 +164,255 @@ pub async fn bearer_validator(
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, actix_web::Error> {
     log::trace!("try validate token: {}", credentials.token());
-    let config = req.app_data::<Data<JwtConfig>>().unwrap().get_ref();
+    // Database calls run on the blocking pool, the middleware runs on the
+    // executor.
+    let config = req.app_data::<Data<JwtConfig>>().unwrap().clone();
+    let db = req.app_data::<Data<DbService>>().unwrap().clone();
 
-    let auth =
-        decode_token(credentials.token(), config).map_err(|e| ErrorInternalServerError(e))?;
+    if api_token::is_api_token(credentials.token()) {
+        let token = credentials.token().to_string();
+        let auth = web::block(move || api_token::authenticate(&token, &db, Utc::now().naive_utc()))
+            .await
+            .map_err(ApiError::from)??;
+        req.extensions_mut().insert(auth);
+        return Ok(req);
+    }
+
+    if let Ok(Some(kid)) = jsonwebtoken::decode_header(credentials.token()).map(|h| h.kid) {
+        if config.keyring.verification_key(Some(&kid)).is_none() {
+            let (config, db) = (config.clone(), db.clone());
+            web::block(move || config.keyring.reload_for_unknown_kid(&kid, &db))
+                .await
+                .map_err(ApiError::from)?;
+        }
+    }
+
+    let auth = decode_token(credentials.token(), &config).map_err(|e| {
+        log::debug!("token rejected: {:?}", e);
+        match e.kind() {
+            ErrorKind::ExpiredSignature => ApiError::TokenExpired,
+            _ => ApiError::InvalidToken,
+        }
+    })?;
+
+    let sessions = req.app_data::<Data<SessionCache>>().unwrap().get_ref();
+    let session_id = auth.jti;
+    let is_active = sessions
+        .is_active(&session_id, || async move {
+            let session = web::block(move || db.session_by_id(&session_id))
+                .await
+                .map_err(ApiError::from)??;
+            Ok::<_, ApiError>(session.is_some_and(|s| s.revoked_at.is_none()))
+        })
+        .await?;
+    if !is_active {
+        return Err(ApiError::SessionRevoked.into());
+    }
 
     log::trace!("validation success: {}", credentials.token());
     req.extensions_mut().insert(auth);
 
     Ok(req)
 }
+
+// -----
+// Admin
+// -----
+
+/// Links sent by mail or shared by users point to pages of the client app
+/// which then call the API.
+pub struct EmailLinkConfig {
+    /// URL of the client app.
+    pub public_url: String,
+    pub password_reset_ttl: Duration,
+    pub email_verification_ttl: Duration,
+}
+
+impl EmailLinkConfig {
+    fn link(&self, page: &str, token: &str) -> String {
+        format!(
+            "{}/{}?token={}",
+            self.public_url.trim_end_matches('/'),
+            page,
+            token
+        )
+    }
+
+    pub fn password_reset_link(&self, token: &str) -> String {
+        self.link("reset-password", token)
+    }
+
+    pub fn email_verification_link(&self, token: &str) -> String {
+        self.link("verify-email", token)
+    }
+
+    pub fn join_link(&self, code: &str) -> String {
+        format!("{}/join/{}", self.public_url.trim_end_matches('/'), code)
+    }
+}
+
+pub struct AdminConfig {
+    /// Hash of the static token accepted on `/admin`. Admin routes are
+    /// closed when it is not set.
+    pub token_hash: Option<String>,
+}
+
+pub async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, actix_web::Error> {
+    let config = req.app_data::<Data<AdminConfig>>().unwrap().get_ref();
+    match &config.token_hash {
+        Some(expected) if *expected == hash_token(credentials.token()) => Ok(req),
+        _ => Err(ApiError::InvalidToken.into()),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use actix_service::Service;
+    use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use jsonwebtoken::{DecodingKey, EncodingKey};
+
+    use super::*;
+    use crate::db::DbPool;
+
+    const SECRET: &str = "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0";
+    const LEEWAY: u64 = 60;
+
+    pub fn config() -> JwtConfig {
+        let key = keys::JwtKey {
+            kid: "test".to_string(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_base64_secret(SECRET).unwrap(),
+            decoding_key: DecodingKey::from_base64_secret(SECRET).unwrap(),
+            created_at: Utc::now().naive_utc(),
+            expires_at: None,
+            jwk: None,
+        };
+        JwtConfig {
+            keyring: Keyring::new(key, None),
+            algorithm: Algorithm::HS256,
+            leeway: LEEWAY,
+            access_token_ttl: Duration::from_secs(300),
+            refresh_token_ttl: Duration::from_secs(3600),
+            guest_ticket_ttl: Duration::from_secs(300),
+            rotation_interval: None,
+        }
+    }
+
+    fn auth() -> Auth {
+        Auth::new(Uuid::new_v4(), Uuid::new_v4(), Duration::from_secs(300))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// Flips one character of the given part of `header.payload.signature`.
+    fn tamper(token: &str, part: usize) -> String {
+        let mut parts = token.split('.').map(String::from).collect::<Vec<_>>();
+        let first = parts[part].remove(0);
+        let replacement = if first == 'A' { 'B' } else { 'A' };
+        parts[part].insert(0, replacement);
+        parts.join(".")
+    }
+
+    #[test]
+    fn decodes_own_token() {
+        let config = config();
+        let claims = auth();
+        let token = encode_token(&claims, &config).unwrap();
+        assert_eq!(decode_token(&token, &config).unwrap(), claims);
+    }
+
+    #[test]
+    fn rejects_changed_signature_or_payload() {
+        let config = config();
+        let token = encode_token(&auth(), &config).unwrap();
+        for part in [1, 2] {
+            let tampered = tamper(&token, part);
+            assert!(decode_token(&tampered, &config).is_err(), "part {}", part);
+        }
+    }
+
+    #[test]
+    fn rejects_token_expired_past_leeway() {
+        let config = config();
+        let mut claims = auth();
+        claims.exp = now() - LEEWAY - 10;
+        let token = encode_token(&claims, &config).unwrap();
+        let err = decode_token(&token, &config).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ExpiredSignature));
+
+        claims.exp = now() - LEEWAY / 2;
+        let token = encode_token(&claims, &config).unwrap();
+        assert!(decode_token(&token, &config).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_algorithm() {
+        let config = config();
+        let mut header = Header::new(Algorithm::HS384);
+        header.kid = Some("test".to_string());
+        let key = EncodingKey::from_base64_secret(SECRET).unwrap();
+        let token = jsonwebtoken::encode(&header, &auth(), &key).unwrap();
+        let err = decode_token(&token, &config).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidAlgorithm));
+    }
+
+    #[test]
+    fn rejects_other_kind_of_token() {
+        let config = config();
+        let ticket = guest::GuestTicket::new(Uuid::new_v4(), Uuid::new_v4(), Duration::from_secs(300));
+        let token = encode_token(&ticket, &config).unwrap();
+        assert!(decode_claims::<guest::GuestTicket>(&token, &config).is_ok());
+        assert!(decode_token(&token, &config).is_err());
+
+        // Claims of an access token, but said to be of another kind
+        let mut claims = serde_json::to_value(auth()).unwrap();
+        claims["typ"] = "guest".into();
+        let token = encode_token(&claims, &config).unwrap();
+        let err = decode_token(&token, &config).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidToken));
+    }
+
+    #[actix_rt::test]
+    async fn bearer_validator_answers_unauthorized() {
+        let config = config();
+        let token = tamper(&encode_token(&auth(), &config).unwrap(), 2);
+        // Never connects, invalid tokens are rejected before the sessions
+        // are checked.
+        let pool: DbPool = Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(ConnectionManager::new("postgres://localhost:1/none"));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(config))
+                .app_data(Data::new(DbService::new(pool)))
+                .wrap(HttpAuthentication::bearer(bearer_validator))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let (status, headers) = match app.call(req).await {
+            Ok(res) => (res.status(), res.headers().clone()),
+            Err(e) => {
+                let res = e.error_response();
+                (res.status(), res.headers().clone())
+            }
+        };
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let challenge = headers.get(WWW_AUTHENTICATE).unwrap().to_str().unwrap();
+        assert!(challenge.starts_with("Bearer error=\"invalid_token\""));
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::url::Url;
+use openidconnect::{
+    reqwest, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet,
+    EndpointNotSet, EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, TokenResponse,
+};
+
+/// Time the user has to sign in at the provider.
+const LOGIN_TTL: Duration = Duration::from_secs(60 * 10);
+
+/// Discovered metadata, including the signing keys, is fetched again after
+/// this time.
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Upper bound of pending logins before expired ones are swept.
+const MAX_PENDING: usize = 10_000;
+
+/// Identity provider from `OIDC_<NAME>_*` variables.
+pub struct OidcProvider {
+    pub issuer: IssuerUrl,
+    pub client_id: ClientId,
+    /// Public clients rely on PKCE alone.
+    pub client_secret: Option<ClientSecret>,
+    /// Scopes besides `openid`, which is always requested.
+    pub scopes: Vec<String>,
+    /// Page of the client that receives the code and passes it on to
+    /// `POST /auth/oidc/{provider}/callback`.
+    pub redirect_url: RedirectUrl,
+}
+
+/// Claims of a verified ID token.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    UnknownProvider,
+    /// The state is unknown, expired or of another provider.
+    InvalidState,
+    /// Discovery, the code exchange or the ID token failed.
+    Provider(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::UnknownProvider => f.write_str("unknown provider"),
+            OidcError::InvalidState => f.write_str("invalid state"),
+            OidcError::Provider(e) => f.write_str(e),
+        }
+    }
+}
+
+fn provider_error(context: &str, e: impl fmt::Display) -> OidcError {
+    OidcError::Provider(format!("{}: {}", context, e))
+}
+
+/// Client built from discovered metadata, the token endpoint is optional
+/// in it.
+type Client = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+struct PendingLogin {
+    provider: String,
+    pkce_verifier: PkceCodeVerifier,
+    nonce: Nonce,
+    created: Instant,
+}
+
+/// Authorization code flow with PKCE. Logins in progress are kept in memory
+/// by their `state`, so they have to finish on the same instance.
+pub struct OidcLogin {
+    providers: HashMap<String, OidcProvider>,
+    http: reqwest::Client,
+    metadata: Mutex<HashMap<String, (CoreProviderMetadata, Instant)>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl OidcLogin {
+    pub fn new(providers: HashMap<String, OidcProvider>) -> Self {
+        let http = reqwest::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Can not create HTTP client");
+        OidcLogin {
+            providers,
+            http,
+            metadata: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn client(&self, name: &str) -> Result<(&OidcProvider, Client), OidcError> {
+        let provider = self.providers.get(name).ok_or(OidcError::UnknownProvider)?;
+
+        let cached = self
+            .metadata
+            .lock()
+            .unwrap()
+            .get(name)
+            .filter(|(_, fetched)| fetched.elapsed() < METADATA_TTL)
+            .map(|(metadata, _)| metadata.clone());
+        let metadata = match cached {
+            Some(metadata) => metadata,
+            None => {
+                let issuer = provider.issuer.clone();
+                let metadata = CoreProviderMetadata::discover_async(issuer, &self.http)
+                    .await
+                    .map_err(|e| provider_error("discovery", e))?;
+                self.metadata
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_string(), (metadata.clone(), Instant::now()));
+                metadata
+            }
+        };
+
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            provider.client_id.clone(),
+            provider.client_secret.clone(),
+        )
+        .set_redirect_uri(provider.redirect_url.clone());
+        Ok((provider, client))
+    }
+
+    /// URL of the provider's sign in page.
+    pub async fn start(&self, name: &str) -> Result<Url, OidcError> {
+        let (provider, client) = self.client(name).await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = client
+            .authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .set_pkce_challenge(pkce_challenge);
+        for scope in &provider.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (url, state, nonce) = request.url();
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.retain(|_, login| now.duration_since(login.created) < LOGIN_TTL);
+        }
+        pending.insert(
+            state.secret().clone(),
+            PendingLogin {
+                provider: name.to_string(),
+                pkce_verifier,
+                nonce,
+                created: now,
+            },
+        );
+        Ok(url)
+    }
+
+    /// Exchanges the code from the redirect and verifies the ID token.
+    /// Each state can be used once.
+    pub async fn finish(&self, name: &str, code: String, state: &str) -> Result<Identity, OidcError> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.provider == name && login.created.elapsed() < LOGIN_TTL)
+            .ok_or(OidcError::InvalidState)?;
+        let (_, client) = self.client(name).await?;
+
+        let response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .map_err(|e| provider_error("token endpoint", e))?
+            .set_pkce_verifier(login.pkce_verifier)
+            .request_async(&self.http)
+            .await
+            .map_err(|e| provider_error("code exchange", e))?;
+        let id_token = response
+            .id_token()
+            .ok_or_else(|| OidcError::Provider("no ID token in response".to_string()))?;
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &login.nonce)
+            .map_err(|e| provider_error("ID token", e))?;
+
+        Ok(Identity {
+            subject: claims.subject().to_string(),
+            email: claims.email().map(|email| email.to_string()),
+            email_verified: claims.email_verified().unwrap_or(false),
+            name: claims
+                .name()
+                .and_then(|name| name.get(None))
+                .map(|name| name.to_string()),
+        })
+    }
+}
+
+/// Identity provider served by wiremock for tests: the discovery document,
+/// its keys and a token endpoint which checks the PKCE verifier.
+#[cfg(test)]
+pub mod mock {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{Algorithm, Header};
+    use openidconnect::url::{form_urlencoded, Url};
+    use openidconnect::{ClientId, IssuerUrl, RedirectUrl};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    use super::{OidcLogin, OidcProvider};
+    use crate::auth::jwk::JwkSet;
+    use crate::auth::keys::JwtKey;
+
+    pub const CLIENT_ID: &str = "oqueue";
+
+    /// Account at the provider.
+    #[derive(Clone, Debug)]
+    pub struct MockUser {
+        pub subject: String,
+        pub email: String,
+        pub email_verified: bool,
+        pub name: String,
+    }
+
+    /// What the user agreed to at the sign in page, kept by the code.
+    struct Grant {
+        code_challenge: String,
+        nonce: String,
+        user: MockUser,
+    }
+
+    struct TokenEndpoint {
+        issuer: String,
+        key: JwtKey,
+        grants: Arc<Mutex<HashMap<String, Grant>>>,
+    }
+
+    impl Respond for TokenEndpoint {
+        fn respond(&self, req: &Request) -> ResponseTemplate {
+            let form = form_urlencoded::parse(&req.body).into_owned().collect::<HashMap<_, _>>();
+            let invalid_grant = ResponseTemplate::new(400).set_body_json(json!({"error": "invalid_grant"}));
+            let grant = match form.get("code").and_then(|code| self.grants.lock().unwrap().remove(code)) {
+                Some(grant) => grant,
+                None => return invalid_grant,
+            };
+            let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+            let challenge = base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+            if challenge != grant.code_challenge {
+                return invalid_grant;
+            }
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let claims = json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "sub": grant.user.subject,
+                "email": grant.user.email,
+                "email_verified": grant.user.email_verified,
+                "name": grant.user.name,
+                "nonce": grant.nonce,
+                "iat": now,
+                "exp": now + 300,
+            });
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some(self.key.kid.clone());
+            let id_token = jsonwebtoken::encode(&header, &claims, &self.key.encoding_key).unwrap();
+            ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "access",
+                "token_type": "Bearer",
+                "expires_in": 300,
+                "id_token": id_token,
+            }))
+        }
+    }
+
+    pub struct MockIdp {
+        server: MockServer,
+        grants: Arc<Mutex<HashMap<String, Grant>>>,
+    }
+
+    impl MockIdp {
+        pub async fn start() -> MockIdp {
+            let server = MockServer::start().await;
+            let issuer = server.uri();
+            let key = JwtKey::from_key_files(
+                "idp".to_string(),
+                Algorithm::RS256,
+                include_bytes!("testdata/idp.key.pem"),
+                include_bytes!("testdata/idp.pub.pem"),
+            )
+            .unwrap();
+            let jwks = JwkSet {
+                keys: vec![key.jwk.clone().unwrap()],
+            };
+
+            Mock::given(method("GET"))
+                .and(path("/.well-known/openid-configuration"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                    "response_types_supported": ["code"],
+                    "subject_types_supported": ["public"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/jwks"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&jwks))
+                .mount(&server)
+                .await;
+            let grants = Arc::new(Mutex::new(HashMap::new()));
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .respond_with(TokenEndpoint {
+                    issuer,
+                    key,
+                    grants: grants.clone(),
+                })
+                .mount(&server)
+                .await;
+
+            MockIdp { server, grants }
+        }
+
+        pub fn provider(&self) -> OidcProvider {
+            OidcProvider {
+                issuer: IssuerUrl::new(self.server.uri()).unwrap(),
+                client_id: ClientId::new(CLIENT_ID.to_string()),
+                client_secret: None,
+                scopes: vec!["email".to_string(), "profile".to_string()],
+                redirect_url: RedirectUrl::new("http://app.test/oidc/idp/callback".to_string())
+                    .unwrap(),
+            }
+        }
+
+        /// Login with this provider registered under each of `names`.
+        pub fn login(&self, names: &[&str]) -> OidcLogin {
+            let providers = names
+                .iter()
+                .map(|name| (name.to_string(), self.provider()))
+                .collect();
+            OidcLogin::new(providers)
+        }
+
+        /// Signs `user` in at the page `authorize_url` leads to and returns
+        /// the `code` and `state` the provider redirects back with. The ID
+        /// token will carry `nonce` instead of the requested one if given.
+        pub fn authorize(&self, authorize_url: &Url, user: &MockUser, nonce: Option<&str>) -> (String, String) {
+            let query = authorize_url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+            assert_eq!(query["response_type"], "code");
+            assert_eq!(query["code_challenge_method"], "S256");
+            let code = format!("code-{}", uuid::Uuid::new_v4());
+            self.grants.lock().unwrap().insert(
+                code.clone(),
+                Grant {
+                    code_challenge: query["code_challenge"].clone(),
+                    nonce: nonce.map_or_else(|| query["nonce"].clone(), String::from),
+                    user: user.clone(),
+                },
+            );
+            (code, query["state"].clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{MockIdp, MockUser};
+    use super::*;
+
+    fn user() -> MockUser {
+        MockUser {
+            subject: "subject-1".to_string(),
+            email: "ann@mail.test".to_string(),
+            email_verified: true,
+            name: "Ann Smith".to_string(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pkce_and_state_round_trip() {
+        let idp = MockIdp::start().await;
+        let login = idp.login(&["idp"]);
+
+        let url = login.start("idp").await.unwrap();
+        let (code, state) = idp.authorize(&url, &user(), None);
+        let identity = login.finish("idp", code.clone(), &state).await.unwrap();
+
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.email.as_deref(), Some("ann@mail.test"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.name.as_deref(), Some("Ann Smith"));
+
+        // A state is used once
+        let err = login.finish("idp", code, &state).await.unwrap_err();
+        assert!(matches!(err, OidcError::InvalidState));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_unknown_state() {
+        let idp = MockIdp::start().await;
+        let login = idp.login(&["idp"]);
+
+        let url = login.start("idp").await.unwrap();
+        let (code, _) = idp.authorize(&url, &user(), None);
+        let err = login.finish("idp", code, "forged").await.unwrap_err();
+        assert!(matches!(err, OidcError::InvalidState));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_state_of_other_provider() {
+        let idp = MockIdp::start().await;
+        let login = idp.login(&["idp", "other"]);
+
+        let url = login.start("idp").await.unwrap();
+        let (code, state) = idp.authorize(&url, &user(), None);
+        let err = login.finish("other", code, &state).await.unwrap_err();
+        assert!(matches!(err, OidcError::InvalidState));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_wrong_nonce() {
+        let idp = MockIdp::start().await;
+        let login = idp.login(&["idp"]);
+
+        let url = login.start("idp").await.unwrap();
+        let (code, state) = idp.authorize(&url, &user(), Some("replayed"));
+        let err = login.finish("idp", code, &state).await.unwrap_err();
+        assert!(matches!(err, OidcError::Provider(_)), "{:?}", err);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Argon2, Params, Version};
+
+/// The most common leaked passwords, always rejected in addition to the
+/// configured blocklist.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "123456789", "12345678", "1234567890", "12345", "1234567", "123123", "111111",
+    "000000", "654321", "666666", "121212", "112233", "7777777", "987654321", "qwerty",
+    "qwerty123", "qwertyuiop", "1q2w3e4r", "1q2w3e4r5t", "1qaz2wsx", "zaq12wsx", "asdfghjkl",
+    "password", "password1", "password123", "passw0rd", "p@ssw0rd", "abc123", "abcd1234",
+    "iloveyou", "admin", "admin123", "welcome", "welcome1", "letmein", "monkey", "dragon",
+    "football", "baseball", "sunshine", "princess", "superman", "starwars", "whatever",
+    "trustno1", "master", "shadow", "michael", "secret", "changeme", "qazwsx", "ytrewq",
+    "йцукен", "йцукенгшщз", "пароль", "oqueue", "oqueue123",
+];
+
+// --------------
+// PasswordPolicy
+// --------------
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum PolicyViolation {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    /// The password is on the blocklist.
+    Blocked,
+}
+
+/// Requirements for new passwords. Existing passwords are not checked, so
+/// a stricter policy applies to users the next time they set one.
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Lowercase passwords that can't be used.
+    blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: usize, max_length: usize, blocklist: impl IntoIterator<Item = String>) -> Self {
+        let blocklist = COMMON_PASSWORDS
+            .iter()
+            .map(|p| p.to_string())
+            .chain(blocklist)
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        PasswordPolicy {
+            min_length,
+            max_length,
+            blocklist,
+        }
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), PolicyViolation> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PolicyViolation::TooShort {
+                min: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            return Err(PolicyViolation::TooLong {
+                max: self.max_length,
+            });
+        }
+        if self.blocklist.contains(&password.to_lowercase()) {
+            return Err(PolicyViolation::Blocked);
+        }
+        Ok(())
+    }
+}
+
+// --------------
+// PasswordHasher
+// --------------
+
+/// Scheme of newly created hashes. Hashes of both schemes are accepted.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum HashScheme {
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+    Bcrypt { cost: u32 },
+}
+
+#[derive(Debug)]
+pub enum HashError {
+    Argon2(password_hash::Error),
+    Bcrypt(bcrypt::BcryptError),
+    /// The stored hash is in neither format.
+    UnknownFormat,
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::Argon2(e) => write!(f, "argon2: {}", e),
+            HashError::Bcrypt(e) => write!(f, "bcrypt: {}", e),
+            HashError::UnknownFormat => f.write_str("unknown password hash format"),
+        }
+    }
+}
+
+impl From<password_hash::Error> for HashError {
+    fn from(e: password_hash::Error) -> Self {
+        HashError::Argon2(e)
+    }
+}
+
+impl From<bcrypt::BcryptError> for HashError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        HashError::Bcrypt(e)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Verified {
+    No,
+    Yes,
+    /// The password is correct, but the hash uses another scheme or
+    /// weaker parameters and should be replaced.
+    NeedsRehash,
+}
+
+pub struct PasswordHasher {
+    scheme: HashScheme,
+}
+
+impl PasswordHasher {
+    /// Panics when the argon2 parameters are out of range.
+    pub fn new(scheme: HashScheme) -> Self {
+        if let HashScheme::Argon2id {
+            memory_kib,
+            iterations,
+            parallelism,
+        } = scheme
+        {
+            Params::new(memory_kib, iterations, parallelism, None)
+                .unwrap_or_else(|e| panic!("Invalid argon2 parameters: {}", e));
+        }
+        PasswordHasher { scheme }
+    }
+
+    fn argon2(memory_kib: u32, iterations: u32, parallelism: u32) -> Argon2<'static> {
+        // Checked in `new`
+        let params = Params::new(memory_kib, iterations, parallelism, None).unwrap();
+        Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params)
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, HashError> {
+        match self.scheme {
+            HashScheme::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Self::argon2(memory_kib, iterations, parallelism)
+                    .hash_password(password.as_bytes(), &salt)?;
+                Ok(hash.to_string())
+            }
+            HashScheme::Bcrypt { cost } => Ok(bcrypt::hash(password, cost)?),
+        }
+    }
+
+    /// Accounts created by single sign-on have an empty hash and no password.
+    pub fn verify(&self, password: &str, pwhash: &str) -> Result<Verified, HashError> {
+        if pwhash.is_empty() {
+            return Ok(Verified::No);
+        }
+        if pwhash.starts_with("$2") {
+            if !bcrypt::verify(password, pwhash)? {
+                return Ok(Verified::No);
+            }
+            return Ok(match self.scheme {
+                HashScheme::Bcrypt { cost } if bcrypt_cost(pwhash) >= Some(cost) => Verified::Yes,
+                _ => Verified::NeedsRehash,
+            });
+        }
+
+        if !pwhash.starts_with("$argon2") {
+            return Err(HashError::UnknownFormat);
+        }
+        let parsed = PasswordHash::new(pwhash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => {}
+            Err(password_hash::Error::Password) => return Ok(Verified::No),
+            Err(e) => return Err(e.into()),
+        }
+        let params = Params::try_from(&parsed)?;
+        Ok(match self.scheme {
+            HashScheme::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } if parsed.algorithm == argon2::ARGON2ID_IDENT
+                && params.m_cost() >= memory_kib
+                && params.t_cost() >= iterations
+                && params.p_cost() >= parallelism =>
+            {
+                Verified::Yes
+            }
+            _ => Verified::NeedsRehash,
+        })
+    }
+}
+
+/// Cost of a `$2b$<cost>$...` hash.
+fn bcrypt_cost(pwhash: &str) -> Option<u32> {
+    pwhash.split('$').nth(2)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The cheapest parameters allowed, the tests hash a lot.
+    const ARGON2: HashScheme = HashScheme::Argon2id {
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+    const BCRYPT: HashScheme = HashScheme::Bcrypt { cost: 4 };
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new(8, 16, vec![" Correct-Horse ".to_string(), " ".to_string()])
+    }
+
+    #[test]
+    fn policy_checks_length_in_characters() {
+        let policy = policy();
+        assert_eq!(policy.check("Zq8!mvT"), Err(PolicyViolation::TooShort { min: 8 }));
+        assert_eq!(policy.check("Zq8!mvT3"), Ok(()));
+        assert_eq!(policy.check("Zq8!mvT3kpLw7#rX"), Ok(()));
+        assert_eq!(
+            policy.check("Zq8!mvT3kpLw7#rXy"),
+            Err(PolicyViolation::TooLong { max: 16 })
+        );
+        // 8 characters, 16 bytes
+        assert_eq!(policy.check("жёлтыйЪ1"), Ok(()));
+    }
+
+    #[test]
+    fn policy_rejects_blocked_passwords() {
+        let policy = policy();
+        assert_eq!(policy.check("PassWord123"), Err(PolicyViolation::Blocked));
+        assert_eq!(policy.check("ЙЦУКЕНГШЩЗ"), Err(PolicyViolation::Blocked));
+        assert_eq!(policy.check("correct-horse"), Err(PolicyViolation::Blocked));
+        assert_eq!(policy.check("correct-horse!"), Ok(()));
+    }
+
+    #[test]
+    fn verifies_own_hashes() {
+        for scheme in [ARGON2, BCRYPT] {
+            let hasher = PasswordHasher::new(scheme);
+            let pwhash = hasher.hash("Zq8!mvT3kpL").unwrap();
+            assert_eq!(hasher.verify("Zq8!mvT3kpL", &pwhash).unwrap(), Verified::Yes);
+            assert_eq!(hasher.verify("Zq8!mvT3kpl", &pwhash).unwrap(), Verified::No);
+        }
+    }
+
+    #[test]
+    fn bcrypt_hash_needs_rehash_to_argon2() {
+        let pwhash = PasswordHasher::new(BCRYPT).hash("Zq8!mvT3kpL").unwrap();
+        let hasher = PasswordHasher::new(ARGON2);
+
+        assert_eq!(hasher.verify("Zq8!mvT3kpL", &pwhash).unwrap(), Verified::NeedsRehash);
+        assert_eq!(hasher.verify("wrong", &pwhash).unwrap(), Verified::No);
+        let rehashed = hasher.hash("Zq8!mvT3kpL").unwrap();
+        assert!(rehashed.starts_with("$argon2id$"));
+        assert_eq!(hasher.verify("Zq8!mvT3kpL", &rehashed).unwrap(), Verified::Yes);
+    }
+
+    #[test]
+    fn weaker_parameters_need_rehash() {
+        let argon2 = PasswordHasher::new(ARGON2).hash("Zq8!mvT3kpL").unwrap();
+        let stronger = PasswordHasher::new(HashScheme::Argon2id {
+            memory_kib: 16,
+            iterations: 1,
+            parallelism: 1,
+        });
+        assert_eq!(stronger.verify("Zq8!mvT3kpL", &argon2).unwrap(), Verified::NeedsRehash);
+
+        let bcrypt = PasswordHasher::new(BCRYPT).hash("Zq8!mvT3kpL").unwrap();
+        let stronger = PasswordHasher::new(HashScheme::Bcrypt { cost: 5 });
+        assert_eq!(stronger.verify("Zq8!mvT3kpL", &bcrypt).unwrap(), Verified::NeedsRehash);
+        // Hashes stronger than configured are kept
+        let argon2 = PasswordHasher::new(HashScheme::Argon2id {
+            memory_kib: 16,
+            iterations: 2,
+            parallelism: 1,
+        })
+        .hash("Zq8!mvT3kpL")
+        .unwrap();
+        assert_eq!(PasswordHasher::new(ARGON2).verify("Zq8!mvT3kpL", &argon2).unwrap(), Verified::Yes);
+    }
+
+    #[test]
+    fn rejects_missing_and_unknown_hashes() {
+        let hasher = PasswordHasher::new(ARGON2);
+        assert_eq!(hasher.verify("", "").unwrap(), Verified::No);
+        assert!(matches!(
+            hasher.verify("Zq8!mvT3kpL", "plain"),
+            Err(HashError::UnknownFormat)
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+use std::future::Future;
+
+use actix_service::{apply_fn_factory, ServiceFactory};
+use actix_web::dev::{Handler, Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, Error, FromRequest, HttpMessage, Responder, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Auth;
+use crate::error::ApiError;
+
+/// Permission carried by a token in its `scopes` claim. Sessions get all
+/// of them, API tokens only the ones chosen on creation.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub enum Scope {
+    /// Read queues, their members and other users.
+    #[serde(rename = "queues:read")]
+    QueuesRead,
+    /// Create, change and delete queues, join and leave them.
+    #[serde(rename = "queues:write")]
+    QueuesWrite,
+    /// Add and remove other members and handle join requests. The handlers
+    /// also check that the user organizes the queue.
+    #[serde(rename = "members:manage")]
+    MembersManage,
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Name, username and locale.
+    #[serde(rename = "profile:write")]
+    ProfileWrite,
+    /// Password, email, sessions, API tokens, export and deletion. Never
+    /// granted to API tokens.
+    #[serde(rename = "account")]
+    Account,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 6] = [
+        Scope::QueuesRead,
+        Scope::QueuesWrite,
+        Scope::MembersManage,
+        Scope::ProfileRead,
+        Scope::ProfileWrite,
+        Scope::Account,
+    ];
+
+    pub fn all() -> Vec<Scope> {
+        Scope::ALL.to_vec()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::QueuesRead => "queues:read",
+            Scope::QueuesWrite => "queues:write",
+            Scope::MembersManage => "members:manage",
+            Scope::ProfileRead => "profile:read",
+            Scope::ProfileWrite => "profile:write",
+            Scope::Account => "account",
+        }
+    }
+
+    pub fn from_str(scope: &str) -> Option<Self> {
+        Scope::ALL.iter().copied().find(|s| s.as_str() == scope)
+    }
+
+    /// Parses a space separated list, unknown scopes are skipped.
+    pub fn parse_list(scopes: &str) -> Vec<Scope> {
+        scopes.split_whitespace().filter_map(Scope::from_str).collect()
+    }
+
+    pub fn join_list(scopes: &[Scope]) -> String {
+        scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Serves `route` with `handler` only to tokens with `scope`, others get
+/// `403`. Used in `configure_routes` in place of `route.to(handler)`.
+pub fn scoped<F, I, R>(route: Route, scope: Scope, handler: F) -> Route
+where
+    F: Handler<I, R>,
+    I: FromRequest + 'static,
+    R: Future + 'static,
+    R::Output: Responder + 'static,
+{
+    route.service(require(scope, web::to(handler)))
+}
+
+fn require(
+    scope: Scope,
+    route: Route,
+) -> impl ServiceFactory<
+    ServiceRequest,
+    Config = (),
+    Response = ServiceResponse,
+    Error = Error,
+    InitError = (),
+> {
+    apply_fn_factory(route, move |req: ServiceRequest, srv| {
+        let error = match req.extensions().get::<Auth>() {
+            Some(auth) if auth.scopes.contains(&scope) => None,
+            Some(_) => Some(ApiError::InsufficientScope),
+            None => Some(ApiError::Unauthorized),
+        };
+        check(error, req, srv)
+    })
+}
+
+fn check<S>(
+    error: Option<ApiError>,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let call = match error {
+        Some(error) => Err((req, error)),
+        None => Ok(srv.call(req)),
+    };
+    async move {
+        match call {
+            Ok(fut) => fut.await,
+            Err((req, error)) => Ok(req.error_response(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn parses_list() {
+        assert_eq!(
+            Scope::parse_list(" queues:read  members:manage unknown "),
+            vec![Scope::QueuesRead, Scope::MembersManage]
+        );
+        assert_eq!(Scope::parse_list(&Scope::join_list(&Scope::ALL)), Scope::all());
+        for scope in Scope::ALL {
+            let json = serde_json::to_string(&scope).unwrap();
+            assert_eq!(json, format!("\"{}\"", scope.as_str()));
+        }
+    }
+
+    /// Status of `method /` served only to `queues:write` for a token with
+    /// `scopes`, or without a token if `None`.
+    async fn call(scopes: Option<Vec<Scope>>) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    if let Some(scopes) = &scopes {
+                        let mut auth = Auth::new(Uuid::new_v4(), Uuid::new_v4(), Duration::from_secs(60));
+                        auth.scopes = scopes.clone();
+                        req.extensions_mut().insert(auth);
+                    }
+                    srv.call(req)
+                })
+                .route("/", scoped(web::post(), Scope::QueuesWrite, HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/").to_request();
+        test::call_service(&app, req).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn requires_scope() {
+        assert_eq!(call(Some(vec![Scope::QueuesRead])).await, StatusCode::FORBIDDEN);
+        assert_eq!(call(Some(vec![Scope::QueuesWrite])).await, StatusCode::OK);
+        assert_eq!(call(Some(Scope::all())).await, StatusCode::OK);
+        assert_eq!(call(None).await, StatusCode::UNAUTHORIZED);
+    }
+}
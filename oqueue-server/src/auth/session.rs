@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// Upper bound of cached sessions before stale entries are swept.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Remembers whether a session is still active so that `bearer_validator`
+/// doesn't hit the database on every request. Revocations made through this
+/// instance are visible immediately, the ones made by other instances after
+/// at most `ttl`.
+pub struct SessionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<Uuid, (bool, Instant)>>,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        SessionCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached state of the session or calls `load` to fetch it.
+    pub async fn is_active<E, F>(
+        &self,
+        session_id: &Uuid,
+        load: impl FnOnce() -> F,
+    ) -> Result<bool, E>
+    where
+        F: Future<Output = Result<bool, E>>,
+    {
+        let now = Instant::now();
+        {
+            let entries = self.entries.lock().unwrap();
+            if let Some((is_active, checked_at)) = entries.get(session_id) {
+                if now.duration_since(*checked_at) < self.ttl {
+                    return Ok(*is_active);
+                }
+            }
+        }
+
+        let is_active = load().await?;
+        self.insert(*session_id, is_active, now);
+        Ok(is_active)
+    }
+
+    pub fn revoke(&self, session_id: &Uuid) {
+        self.insert(*session_id, false, Instant::now());
+    }
+
+    fn insert(&self, session_id: Uuid, is_active: bool, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, (_, checked_at)| now.duration_since(*checked_at) < ttl);
+        }
+        entries.insert(session_id, (is_active, now));
+    }
+}
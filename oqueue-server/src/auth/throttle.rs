@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+/// Upper bound of remembered keys before stale entries are swept.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Failures are forgotten after this long without a new one.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Delay after the first failure over the limit, it doubles with each next one.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+// ------------
+// AttemptStore
+// ------------
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Attempts {
+    pub failures: u32,
+    pub last_failure: NaiveDateTime,
+}
+
+/// Keeps failed sign-in attempts per key. Implementations shared by several
+/// instances make the limits global.
+pub trait AttemptStore: Send + Sync {
+    /// Counts a failure under each of `keys` unless `wait` returns a time to
+    /// wait for one of them, then the longest one is returned. Keys are
+    /// checked and counted at once. Failures older than `window` are
+    /// forgotten.
+    fn add_failures_unless_waiting(
+        &self,
+        keys: &[&str],
+        now: NaiveDateTime,
+        window: Duration,
+        wait: &dyn Fn(&str, &Attempts) -> Option<Duration>,
+    ) -> Result<(), Duration>;
+
+    /// Takes back one failure counted under `key`.
+    fn remove_failure(&self, key: &str);
+
+    fn clear(&self, key: &str);
+}
+
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    entries: Mutex<HashMap<String, Attempts>>,
+}
+
+fn is_stale(attempts: &Attempts, now: NaiveDateTime, window: Duration) -> bool {
+    (now - attempts.last_failure).to_std().unwrap_or_default() >= window
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn add_failures_unless_waiting(
+        &self,
+        keys: &[&str],
+        now: NaiveDateTime,
+        window: Duration,
+        wait: &dyn Fn(&str, &Attempts) -> Option<Duration>,
+    ) -> Result<(), Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        let longest_wait = keys
+            .iter()
+            .filter_map(|key| {
+                let attempts = entries.get(*key).filter(|a| !is_stale(a, now, window))?;
+                wait(key, attempts)
+            })
+            .max();
+        if let Some(wait) = longest_wait {
+            return Err(wait);
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, a| !is_stale(a, now, window));
+        }
+        for key in keys {
+            entries
+                .entry(key.to_string())
+                .and_modify(|a| {
+                    if is_stale(a, now, window) {
+                        a.failures = 0;
+                    }
+                    a.failures += 1;
+                    a.last_failure = now;
+                })
+                .or_insert(Attempts {
+                    failures: 1,
+                    last_failure: now,
+                });
+        }
+        Ok(())
+    }
+
+    fn remove_failure(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(attempts) = entries.get_mut(key) {
+            attempts.failures = attempts.failures.saturating_sub(1);
+            if attempts.failures == 0 {
+                entries.remove(key);
+            }
+        }
+    }
+
+    fn clear(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// --------------
+// SignInThrottle
+// --------------
+
+/// Slows down password guessing on `/auth/signin`. Each account and each
+/// client address gets a number of free failures, after that every failure
+/// doubles the time until the next attempt is allowed, up to `max_delay`.
+pub struct SignInThrottle {
+    store: Box<dyn AttemptStore>,
+    account_limit: u32,
+    ip_limit: u32,
+    max_delay: Duration,
+}
+
+impl SignInThrottle {
+    pub fn new(
+        store: Box<dyn AttemptStore>,
+        account_limit: u32,
+        ip_limit: u32,
+        max_delay: Duration,
+    ) -> Self {
+        SignInThrottle {
+            store,
+            account_limit,
+            ip_limit,
+            max_delay,
+        }
+    }
+
+    fn account_key(login: &str) -> String {
+        format!("account:{}", login.to_lowercase())
+    }
+
+    fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    fn delay(&self, failures: u32, is_account: bool) -> Duration {
+        let limit = if is_account {
+            self.account_limit
+        } else {
+            self.ip_limit
+        };
+        if failures < limit {
+            return Duration::ZERO;
+        }
+        let exponent = (failures - limit).min(20);
+        (BASE_DELAY * 2u32.pow(exponent)).min(self.max_delay)
+    }
+
+    /// Counts the attempt as failed before the password is checked, so
+    /// parallel requests can't get past the limits. Returns the time the
+    /// client has to wait instead, if any.
+    pub fn reserve(&self, login: &str, ip: Option<&str>, now: NaiveDateTime) -> Result<(), Duration> {
+        let account_key = Self::account_key(login);
+        let ip_key = ip.map(Self::ip_key);
+        let mut keys = vec![account_key.as_str()];
+        keys.extend(ip_key.as_deref());
+
+        let wait = |key: &str, attempts: &Attempts| {
+            let delay = self.delay(attempts.failures, key == account_key);
+            let waited = (now - attempts.last_failure).to_std().unwrap_or_default();
+            delay.checked_sub(waited).filter(|d| !d.is_zero())
+        };
+        self.store
+            .add_failures_unless_waiting(&keys, now, FAILURE_WINDOW, &wait)
+    }
+
+    /// Forgets the failures of the account and takes back the attempt
+    /// reserved for the address. Other address failures are kept, so
+    /// signing in to an own account doesn't allow guessing others.
+    pub fn succeeded(&self, login: &str, ip: Option<&str>) {
+        self.store.clear(&Self::account_key(login));
+        if let Some(ip) = ip {
+            self.store.remove_failure(&Self::ip_key(ip));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    const IP: Option<&str> = Some("192.0.2.1");
+
+    fn throttle() -> SignInThrottle {
+        SignInThrottle::new(Box::new(MemoryAttemptStore::default()), 3, 5, Duration::from_secs(4))
+    }
+
+    fn at(secs: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()
+            + chrono::Duration::seconds(secs)
+    }
+
+    #[test]
+    fn locks_account_after_limit_with_backoff() {
+        let throttle = throttle();
+        for _ in 0..3 {
+            assert_eq!(throttle.reserve("ann", IP, at(0)), Ok(()));
+        }
+        assert_eq!(throttle.reserve("ann", IP, at(0)), Err(Duration::from_secs(1)));
+        // Waiting out the delay allows one more attempt, the next delay doubles
+        assert_eq!(throttle.reserve("ANN", IP, at(1)), Ok(()));
+        assert_eq!(throttle.reserve("ann", IP, at(1)), Err(Duration::from_secs(2)));
+        assert_eq!(throttle.reserve("ann", IP, at(3)), Ok(()));
+        // Capped by `max_delay`
+        assert_eq!(throttle.reserve("ann", IP, at(4)), Err(Duration::from_secs(3)));
+        assert_eq!(throttle.reserve("ann", IP, at(7)), Ok(()));
+        assert_eq!(throttle.reserve("ann", IP, at(7)), Err(Duration::from_secs(4)));
+        // Other accounts are not affected
+        assert_eq!(throttle.reserve("bob", Some("192.0.2.2"), at(7)), Ok(()));
+    }
+
+    #[test]
+    fn locks_address_after_limit() {
+        let throttle = throttle();
+        for login in ["a", "b", "c", "d", "e"] {
+            assert_eq!(throttle.reserve(login, IP, at(0)), Ok(()));
+        }
+        assert_eq!(throttle.reserve("f", IP, at(0)), Err(Duration::from_secs(1)));
+        assert_eq!(throttle.reserve("f", Some("192.0.2.2"), at(0)), Ok(()));
+        assert_eq!(throttle.reserve("g", None, at(0)), Ok(()));
+    }
+
+    #[test]
+    fn success_resets_account() {
+        let throttle = throttle();
+        for _ in 0..3 {
+            throttle.reserve("ann", IP, at(0)).unwrap();
+        }
+        throttle.succeeded("ann", IP);
+        for _ in 0..3 {
+            assert_eq!(throttle.reserve("ann", None, at(0)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn success_takes_back_address_attempt() {
+        // The address keeps its other failures
+        let throttle = throttle();
+        for login in ["a", "b", "c", "d"] {
+            throttle.reserve(login, IP, at(0)).unwrap();
+        }
+        throttle.reserve("ann", IP, at(0)).unwrap();
+        throttle.succeeded("ann", IP);
+        assert_eq!(throttle.reserve("e", IP, at(0)), Ok(()));
+        assert_eq!(throttle.reserve("f", IP, at(0)), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let throttle = throttle();
+        for _ in 0..4 {
+            let _ = throttle.reserve("ann", IP, at(0));
+        }
+        let later = FAILURE_WINDOW.as_secs() as i64;
+        for _ in 0..3 {
+            assert_eq!(throttle.reserve("ann", IP, at(later)), Ok(()));
+        }
+        assert!(throttle.reserve("ann", IP, at(later)).is_err());
+    }
+}
@@ -1,8 +1,28 @@
 use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Utc;
 use jsonwebtoken::{DecodingKey, EncodingKey, Algorithm};
+use log::info;
+use openidconnect::{ClientId, ClientSecret, IssuerUrl, RedirectUrl};
 
-use crate::auth::JwtConfig;
+use crate::auth::keys::{JwtKey, Keyring, SecretCipher};
+use crate::auth::oidc::{OidcLogin, OidcProvider};
+use crate::auth::password::{HashScheme, PasswordHasher, PasswordPolicy};
+use crate::auth::session::SessionCache;
+use crate::auth::throttle::{MemoryAttemptStore, SignInThrottle};
+use crate::auth::token::hash_token;
+use crate::auth::{AdminConfig, JwtConfig, EmailLinkConfig};
+use crate::handlers::TrustedProxies;
+use crate::mail::file::FileMailer;
+use crate::mail::smtp::{SmtpConfig, SmtpMailer, SmtpSecurity};
+use crate::mail::Mailer;
+use crate::rate_limit::{Limit, RateLimiter, RouteGroup};
 
 pub fn env_database_url() -> String {
     env::var("DATABASE_URL").expect("DATABASE_URL must be set")
@@ -20,25 +40,460 @@ pub fn env_decoding_key() -> String {
     env::var("JWT_DECODING_KEY").expect("JWT_DECODING_KEY must be set")
 }
 
+pub fn env_private_key_file() -> Option<String> {
+    env::var("JWT_PRIVATE_KEY_FILE").ok()
+}
+
+pub fn env_public_key_file() -> Option<String> {
+    env::var("JWT_PUBLIC_KEY_FILE").ok()
+}
+
 pub fn env_jwt_algorithm() -> Option<String> {
     env::var("JWT_ALGORITHM").ok()
 }
 
+pub fn env_jwt_key_id() -> Option<String> {
+    env::var("JWT_KEY_ID").ok()
+}
+
+pub fn env_jwt_rotation_interval() -> Option<String> {
+    env::var("JWT_ROTATION_INTERVAL").ok()
+}
+
+pub fn env_jwt_key_encryption_key() -> Option<String> {
+    env::var("JWT_KEY_ENCRYPTION_KEY").ok()
+}
+
+pub fn env_admin_token() -> Option<String> {
+    env::var("ADMIN_TOKEN").ok()
+}
+
+pub fn env_jwt_leeway() -> Option<String> {
+    env::var("JWT_LEEWAY").ok()
+}
+
+pub fn env_access_token_ttl() -> Option<String> {
+    env::var("ACCESS_TOKEN_TTL").ok()
+}
+
+pub fn env_refresh_token_ttl() -> Option<String> {
+    env::var("REFRESH_TOKEN_TTL").ok()
+}
+
+pub fn env_guest_ticket_ttl() -> Option<String> {
+    env::var("GUEST_TICKET_TTL").ok()
+}
+
+pub fn env_session_cache_ttl() -> Option<String> {
+    env::var("SESSION_CACHE_TTL").ok()
+}
+
+pub fn env_signin_account_limit() -> Option<String> {
+    env::var("SIGNIN_ACCOUNT_LIMIT").ok()
+}
+
+pub fn env_signin_ip_limit() -> Option<String> {
+    env::var("SIGNIN_IP_LIMIT").ok()
+}
+
+pub fn env_signin_max_delay() -> Option<String> {
+    env::var("SIGNIN_MAX_DELAY").ok()
+}
+
+pub fn env_trusted_proxies() -> Option<String> {
+    env::var("TRUSTED_PROXIES").ok()
+}
+
+pub fn env_rate_limit_auth() -> Option<String> {
+    env::var("RATE_LIMIT_AUTH").ok()
+}
+
+pub fn env_rate_limit_api_read() -> Option<String> {
+    env::var("RATE_LIMIT_API_READ").ok()
+}
+
+pub fn env_rate_limit_api_write() -> Option<String> {
+    env::var("RATE_LIMIT_API_WRITE").ok()
+}
+
+pub fn env_public_url() -> Option<String> {
+    env::var("PUBLIC_URL").ok()
+}
+
+pub fn env_oidc_providers() -> Option<String> {
+    env::var("OIDC_PROVIDERS").ok()
+}
+
+/// `OIDC_<PROVIDER>_<KEY>`, e.g. `OIDC_UNIVERSITY_ISSUER`.
+pub fn env_oidc(provider: &str, key: &str) -> Option<String> {
+    env::var(oidc_var(provider, key)).ok()
+}
+
+fn oidc_var(provider: &str, key: &str) -> String {
+    format!("OIDC_{}_{}", provider.to_uppercase(), key)
+}
+
+pub fn env_password_reset_ttl() -> Option<String> {
+    env::var("PASSWORD_RESET_TTL").ok()
+}
+
+pub fn env_email_verification_ttl() -> Option<String> {
+    env::var("EMAIL_VERIFICATION_TTL").ok()
+}
+
+pub fn env_password_min_length() -> Option<String> {
+    env::var("PASSWORD_MIN_LENGTH").ok()
+}
+
+pub fn env_password_max_length() -> Option<String> {
+    env::var("PASSWORD_MAX_LENGTH").ok()
+}
+
+pub fn env_password_blocklist_file() -> Option<String> {
+    env::var("PASSWORD_BLOCKLIST_FILE").ok()
+}
+
+pub fn env_password_hash() -> Option<String> {
+    env::var("PASSWORD_HASH").ok()
+}
+
+pub fn env_argon2_memory_kib() -> Option<String> {
+    env::var("ARGON2_MEMORY_KIB").ok()
+}
+
+pub fn env_argon2_iterations() -> Option<String> {
+    env::var("ARGON2_ITERATIONS").ok()
+}
+
+pub fn env_argon2_parallelism() -> Option<String> {
+    env::var("ARGON2_PARALLELISM").ok()
+}
+
+pub fn env_bcrypt_cost() -> Option<String> {
+    env::var("BCRYPT_COST").ok()
+}
+
+pub fn env_mailer() -> Option<String> {
+    env::var("MAILER").ok()
+}
+
+pub fn env_mail_from() -> Option<String> {
+    env::var("MAIL_FROM").ok()
+}
+
+pub fn env_mail_dir() -> Option<String> {
+    env::var("MAIL_DIR").ok()
+}
+
+pub fn env_smtp_host() -> String {
+    env::var("SMTP_HOST").expect("SMTP_HOST must be set")
+}
+
+pub fn env_smtp_port() -> Option<String> {
+    env::var("SMTP_PORT").ok()
+}
+
+pub fn env_smtp_security() -> Option<String> {
+    env::var("SMTP_SECURITY").ok()
+}
+
+pub fn env_smtp_username() -> Option<String> {
+    env::var("SMTP_USERNAME").ok()
+}
+
+pub fn env_smtp_password() -> Option<String> {
+    env::var("SMTP_PASSWORD").ok()
+}
+
+fn parse_secs(value: Option<String>, default: u64, name: &str) -> u64 {
+    match value {
+        None => default,
+        Some(secs) => secs
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of seconds", name)),
+    }
+}
+
+fn parse_count(value: Option<String>, default: u32, name: &str) -> u32 {
+    match value {
+        None => default,
+        Some(count) => count
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", name)),
+    }
+}
+
+pub fn load_signin_throttle() -> SignInThrottle {
+    let account_limit = parse_count(env_signin_account_limit(), 5, "SIGNIN_ACCOUNT_LIMIT");
+    let ip_limit = parse_count(env_signin_ip_limit(), 50, "SIGNIN_IP_LIMIT");
+    let max_delay = parse_secs(env_signin_max_delay(), 60 * 15, "SIGNIN_MAX_DELAY");
+    SignInThrottle::new(
+        Box::new(MemoryAttemptStore::default()),
+        account_limit,
+        ip_limit,
+        Duration::from_secs(max_delay),
+    )
+}
+
+/// Comma separated addresses of reverse proxies in front of the server.
+pub fn load_trusted_proxies() -> TrustedProxies {
+    let proxies = env_trusted_proxies()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            addr.parse::<IpAddr>()
+                .unwrap_or_else(|_| panic!("TRUSTED_PROXIES has invalid address {}", addr))
+        })
+        .collect();
+    TrustedProxies(proxies)
+}
+
+/// Reads a limit written as `<requests>/<seconds>`, or `off`.
+fn parse_limit(value: Option<String>, default: Limit, name: &str) -> Option<Limit> {
+    let value = match value {
+        None => return Some(default),
+        Some(value) if value == "off" => return None,
+        Some(value) => value,
+    };
+    let limit = value.split_once('/').and_then(|(burst, secs)| {
+        let burst = burst.trim().parse().ok().filter(|b| *b > 0)?;
+        let secs = secs.trim().parse().ok().filter(|s| *s > 0)?;
+        Some(Limit {
+            burst,
+            period: Duration::from_secs(secs),
+        })
+    });
+    Some(limit.unwrap_or_else(|| panic!("{} must be <requests>/<seconds> or off", name)))
+}
+
+pub fn load_rate_limiter() -> RateLimiter {
+    let minute = Duration::from_secs(60);
+    let groups = vec![
+        (RouteGroup::Auth, env_rate_limit_auth(), 20, "RATE_LIMIT_AUTH"),
+        (RouteGroup::ApiRead, env_rate_limit_api_read(), 300, "RATE_LIMIT_API_READ"),
+        (RouteGroup::ApiWrite, env_rate_limit_api_write(), 60, "RATE_LIMIT_API_WRITE"),
+    ];
+    let limits = groups
+        .into_iter()
+        .filter_map(|(group, value, burst, name)| {
+            let default = Limit {
+                burst,
+                period: minute,
+            };
+            parse_limit(value, default, name).map(|limit| (group, limit))
+        })
+        .collect();
+    RateLimiter::new(limits)
+}
+
+pub fn load_session_cache() -> SessionCache {
+    let ttl = parse_secs(env_session_cache_ttl(), 30, "SESSION_CACHE_TTL");
+    SessionCache::new(Duration::from_secs(ttl))
+}
+
+pub fn load_admin_config() -> AdminConfig {
+    AdminConfig {
+        token_hash: env_admin_token().map(|token| hash_token(&token)),
+    }
+}
+
+fn public_url() -> String {
+    env_public_url().unwrap_or_else(|| {
+        let host = env_host().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+        format!("http://{}", host)
+    })
+}
+
+pub fn load_email_link_config() -> EmailLinkConfig {
+    let password_reset_ttl = parse_secs(env_password_reset_ttl(), 60 * 60, "PASSWORD_RESET_TTL");
+    let email_verification_ttl =
+        parse_secs(env_email_verification_ttl(), 60 * 60 * 24, "EMAIL_VERIFICATION_TTL");
+    EmailLinkConfig {
+        public_url: public_url(),
+        password_reset_ttl: Duration::from_secs(password_reset_ttl),
+        email_verification_ttl: Duration::from_secs(email_verification_ttl),
+    }
+}
+
+/// `OIDC_PROVIDERS` is a comma separated list of provider names, each set up
+/// with `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID` and optionally
+/// `OIDC_<NAME>_CLIENT_SECRET`, `OIDC_<NAME>_SCOPES` and
+/// `OIDC_<NAME>_REDIRECT_URL`. The redirect defaults to the client page
+/// `<PUBLIC_URL>/oidc/<name>/callback`.
+pub fn load_oidc_login() -> OidcLogin {
+    let names = env_oidc_providers().unwrap_or_default();
+    let providers = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+            if !name.chars().all(valid) {
+                panic!("OIDC provider name {} must consist of a-z, 0-9 and _", name);
+            }
+            let required = |key: &str| {
+                env_oidc(name, key).unwrap_or_else(|| panic!("{} must be set", oidc_var(name, key)))
+            };
+            let issuer = IssuerUrl::new(required("ISSUER"))
+                .unwrap_or_else(|e| panic!("Invalid {}: {}", oidc_var(name, "ISSUER"), e));
+            let redirect_url = env_oidc(name, "REDIRECT_URL")
+                .unwrap_or_else(|| format!("{}/oidc/{}/callback", public_url(), name));
+            let redirect_url = RedirectUrl::new(redirect_url)
+                .unwrap_or_else(|e| panic!("Invalid redirect URL of OIDC provider {}: {}", name, e));
+            let scopes = env_oidc(name, "SCOPES")
+                .unwrap_or_else(|| "email profile".to_string())
+                .split_whitespace()
+                .filter(|scope| *scope != "openid")
+                .map(String::from)
+                .collect();
+            let provider = OidcProvider {
+                issuer,
+                client_id: ClientId::new(required("CLIENT_ID")),
+                client_secret: env_oidc(name, "CLIENT_SECRET").map(ClientSecret::new),
+                scopes,
+                redirect_url,
+            };
+            info!("Enabled OIDC provider {}", name);
+            (name.to_string(), provider)
+        })
+        .collect();
+    OidcLogin::new(providers)
+}
+
+/// `PASSWORD_BLOCKLIST_FILE` has one password per line, they are rejected
+/// together with a built-in list of the most common ones.
+pub fn load_password_policy() -> PasswordPolicy {
+    let min_length = parse_count(env_password_min_length(), 8, "PASSWORD_MIN_LENGTH");
+    let max_length = parse_count(env_password_max_length(), 128, "PASSWORD_MAX_LENGTH");
+    if min_length > max_length {
+        panic!("PASSWORD_MIN_LENGTH must not be greater than PASSWORD_MAX_LENGTH");
+    }
+    let blocklist = match env_password_blocklist_file() {
+        Some(file) => fs::read_to_string(&file)
+            .unwrap_or_else(|e| panic!("Can not read PASSWORD_BLOCKLIST_FILE {}: {}", file, e))
+            .lines()
+            .map(String::from)
+            .collect(),
+        None => Vec::new(),
+    };
+    PasswordPolicy::new(min_length as usize, max_length as usize, blocklist)
+}
+
+/// New passwords are hashed with argon2id unless `PASSWORD_HASH=bcrypt`.
+/// Defaults follow the OWASP recommendations.
+pub fn load_password_hasher() -> PasswordHasher {
+    let scheme = match env_password_hash().as_deref() {
+        None | Some("argon2id") => HashScheme::Argon2id {
+            memory_kib: parse_count(env_argon2_memory_kib(), 19 * 1024, "ARGON2_MEMORY_KIB"),
+            iterations: parse_count(env_argon2_iterations(), 2, "ARGON2_ITERATIONS"),
+            parallelism: parse_count(env_argon2_parallelism(), 1, "ARGON2_PARALLELISM"),
+        },
+        Some("bcrypt") => HashScheme::Bcrypt {
+            cost: parse_count(env_bcrypt_cost(), bcrypt::DEFAULT_COST, "BCRYPT_COST"),
+        },
+        Some(other) => panic!("Unknown PASSWORD_HASH {}", other),
+    };
+    PasswordHasher::new(scheme)
+}
+
+/// `MAILER=smtp` sends mails through `SMTP_HOST`. Otherwise they are
+/// written to `MAIL_DIR` or to the log.
+pub fn load_mailer() -> Arc<dyn Mailer> {
+    let from = env_mail_from().unwrap_or_else(|| "OQueue <noreply@localhost>".to_string());
+    match env_mailer().as_deref() {
+        Some("smtp") => {
+            let security = match env_smtp_security().as_deref() {
+                None | Some("starttls") => SmtpSecurity::StartTls,
+                Some("tls") => SmtpSecurity::Tls,
+                Some("none") => SmtpSecurity::None,
+                Some(other) => panic!("Unknown SMTP_SECURITY {}", other),
+            };
+            let port = env_smtp_port()
+                .map(|port| port.parse().expect("SMTP_PORT must be a port number"));
+            let config = SmtpConfig {
+                host: env_smtp_host(),
+                port,
+                security,
+                username: env_smtp_username(),
+                password: env_smtp_password(),
+                from,
+            };
+            let mailer = SmtpMailer::new(config)
+                .unwrap_or_else(|e| panic!("Invalid SMTP configuration: {}", e));
+            Arc::new(mailer)
+        }
+        None | Some("file") => Arc::new(FileMailer::new(from, env_mail_dir().map(PathBuf::from))),
+        Some(other) => panic!("Unknown MAILER {}", other),
+    }
+}
+
 pub fn load_jwt_config() -> JwtConfig {
-    let encoding_key = env_encoding_key();
-    let decoding_key = env_decoding_key();
     let algorithm = env_jwt_algorithm();
+    let kid = env_jwt_key_id().unwrap_or_else(|| "default".to_string());
+    let leeway = parse_secs(env_jwt_leeway(), 60, "JWT_LEEWAY");
+    let access_token_ttl = parse_secs(env_access_token_ttl(), 60 * 15, "ACCESS_TOKEN_TTL");
+    let refresh_token_ttl = parse_secs(env_refresh_token_ttl(), 60 * 60 * 24 * 30, "REFRESH_TOKEN_TTL");
+    let guest_ticket_ttl = parse_secs(env_guest_ticket_ttl(), 60 * 60 * 12, "GUEST_TICKET_TTL");
+    let rotation_interval = env_jwt_rotation_interval()
+        .map(|secs| parse_secs(Some(secs), 0, "JWT_ROTATION_INTERVAL"))
+        .map(Duration::from_secs);
 
-    let encoding_key = EncodingKey::from_base64_secret(&encoding_key).unwrap();
-    let decoding_key = DecodingKey::from_base64_secret(&decoding_key).unwrap();
     let algorithm = match algorithm {
         None => Algorithm::default(),
         Some(alg) => Algorithm::from_str(&alg).unwrap(),
     };
 
+    let configured_key = match env_private_key_file() {
+        Some(private_key_file) => {
+            let public_key_file = env_public_key_file()
+                .expect("JWT_PUBLIC_KEY_FILE must be set with JWT_PRIVATE_KEY_FILE");
+            let private_key = fs::read(&private_key_file)
+                .unwrap_or_else(|e| panic!("Can not read {}: {}", private_key_file, e));
+            let public_key = fs::read(&public_key_file)
+                .unwrap_or_else(|e| panic!("Can not read {}: {}", public_key_file, e));
+            let key = JwtKey::from_key_files(kid, algorithm, &private_key, &public_key)
+                .unwrap_or_else(|e| panic!("Invalid JWT key files: {}", e));
+            if rotation_interval.is_some() {
+                panic!("JWT_ROTATION_INTERVAL is only supported for HMAC keys");
+            }
+            info!("Loaded {:?} JWT key {}", algorithm, key.kid);
+            key
+        }
+        None => {
+            if !matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                panic!("JWT_PRIVATE_KEY_FILE must be set for {:?}", algorithm);
+            }
+            let encoding_key = EncodingKey::from_base64_secret(&env_encoding_key()).unwrap();
+            let decoding_key = DecodingKey::from_base64_secret(&env_decoding_key()).unwrap();
+            JwtKey {
+                kid,
+                algorithm,
+                encoding_key,
+                decoding_key,
+                created_at: Utc::now().naive_utc(),
+                expires_at: None,
+                jwk: None,
+            }
+        }
+    };
+
+    let cipher = env_jwt_key_encryption_key().map(|key| {
+        SecretCipher::from_base64(&key)
+            .expect("JWT_KEY_ENCRYPTION_KEY must be 32 bytes in base64")
+    });
+    if rotation_interval.is_some() && cipher.is_none() {
+        panic!("JWT_KEY_ENCRYPTION_KEY must be set with JWT_ROTATION_INTERVAL");
+    }
+
     JwtConfig {
-        encoding_key,
-        decoding_key,
-        algorithm
+        keyring: Keyring::new(configured_key, cipher),
+        algorithm,
+        leeway,
+        access_token_ttl: Duration::from_secs(access_token_ttl),
+        refresh_token_ttl: Duration::from_secs(refresh_token_ttl),
+        guest_ticket_ttl: Duration::from_secs(guest_ticket_ttl),
+        rotation_interval,
     }
 }
@@ -1,11 +1,18 @@
-use std::collections::HashSet;
 use chrono::NaiveDateTime;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Float, Nullable, Text};
 use uuid::Uuid;
 
-use crate::db::models::{QueueDao, QueueEntryDao, UserDao};
+use crate::db::models::{
+    ApiTokenDao, EmailVerificationTokenDao, GuestDao, JoinCodeDao, JoinRequestDao, JwtKeyDao,
+    PasswordResetTokenDao, QueueDao, QueueEntryDao, QueueSettingsChangeset, RefreshTokenDao,
+    SessionDao, UserDao, UserIdentityDao,
+};
 use crate::db::DbConnection;
 
+#[allow(dead_code)]
 type Result<T> = QueryResult<T>;
 
 // ----
@@ -19,11 +26,19 @@ pub fn add_user(conn: &DbConnection, user_data: &UserDao) -> QueryResult<usize>
 
 pub fn user_by_email(conn: &DbConnection, email_str: &str) -> QueryResult<Option<UserDao>> {
     use crate::db::schema::users::dsl::*;
-    let user = users
-        .filter(email.eq(email_str))
+    users
+        // Uses the `lower("email")` index
+        .filter(sql::<Bool>("lower(email) = ").bind::<Text, _>(email_str.to_lowercase()))
         .first::<UserDao>(conn)
-        .optional();
-    user
+        .optional()
+}
+
+pub fn user_by_username(conn: &DbConnection, username_str: &str) -> QueryResult<Option<UserDao>> {
+    use crate::db::schema::users::dsl::*;
+    users
+        .filter(sql::<Bool>("lower(username) = ").bind::<Text, _>(username_str.to_lowercase()))
+        .first::<UserDao>(conn)
+        .optional()
 }
 
 pub fn has_user_with_email(conn: &DbConnection, email_str: &str) -> QueryResult<bool> {
@@ -32,11 +47,73 @@ pub fn has_user_with_email(conn: &DbConnection, email_str: &str) -> QueryResult<
 
 pub fn user_by_id(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Option<UserDao>> {
     use crate::db::schema::users::dsl::*;
-    let user = users
+    users
         .filter(id.eq(user_id))
         .first::<UserDao>(conn)
-        .optional();
-    user
+        .optional()
+}
+
+pub fn user_locale(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Option<String>> {
+    use crate::db::schema::users::dsl::*;
+    users
+        .select(locale)
+        .filter(id.eq(user_id))
+        .first::<Option<String>>(conn)
+        .optional()
+        .map(Option::flatten)
+}
+
+pub fn set_user_locale(conn: &DbConnection, user_id: &Uuid, new_locale: Option<&str>) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(locale.eq(new_locale))
+        .execute(conn)
+}
+
+pub fn delete_user(conn: &DbConnection, user_id: &Uuid) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::delete(users.filter(id.eq(user_id))).execute(conn)
+}
+
+pub fn set_user_name(conn: &DbConnection, user_id: &Uuid, new_name: &str) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(name.eq(new_name))
+        .execute(conn)
+}
+
+pub fn set_user_username(
+    conn: &DbConnection,
+    user_id: &Uuid,
+    new_username: Option<&str>,
+) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(username.eq(new_username))
+        .execute(conn)
+}
+
+/// Changes the email, the new one is not verified yet.
+pub fn set_user_email(conn: &DbConnection, user_id: &Uuid, new_email: &str) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set((email.eq(new_email), email_verified_at.eq(None::<NaiveDateTime>)))
+        .execute(conn)
+}
+
+pub fn set_user_pwhash(conn: &DbConnection, user_id: &Uuid, new_pwhash: &str) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(pwhash.eq(new_pwhash))
+        .execute(conn)
+}
+
+/// Marks the email as verified unless the user has changed it meanwhile.
+pub fn set_email_verified(conn: &DbConnection, user_id: &Uuid, email_str: &str, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::users::dsl::*;
+    diesel::update(users.filter(id.eq(user_id).and(email.eq(email_str))))
+        .set(email_verified_at.eq(now))
+        .execute(conn)
 }
 
 // ------
@@ -55,13 +132,13 @@ pub fn delete_queue(conn: &DbConnection, queue_id: &Uuid) -> QueryResult<usize>
 
 pub fn queue_by_id(conn: &DbConnection, queue_id: &Uuid) -> QueryResult<Option<QueueDao>> {
     use crate::db::schema::queues::dsl::*;
-    let queue = queues
+    queues
         .filter(id.eq(queue_id))
         .first::<QueueDao>(conn)
-        .optional();
-    queue
+        .optional()
 }
 
+#[allow(dead_code)]
 pub fn queues_with_member(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Vec<QueueDao>> {
     use crate::db::schema::*;
     let queues = queues::table
@@ -71,33 +148,240 @@ pub fn queues_with_member(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Ve
     queues.map(|v| v.into_iter().map(|(q, _)| q).collect())
 }
 
-pub fn available_queues(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Vec<QueueDao>> {
-    use crate::db::schema::*;
+pub fn organized_queues(conn: &DbConnection, user_id: &Uuid) -> QueryResult<Vec<QueueDao>> {
+    use crate::db::schema::queues::dsl::*;
+    queues
+        .filter(organizer_id.eq(user_id))
+        .order_by(created_at)
+        .load::<QueueDao>(conn)
+}
 
-    let queues_with_organizer: Vec<QueueDao> = queues::table
-        .filter(queues::organizer_id.eq(user_id))
-        .load::<QueueDao>(conn)?;
+pub fn set_queue_organizer(conn: &DbConnection, queue_id: &Uuid, user_id: &Uuid) -> QueryResult<usize> {
+    use crate::db::schema::queues::dsl::*;
+    diesel::update(queues.filter(id.eq(queue_id)))
+        .set(organizer_id.eq(user_id))
+        .execute(conn)
+}
 
-    let queues_with_members: Vec<(QueueDao, QueueEntryDao)> = queues::table
-        .inner_join(queue_entries::table)
-        .filter(queue_entries::user_id.eq(user_id))
-        .load::<(QueueDao, QueueEntryDao)>(conn)?;
+pub fn update_queue_settings(
+    conn: &DbConnection,
+    queue_id: &Uuid,
+    changes: &QueueSettingsChangeset,
+) -> QueryResult<usize> {
+    use crate::db::schema::queues::dsl::*;
+    diesel::update(queues.filter(id.eq(queue_id)))
+        .set(changes)
+        .execute(conn)
+}
 
-    let mut queues = Vec::new();
-    let mut queues_uuids = HashSet::new();
+/// Sort key of [`available_queues`], ties are broken by the id.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum QueueOrder {
+    CreatedAt,
+    Name,
+}
 
-    let mut add_or_ignore = |q: QueueDao| {
-        let has_copy = queues_uuids.contains(&q.id);
-        if !has_copy {
-            queues_uuids.insert(q.id);
-            queues.push(q);
-        }
+/// Keys of the last queue of the previous page.
+#[derive(Clone, Debug)]
+pub struct QueueAfter {
+    pub created_at: NaiveDateTime,
+    pub name: String,
+    pub id: Uuid,
+}
+
+/// Filter and page of [`available_queues`], `None` fields don't filter.
+#[derive(Clone, Debug)]
+pub struct QueueListing {
+    pub user_id: Uuid,
+    /// Only organized (`true`) or only joined (`false`) queues.
+    pub organized: Option<bool>,
+    /// Queues that still exist (`true`) or have expired (`false`) at `now`.
+    pub open: Option<bool>,
+    pub now: NaiveDateTime,
+    /// Inclusive.
+    pub created_after: Option<NaiveDateTime>,
+    /// Exclusive.
+    pub created_before: Option<NaiveDateTime>,
+    pub order: QueueOrder,
+    pub descending: bool,
+    pub after: Option<QueueAfter>,
+    pub limit: i64,
+}
+
+/// Queue of [`available_queues`] with the place of the user in it.
+#[derive(Clone, Debug)]
+pub struct AvailableQueue {
+    pub queue: QueueDao,
+    /// Of the user's entry counting from 1, in the order of
+    /// [`entries_ordered`]. `None` when not a member.
+    pub position: Option<i64>,
+    pub member_count: i64,
+}
+
+const QUEUE_POSITION: &str = "(case when \"queue_entries\".\"id\" is null then null else \
+    (select count(*) + 1 from \"queue_entries\" \"e\" \
+    where \"e\".\"queue_id\" = \"queue_entries\".\"queue_id\" \
+    and (\"e\".\"is_held\" > \"queue_entries\".\"is_held\" \
+    or (\"e\".\"is_held\" = \"queue_entries\".\"is_held\" \
+    and \"e\".\"order\" < \"queue_entries\".\"order\"))) end)";
+
+const QUEUE_MEMBER_COUNT: &str =
+    "(select count(*) from \"queue_entries\" \"e\" where \"e\".\"queue_id\" = \"queues\".\"id\")";
+
+/// Queues the user organizes or is a member of, in one query. The entry of
+/// the user is joined, so each queue comes once.
+pub fn available_queues(
+    conn: &DbConnection,
+    listing: &QueueListing,
+) -> QueryResult<Vec<AvailableQueue>> {
+    use crate::db::schema::queue_entries as qe;
+    use crate::db::schema::queues::dsl::*;
+
+    let user = listing.user_id;
+    let mut query = queues
+        .left_join(qe::table.on(qe::queue_id.eq(id).and(qe::user_id.eq(user))))
+        .select((
+            queues::all_columns(),
+            sql::<Nullable<BigInt>>(QUEUE_POSITION),
+            sql::<BigInt>(QUEUE_MEMBER_COUNT),
+        ))
+        .into_boxed();
+    query = match listing.organized {
+        Some(true) => query.filter(organizer_id.eq(user)),
+        Some(false) => query.filter(qe::id.is_not_null()),
+        None => query.filter(organizer_id.eq(user).or(qe::id.is_not_null())),
+    };
+    match listing.open {
+        Some(true) => query = query.filter(exists_before.gt(listing.now)),
+        Some(false) => query = query.filter(exists_before.le(listing.now)),
+        None => {}
+    }
+    if let Some(after) = listing.created_after {
+        query = query.filter(created_at.ge(after));
+    }
+    if let Some(before) = listing.created_before {
+        query = query.filter(created_at.lt(before));
+    }
+
+    if let Some(after) = &listing.after {
+        let tie = id.gt(after.id);
+        query = match (listing.order, listing.descending) {
+            (QueueOrder::CreatedAt, false) => query.filter(
+                created_at
+                    .gt(after.created_at)
+                    .or(created_at.eq(after.created_at).and(tie)),
+            ),
+            (QueueOrder::CreatedAt, true) => query.filter(
+                created_at
+                    .lt(after.created_at)
+                    .or(created_at.eq(after.created_at).and(tie)),
+            ),
+            (QueueOrder::Name, false) => query.filter(
+                name.gt(after.name.clone())
+                    .or(name.eq(after.name.clone()).and(tie)),
+            ),
+            (QueueOrder::Name, true) => query.filter(
+                name.lt(after.name.clone())
+                    .or(name.eq(after.name.clone()).and(tie)),
+            ),
+        };
+    }
+    query = match (listing.order, listing.descending) {
+        (QueueOrder::CreatedAt, false) => query.order(created_at.asc()),
+        (QueueOrder::CreatedAt, true) => query.order(created_at.desc()),
+        (QueueOrder::Name, false) => query.order(name.asc()),
+        (QueueOrder::Name, true) => query.order(name.desc()),
     };
+    let rows = query
+        .then_order_by(id)
+        .limit(listing.limit)
+        .load::<(QueueDao, Option<i64>, i64)>(conn)?;
+    Ok(rows
+        .into_iter()
+        .map(|(queue, position, member_count)| AvailableQueue {
+            queue,
+            position,
+            member_count,
+        })
+        .collect())
+}
 
-    for q in queues_with_organizer { add_or_ignore(q); }
-    for (q, _) in queues_with_members { add_or_ignore(q); }
+/// Document of the full-text search, must match `queues_search_idx`.
+const QUEUE_SEARCH_DOCUMENT: &str = "(setweight(to_tsvector('simple', \"name\"), 'A') || \
+    setweight(to_tsvector('simple', \"description\"), 'B'))";
 
-    Ok(queues)
+/// Keys of the last match of the previous page.
+#[derive(Clone, Debug)]
+pub struct SearchAfter {
+    pub rank: f32,
+    pub created_at: NaiveDateTime,
+    pub id: Uuid,
+}
+
+/// Filter of [`search_public_queues`], `None` fields don't filter.
+#[derive(Clone, Debug)]
+pub struct QueueSearch<'a> {
+    /// Words in the name or the description, in `websearch_to_tsquery` syntax.
+    pub text: Option<&'a str>,
+    pub organizer_id: Option<Uuid>,
+    /// Queues that still exist (`true`) or have expired (`false`) at `now`.
+    pub open: Option<bool>,
+    pub now: NaiveDateTime,
+    pub after: Option<SearchAfter>,
+    pub limit: i64,
+}
+
+/// Public queues with their rank, the best matches first when searching by
+/// text and the newest first otherwise. The rank is 0 without text.
+pub fn search_public_queues(
+    conn: &DbConnection,
+    search: &QueueSearch,
+) -> QueryResult<Vec<(QueueDao, f32)>> {
+    use crate::db::schema::queues::dsl::*;
+
+    type Rank = Box<dyn BoxableExpression<crate::db::schema::queues::table, Pg, SqlType = Float>>;
+    let rank = || -> Rank {
+        match search.text {
+            Some(text) => {
+                let rank = format!("ts_rank({}, websearch_to_tsquery('simple', ", QUEUE_SEARCH_DOCUMENT);
+                Box::new(sql::<Float>(&rank).bind::<Text, _>(text.to_string()).sql("))"))
+            }
+            None => Box::new(sql::<Float>("0::real")),
+        }
+    };
+
+    let mut query = queues
+        .select((queues::all_columns(), rank()))
+        .filter(is_public.eq(true))
+        .into_boxed();
+    if let Some(organizer) = search.organizer_id {
+        query = query.filter(organizer_id.eq(organizer));
+    }
+    match search.open {
+        Some(true) => query = query.filter(exists_before.gt(search.now)),
+        Some(false) => query = query.filter(exists_before.le(search.now)),
+        None => {}
+    }
+    if let Some(text) = search.text {
+        let matches = format!("{} @@ websearch_to_tsquery('simple', ", QUEUE_SEARCH_DOCUMENT);
+        query = query.filter(sql::<Bool>(&matches).bind::<Text, _>(text).sql(")"));
+    }
+    if let Some(after) = &search.after {
+        let tie = created_at
+            .lt(after.created_at)
+            .or(created_at.eq(after.created_at).and(id.gt(after.id)));
+        query = query.filter(
+            rank()
+                .lt(after.rank)
+                .or(rank().eq(after.rank).and(tie)),
+        );
+    }
+    query
+        .order(rank().desc())
+        .then_order_by(created_at.desc())
+        .then_order_by(id)
+        .limit(search.limit)
+        .load::<(QueueDao, f32)>(conn)
 }
 
 // ------------
@@ -112,6 +396,7 @@ pub struct QueueEntryToAdd {
     pub joined_at: NaiveDateTime,
 }
 
+#[allow(dead_code)]
 pub fn add_entry_raw(conn: &DbConnection, data: &QueueEntryDao) -> QueryResult<usize> {
     use crate::db::schema::queue_entries::dsl::*;
     diesel::insert_into(queue_entries)
@@ -119,25 +404,62 @@ pub fn add_entry_raw(conn: &DbConnection, data: &QueueEntryDao) -> QueryResult<u
         .execute(conn)
 }
 
-pub fn add_entry(conn: &DbConnection, data: &QueueEntryToAdd) -> QueryResult<usize> {
+fn next_order(conn: &DbConnection, q_id: &Uuid) -> QueryResult<i32> {
     use crate::db::schema::queue_entries::dsl as qe;
 
-    let new_order: i32 = qe::queue_entries
+    let new_order = qe::queue_entries
         .select(diesel::dsl::max(qe::order))
-        .filter(qe::queue_id.eq(&data.queue_id))
+        .filter(qe::queue_id.eq(q_id))
         .first::<Option<i32>>(conn)
         .optional()?
         .flatten()
         .map(|x| x + 1)
         .unwrap_or(0);
+    Ok(new_order)
+}
+
+pub fn add_entry(conn: &DbConnection, data: &QueueEntryToAdd) -> QueryResult<usize> {
+    let order = next_order(conn, &data.queue_id)?;
+    insert_entry(conn, data, order)
+}
+
+/// Adds the user before the entries that joined after `joined_at`, so a
+/// join that waited for approval keeps its place.
+pub fn add_entry_in_time(conn: &DbConnection, data: &QueueEntryToAdd) -> QueryResult<usize> {
+    use crate::db::schema::queue_entries::dsl as qe;
+
+    let later = qe::queue_entries
+        .select(diesel::dsl::min(qe::order))
+        .filter(qe::queue_id.eq(data.queue_id))
+        .filter(qe::joined_at.gt(data.joined_at))
+        .first::<Option<i32>>(conn)?;
+    let order = match later {
+        Some(order) => {
+            let behind = qe::queue_entries
+                .filter(qe::queue_id.eq(data.queue_id))
+                .filter(qe::order.ge(order));
+            diesel::update(behind)
+                .set(qe::order.eq(qe::order + 1))
+                .execute(conn)?;
+            order
+        }
+        None => next_order(conn, &data.queue_id)?,
+    };
+    insert_entry(conn, data, order)
+}
+
+fn insert_entry(conn: &DbConnection, data: &QueueEntryToAdd, order: i32) -> QueryResult<usize> {
+    use crate::db::schema::queue_entries::dsl as qe;
 
     let entry = QueueEntryDao {
         queue_id: data.queue_id,
-        user_id: data.user_id,
-        order: new_order,
+        user_id: Some(data.user_id),
+        order,
         has_priority: data.has_priority,
         is_held: false,
         joined_at: data.joined_at,
+        id: Uuid::new_v4(),
+        guest_id: None,
     };
 
     diesel::insert_into(qe::queue_entries)
@@ -145,24 +467,528 @@ pub fn add_entry(conn: &DbConnection, data: &QueueEntryToAdd) -> QueryResult<usi
         .execute(conn)
 }
 
-pub fn delete_entry(conn: &DbConnection, queue_id: &Uuid, member_id: &Uuid) -> QueryResult<()> {
-    use crate::db::schema::queue_entries as qe;
+/// Adds the guest to the end of their queue.
+pub fn add_guest_entry(conn: &DbConnection, guest: &GuestDao) -> QueryResult<usize> {
+    use crate::db::schema::{guests, queue_entries};
 
-    let to_del = qe::table.filter(qe::queue_id.eq(&queue_id).and(qe::user_id.eq(&member_id)));
+    diesel::insert_into(guests::table).values(guest).execute(conn)?;
+    let entry = QueueEntryDao {
+        queue_id: guest.queue_id,
+        user_id: None,
+        order: next_order(conn, &guest.queue_id)?,
+        has_priority: false,
+        is_held: false,
+        joined_at: guest.created_at,
+        id: Uuid::new_v4(),
+        guest_id: Some(guest.id),
+    };
+    diesel::insert_into(queue_entries::table)
+        .values(entry)
+        .execute(conn)
+}
 
-    diesel::delete(to_del).execute(conn)?;
+/// Removes the entry of a user or a guest, guests are removed with it.
+pub fn delete_entry(conn: &DbConnection, queue_id: &Uuid, member_id: &Uuid) -> QueryResult<usize> {
+    use crate::db::schema::{guests, queue_entries as qe};
 
-    Ok(())
+    let guest = guests::table.filter(guests::queue_id.eq(queue_id).and(guests::id.eq(member_id)));
+    let deleted = diesel::delete(guest).execute(conn)?;
+    if deleted > 0 {
+        // The entry is deleted by the cascade
+        return Ok(deleted);
+    }
+
+    let to_del = qe::table.filter(qe::queue_id.eq(&queue_id).and(qe::user_id.eq(&member_id)));
+
+    diesel::delete(to_del).execute(conn)
 }
 
-pub fn entries_ordered(conn: &DbConnection, q_id: &Uuid) -> QueryResult<Vec<QueueEntryDao>> {
-    use crate::db::schema::queue_entries::dsl as qe;
+/// Entries of the queue in order, with the guest of guest entries.
+pub fn entries_ordered(
+    conn: &DbConnection,
+    q_id: &Uuid,
+) -> QueryResult<Vec<(QueueEntryDao, Option<GuestDao>)>> {
+    use crate::db::schema::{guests, queue_entries as qe};
 
-    let entries = qe::queue_entries
+    qe::table
+        .left_join(guests::table)
         .filter(qe::queue_id.eq(q_id))
         .order_by((qe::is_held.desc(), qe::order))
-        .load::<QueueEntryDao>(conn);
-    entries
+        .load::<(QueueEntryDao, Option<GuestDao>)>(conn)
+}
+
+/// Keys of the last entry of the previous page.
+#[derive(Clone, Debug)]
+pub struct EntryAfter {
+    pub is_held: bool,
+    pub order: i32,
+    pub id: Uuid,
+}
+
+/// Page of [`entries_ordered`].
+pub fn entries_page(
+    conn: &DbConnection,
+    q_id: &Uuid,
+    after: Option<&EntryAfter>,
+    limit: i64,
+) -> QueryResult<Vec<(QueueEntryDao, Option<GuestDao>)>> {
+    use crate::db::schema::{guests, queue_entries as qe};
+
+    let mut query = qe::table
+        .left_join(guests::table)
+        .filter(qe::queue_id.eq(q_id))
+        .into_boxed();
+    if let Some(after) = after {
+        // Held entries come first
+        query = query.filter(
+            qe::is_held.lt(after.is_held).or(qe::is_held.eq(after.is_held).and(
+                qe::order
+                    .gt(after.order)
+                    .or(qe::order.eq(after.order).and(qe::id.gt(after.id))),
+            )),
+        );
+    }
+    query
+        .order_by((qe::is_held.desc(), qe::order, qe::id))
+        .limit(limit)
+        .load::<(QueueEntryDao, Option<GuestDao>)>(conn)
+}
+
+/// Queues the user is a member of, in the order of joining.
+pub fn memberships(conn: &DbConnection, user: &Uuid) -> QueryResult<Vec<(QueueEntryDao, QueueDao)>> {
+    use crate::db::schema::*;
+    queue_entries::table
+        .inner_join(queues::table)
+        .filter(queue_entries::user_id.eq(user))
+        .order_by(queue_entries::joined_at)
+        .load::<(QueueEntryDao, QueueDao)>(conn)
+}
+
+// ---------
+// JoinCodes
+// ---------
+
+pub fn add_join_code(conn: &DbConnection, join_code: &JoinCodeDao) -> QueryResult<usize> {
+    use crate::db::schema::join_codes::dsl::*;
+    diesel::insert_into(join_codes).values(join_code).execute(conn)
+}
+
+pub fn has_join_code(conn: &DbConnection, code_str: &str) -> QueryResult<bool> {
+    use crate::db::schema::join_codes::dsl::*;
+    diesel::select(diesel::dsl::exists(join_codes.filter(code.eq(code_str)))).get_result(conn)
+}
+
+/// The newest code of the queue that is not revoked, expired or used up.
+pub fn active_join_code(conn: &DbConnection, q_id: &Uuid, now: NaiveDateTime) -> QueryResult<Option<JoinCodeDao>> {
+    use crate::db::schema::join_codes::dsl::*;
+    join_codes
+        .filter(queue_id.eq(q_id).and(revoked_at.is_null()))
+        .filter(expires_at.is_null().or(expires_at.gt(now)))
+        .filter(sql::<Bool>("(max_uses is null or uses < max_uses)"))
+        .order_by(created_at.desc())
+        .first::<JoinCodeDao>(conn)
+        .optional()
+}
+
+pub fn revoke_join_codes(conn: &DbConnection, q_id: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::join_codes::dsl::*;
+    diesel::update(join_codes.filter(queue_id.eq(q_id).and(revoked_at.is_null())))
+        .set(revoked_at.eq(now))
+        .execute(conn)
+}
+
+/// Counts a use of an active code and returns its queue.
+pub fn use_join_code(conn: &DbConnection, code_str: &str, now: NaiveDateTime) -> QueryResult<Option<Uuid>> {
+    use crate::db::schema::join_codes::dsl::*;
+    let active = join_codes
+        .filter(code.eq(code_str).and(revoked_at.is_null()))
+        .filter(expires_at.is_null().or(expires_at.gt(now)))
+        .filter(sql::<Bool>("(max_uses is null or uses < max_uses)"));
+    diesel::update(active)
+        .set(uses.eq(uses + 1))
+        .returning(queue_id)
+        .get_result::<Uuid>(conn)
+        .optional()
+}
+
+/// Takes back a use counted by [`use_join_code`].
+pub fn release_join_code(conn: &DbConnection, code_str: &str) -> QueryResult<usize> {
+    use crate::db::schema::join_codes::dsl::*;
+    diesel::update(join_codes.filter(code.eq(code_str).and(uses.gt(0))))
+        .set(uses.eq(uses - 1))
+        .execute(conn)
+}
+
+// ------------
+// JoinRequests
+// ------------
+
+pub fn has_entry(conn: &DbConnection, q_id: &Uuid, u_id: &Uuid) -> QueryResult<bool> {
+    use crate::db::schema::queue_entries::dsl::*;
+    diesel::select(diesel::dsl::exists(
+        queue_entries.filter(queue_id.eq(q_id).and(user_id.eq(u_id))),
+    ))
+    .get_result(conn)
+}
+
+pub fn add_join_request(conn: &DbConnection, request: &JoinRequestDao) -> QueryResult<usize> {
+    use crate::db::schema::join_requests::dsl::*;
+    diesel::insert_into(join_requests).values(request).execute(conn)
+}
+
+/// Pending requests of the queue with the names of the users, the oldest
+/// first. `after` is the time and the id of the last request of the previous
+/// page.
+pub fn join_requests_page(
+    conn: &DbConnection,
+    q_id: &Uuid,
+    after: Option<(NaiveDateTime, Uuid)>,
+    limit: i64,
+) -> QueryResult<Vec<(JoinRequestDao, String)>> {
+    use crate::db::schema::{join_requests as jr, users};
+
+    let mut query = jr::table
+        .inner_join(users::table)
+        .select((jr::all_columns, users::name))
+        .filter(jr::queue_id.eq(q_id))
+        .into_boxed();
+    if let Some((time, last_id)) = after {
+        query = query.filter(
+            jr::requested_at
+                .gt(time)
+                .or(jr::requested_at.eq(time).and(jr::id.gt(last_id))),
+        );
+    }
+    query
+        .order_by((jr::requested_at, jr::id))
+        .limit(limit)
+        .load::<(JoinRequestDao, String)>(conn)
+}
+
+/// Deletes the request and returns it, for approving or rejecting.
+pub fn take_join_request(
+    conn: &DbConnection,
+    q_id: &Uuid,
+    request_id: &Uuid,
+) -> QueryResult<Option<JoinRequestDao>> {
+    use crate::db::schema::join_requests::dsl::*;
+    diesel::delete(join_requests.filter(queue_id.eq(q_id).and(id.eq(request_id))))
+        .get_result::<JoinRequestDao>(conn)
+        .optional()
+}
+
+/// Deletes all requests of the queue and returns them.
+pub fn take_join_requests(conn: &DbConnection, q_id: &Uuid) -> QueryResult<Vec<JoinRequestDao>> {
+    use crate::db::schema::join_requests::dsl::*;
+    diesel::delete(join_requests.filter(queue_id.eq(q_id))).get_results::<JoinRequestDao>(conn)
+}
+
+/// Pending requests of the user with their queues, the oldest first.
+pub fn join_requests_of_user(
+    conn: &DbConnection,
+    user: &Uuid,
+) -> QueryResult<Vec<(JoinRequestDao, QueueDao)>> {
+    use crate::db::schema::*;
+    join_requests::table
+        .inner_join(queues::table)
+        .filter(join_requests::user_id.eq(user))
+        .order_by(join_requests::requested_at)
+        .load::<(JoinRequestDao, QueueDao)>(conn)
+}
+
+pub fn delete_join_request_of(conn: &DbConnection, q_id: &Uuid, u_id: &Uuid) -> QueryResult<usize> {
+    use crate::db::schema::join_requests::dsl::*;
+    diesel::delete(join_requests.filter(queue_id.eq(q_id).and(user_id.eq(u_id)))).execute(conn)
+}
+
+// -------------
+// RefreshTokens
+// -------------
+
+pub fn add_refresh_token(conn: &DbConnection, token: &RefreshTokenDao) -> QueryResult<usize> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+    diesel::insert_into(refresh_tokens).values(token).execute(conn)
+}
+
+pub fn refresh_token_by_hash(conn: &DbConnection, hash: &str) -> QueryResult<Option<RefreshTokenDao>> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+    refresh_tokens
+        .filter(token_hash.eq(hash))
+        .first::<RefreshTokenDao>(conn)
+        .optional()
+}
+
+/// Marks the token as used. Returns 0 if it was already used by someone else.
+pub fn use_refresh_token(conn: &DbConnection, token_id: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::refresh_tokens::dsl::*;
+    diesel::update(refresh_tokens.filter(id.eq(token_id).and(used_at.is_null())))
+        .set(used_at.eq(now))
+        .execute(conn)
+}
+
+// --------
+// Sessions
+// --------
+
+pub fn add_session(conn: &DbConnection, session: &SessionDao) -> QueryResult<usize> {
+    use crate::db::schema::sessions::dsl::*;
+    diesel::insert_into(sessions).values(session).execute(conn)
+}
+
+pub fn session_by_id(conn: &DbConnection, session_id: &Uuid) -> QueryResult<Option<SessionDao>> {
+    use crate::db::schema::sessions::dsl::*;
+    sessions
+        .filter(id.eq(session_id))
+        .first::<SessionDao>(conn)
+        .optional()
+}
+
+pub fn active_sessions(conn: &DbConnection, user: &Uuid) -> QueryResult<Vec<SessionDao>> {
+    use crate::db::schema::sessions::dsl::*;
+    sessions
+        .filter(user_id.eq(user).and(revoked_at.is_null()))
+        .order_by(last_used_at.desc())
+        .load::<SessionDao>(conn)
+}
+
+/// All sessions of the user including revoked ones, oldest first.
+pub fn all_sessions(conn: &DbConnection, user: &Uuid) -> QueryResult<Vec<SessionDao>> {
+    use crate::db::schema::sessions::dsl::*;
+    sessions
+        .filter(user_id.eq(user))
+        .order_by(created_at)
+        .load::<SessionDao>(conn)
+}
+
+pub fn touch_session(conn: &DbConnection, session_id: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::sessions::dsl::*;
+    diesel::update(sessions.filter(id.eq(session_id)))
+        .set(last_used_at.eq(now))
+        .execute(conn)
+}
+
+/// Revokes the given sessions of the user, or all of them if `session_ids`
+/// is `None`. Returns ids of revoked sessions.
+pub fn revoke_sessions(
+    conn: &DbConnection,
+    user: &Uuid,
+    session_ids: Option<&[Uuid]>,
+    now: NaiveDateTime,
+) -> QueryResult<Vec<Uuid>> {
+    use crate::db::schema::sessions::dsl::*;
+    let active = sessions.filter(user_id.eq(user).and(revoked_at.is_null()));
+    match session_ids {
+        Some(ids) => diesel::update(active.filter(id.eq_any(ids)))
+            .set(revoked_at.eq(now))
+            .returning(id)
+            .get_results(conn),
+        None => diesel::update(active)
+            .set(revoked_at.eq(now))
+            .returning(id)
+            .get_results(conn),
+    }
+}
+
+/// Revokes all active sessions of the user except `keep`.
+pub fn revoke_other_sessions(
+    conn: &DbConnection,
+    user: &Uuid,
+    keep: &Uuid,
+    now: NaiveDateTime,
+) -> QueryResult<Vec<Uuid>> {
+    use crate::db::schema::sessions::dsl::*;
+    diesel::update(sessions.filter(user_id.eq(user).and(revoked_at.is_null()).and(id.ne(keep))))
+        .set(revoked_at.eq(now))
+        .returning(id)
+        .get_results(conn)
+}
+
+// ---------
+// ApiTokens
+// ---------
+
+pub fn add_api_token(conn: &DbConnection, token: &ApiTokenDao) -> QueryResult<usize> {
+    use crate::db::schema::api_tokens::dsl::*;
+    diesel::insert_into(api_tokens).values(token).execute(conn)
+}
+
+pub fn api_token_by_hash(conn: &DbConnection, hash: &str) -> QueryResult<Option<ApiTokenDao>> {
+    use crate::db::schema::api_tokens::dsl::*;
+    api_tokens
+        .filter(token_hash.eq(hash))
+        .first::<ApiTokenDao>(conn)
+        .optional()
+}
+
+/// Tokens of the user that are neither revoked nor expired, newest first.
+pub fn active_api_tokens(conn: &DbConnection, user: &Uuid, now: NaiveDateTime) -> QueryResult<Vec<ApiTokenDao>> {
+    use crate::db::schema::api_tokens::dsl::*;
+    api_tokens
+        .filter(user_id.eq(user).and(revoked_at.is_null()))
+        .filter(expires_at.is_null().or(expires_at.gt(now)))
+        .order_by(created_at.desc())
+        .load::<ApiTokenDao>(conn)
+}
+
+pub fn touch_api_token(conn: &DbConnection, token_id: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::api_tokens::dsl::*;
+    diesel::update(api_tokens.filter(id.eq(token_id)))
+        .set(last_used_at.eq(now))
+        .execute(conn)
+}
+
+pub fn revoke_api_token(
+    conn: &DbConnection,
+    user: &Uuid,
+    token_id: &Uuid,
+    now: NaiveDateTime,
+) -> QueryResult<usize> {
+    use crate::db::schema::api_tokens::dsl::*;
+    diesel::update(api_tokens.filter(id.eq(token_id).and(user_id.eq(user)).and(revoked_at.is_null())))
+        .set(revoked_at.eq(now))
+        .execute(conn)
+}
+
+// --------------
+// UserIdentities
+// --------------
+
+pub fn add_user_identity(conn: &DbConnection, identity: &UserIdentityDao) -> QueryResult<usize> {
+    use crate::db::schema::user_identities::dsl::*;
+    diesel::insert_into(user_identities).values(identity).execute(conn)
+}
+
+pub fn user_by_identity(
+    conn: &DbConnection,
+    provider_name: &str,
+    subject_id: &str,
+) -> QueryResult<Option<UserDao>> {
+    use crate::db::schema::user_identities::dsl::*;
+    use crate::db::schema::users;
+    user_identities
+        .inner_join(users::table)
+        .filter(provider.eq(provider_name).and(subject.eq(subject_id)))
+        .select(users::all_columns)
+        .first::<UserDao>(conn)
+        .optional()
+}
+
+// -----------------------
+// EmailVerificationTokens
+// -----------------------
+
+pub fn add_email_verification_token(conn: &DbConnection, token: &EmailVerificationTokenDao) -> QueryResult<usize> {
+    use crate::db::schema::email_verification_tokens::dsl::*;
+    diesel::insert_into(email_verification_tokens).values(token).execute(conn)
+}
+
+/// Marks an unused and unexpired token as used and returns its user and email.
+pub fn use_email_verification_token(
+    conn: &DbConnection,
+    hash: &str,
+    now: NaiveDateTime,
+) -> QueryResult<Option<(Uuid, String)>> {
+    use crate::db::schema::email_verification_tokens::dsl::*;
+    let usable = token_hash
+        .eq(hash)
+        .and(used_at.is_null())
+        .and(expires_at.gt(now));
+    diesel::update(email_verification_tokens.filter(usable))
+        .set(used_at.eq(now))
+        .returning((user_id, email))
+        .get_result(conn)
+        .optional()
+}
+
+/// Marks all unused tokens of the user as used.
+pub fn discard_email_verification_tokens(conn: &DbConnection, user: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::email_verification_tokens::dsl::*;
+    diesel::update(email_verification_tokens.filter(user_id.eq(user).and(used_at.is_null())))
+        .set(used_at.eq(now))
+        .execute(conn)
+}
+
+// -------------------
+// PasswordResetTokens
+// -------------------
+
+pub fn add_password_reset_token(conn: &DbConnection, token: &PasswordResetTokenDao) -> QueryResult<usize> {
+    use crate::db::schema::password_reset_tokens::dsl::*;
+    diesel::insert_into(password_reset_tokens).values(token).execute(conn)
+}
+
+/// Marks an unused and unexpired token as used and returns its user.
+pub fn use_password_reset_token(
+    conn: &DbConnection,
+    hash: &str,
+    now: NaiveDateTime,
+) -> QueryResult<Option<Uuid>> {
+    use crate::db::schema::password_reset_tokens::dsl::*;
+    let usable = token_hash
+        .eq(hash)
+        .and(used_at.is_null())
+        .and(expires_at.gt(now));
+    diesel::update(password_reset_tokens.filter(usable))
+        .set(used_at.eq(now))
+        .returning(user_id)
+        .get_result(conn)
+        .optional()
+}
+
+/// Marks all unused tokens of the user as used.
+pub fn discard_password_reset_tokens(conn: &DbConnection, user: &Uuid, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::password_reset_tokens::dsl::*;
+    diesel::update(password_reset_tokens.filter(user_id.eq(user).and(used_at.is_null())))
+        .set(used_at.eq(now))
+        .execute(conn)
+}
+
+// -------
+// JwtKeys
+// -------
+
+pub fn jwt_keys_valid_at(conn: &DbConnection, now: NaiveDateTime) -> QueryResult<Vec<JwtKeyDao>> {
+    use crate::db::schema::jwt_keys::dsl::*;
+    jwt_keys
+        .filter(expires_at.is_null().or(expires_at.gt(now)))
+        .order_by(created_at)
+        .load::<JwtKeyDao>(conn)
+}
+
+/// Held by the instance changing the keys until its transaction ends.
+pub fn lock_jwt_keys(conn: &DbConnection) -> QueryResult<()> {
+    const JWT_KEYS_LOCK: i64 = 0x6f71_6a77_746b;
+    diesel::sql_query("select pg_advisory_xact_lock($1)")
+        .bind::<BigInt, _>(JWT_KEYS_LOCK)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn newest_signing_jwt_key(conn: &DbConnection) -> QueryResult<Option<JwtKeyDao>> {
+    use crate::db::schema::jwt_keys::dsl::*;
+    jwt_keys
+        .filter(expires_at.is_null())
+        .order_by(created_at.desc())
+        .first::<JwtKeyDao>(conn)
+        .optional()
+}
+
+pub fn add_jwt_key(conn: &DbConnection, key: &JwtKeyDao) -> QueryResult<usize> {
+    use crate::db::schema::jwt_keys::dsl::*;
+    diesel::insert_into(jwt_keys).values(key).execute(conn)
+}
+
+/// Sets expiration time of all keys that are still used for signing.
+pub fn retire_jwt_keys(conn: &DbConnection, retire_at: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::jwt_keys::dsl::*;
+    diesel::update(jwt_keys.filter(expires_at.is_null()))
+        .set(expires_at.eq(retire_at))
+        .execute(conn)
+}
+
+pub fn delete_expired_jwt_keys(conn: &DbConnection, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::db::schema::jwt_keys::dsl::*;
+    diesel::delete(jwt_keys.filter(expires_at.le(now))).execute(conn)
 }
 
 // ----------
@@ -1,17 +1,24 @@
-use std::borrow::Borrow;
-use std::ops::Deref;
-
-use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
-use diesel::result::QueryResult;
 use diesel::PgConnection;
+use chrono::NaiveDateTime;
+use diesel::Connection;
 use r2d2::{Pool, PooledConnection};
 use uuid::Uuid;
 
-use crate::db::actions::QueueEntryToAdd;
-use crate::db::models::{QueueDao, QueueEntryDao, UserDao};
+use crate::db::actions::{
+    AvailableQueue, EntryAfter, QueueEntryToAdd, QueueListing, QueueSearch,
+};
+use crate::db::models::{
+    ApiTokenDao, EmailVerificationTokenDao, GuestDao, JoinCodeDao, JoinRequestDao, JwtKeyDao,
+    PasswordResetTokenDao, QueueDao, QueueEntryDao, QueueSettingsChangeset, RefreshTokenDao,
+    SessionDao, UserDao, UserIdentityDao,
+};
 
+// Diesel 1.4 expands `table!` and its derives into impls nested in
+// functions, which the lint can't be silenced for item by item.
+#[allow(non_local_definitions)]
 pub mod models;
+#[allow(non_local_definitions)]
 mod schema;
 
 pub mod actions;
@@ -40,7 +47,7 @@ impl From<diesel::result::Error> for Error {
     }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
 
 // ---------
 // DbService
@@ -76,16 +83,117 @@ impl DbService {
         Ok(actions::user_by_email(conn, email_str)?)
     }
 
+    pub fn user_by_username(&self, username: &str) -> Result<Option<UserDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::user_by_username(conn, username)?)
+    }
+
     pub fn user_by_id(&self, user_id: &Uuid) -> Result<Option<UserDao>> {
         let conn = &*self.conn()?;
         Ok(actions::user_by_id(conn, user_id)?)
     }
 
+    pub fn user_locale(&self, user_id: &Uuid) -> Result<Option<String>> {
+        let conn = &*self.conn()?;
+        Ok(actions::user_locale(conn, user_id)?)
+    }
+
+    pub fn set_user_locale(&self, user_id: &Uuid, locale: Option<&str>) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let updated = actions::set_user_locale(conn, user_id, locale)?;
+        Ok(updated > 0)
+    }
+
+    /// Deletes the user with their memberships, sessions and tokens. Queues
+    /// they organize are handed to their first member with an account when
+    /// `transfer_queues` is set and deleted otherwise. Returns the ids of revoked sessions.
+    pub fn delete_user(&self, user_id: &Uuid, transfer_queues: bool, now: NaiveDateTime) -> Result<Vec<Uuid>> {
+        let conn = &*self.conn()?;
+        let revoked = conn.transaction::<_, diesel::result::Error, _>(|| {
+            let revoked = actions::revoke_sessions(conn, user_id, None, now)?;
+            for queue in actions::organized_queues(conn, user_id)? {
+                let successor = if transfer_queues {
+                    actions::entries_ordered(conn, &queue.id)?
+                        .into_iter()
+                        .find_map(|(e, _)| e.user_id.filter(|id| id != user_id))
+                } else {
+                    None
+                };
+                match successor {
+                    Some(successor) => actions::set_queue_organizer(conn, &queue.id, &successor)?,
+                    None => actions::delete_queue(conn, &queue.id)?,
+                };
+            }
+            actions::delete_user(conn, user_id)?;
+            Ok(revoked)
+        })?;
+        Ok(revoked)
+    }
+
+    pub fn set_user_name(&self, user_id: &Uuid, name: &str) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let updated = actions::set_user_name(conn, user_id, name)?;
+        Ok(updated > 0)
+    }
+
+    /// Sets or clears the username, fails with a unique violation when it
+    /// is taken.
+    pub fn set_user_username(&self, user_id: &Uuid, username: Option<&str>) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let updated = actions::set_user_username(conn, user_id, username)?;
+        Ok(updated > 0)
+    }
+
+    /// Replaces the password hash without touching sessions, used when the
+    /// hash is upgraded.
+    pub fn set_user_pwhash(&self, user_id: &Uuid, pwhash: &str) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let updated = actions::set_user_pwhash(conn, user_id, pwhash)?;
+        Ok(updated > 0)
+    }
+
+    /// Sets a new unverified email. Links sent to the previous one stop working.
+    pub fn change_email(&self, user_id: &Uuid, email: &str, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let updated = conn.transaction::<_, diesel::result::Error, _>(|| {
+            if actions::set_user_email(conn, user_id, email)? == 0 {
+                return Ok(false);
+            }
+            actions::discard_email_verification_tokens(conn, user_id, now)?;
+            actions::discard_password_reset_tokens(conn, user_id, now)?;
+            Ok(true)
+        })?;
+        Ok(updated)
+    }
+
+    /// Sets a new password and revokes all sessions except `keep_session`.
+    /// Returns the ids of the revoked sessions.
+    pub fn change_password(
+        &self,
+        user_id: &Uuid,
+        pwhash: &str,
+        keep_session: &Uuid,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Uuid>> {
+        let conn = &*self.conn()?;
+        let revoked = conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::set_user_pwhash(conn, user_id, pwhash)?;
+            actions::discard_password_reset_tokens(conn, user_id, now)?;
+            actions::revoke_other_sessions(conn, user_id, keep_session, now)
+        })?;
+        Ok(revoked)
+    }
+
     pub fn has_user_with_email(&self, email_str: &str) -> Result<bool> {
         let conn = &*self.conn()?;
         Ok(actions::has_user_with_email(conn, email_str)?)
     }
 
+    pub fn has_user_with_username(&self, username: &str) -> Result<bool> {
+        let conn = &*self.conn()?;
+        Ok(actions::user_by_username(conn, username)?.is_some())
+    }
+
     // ------
     // Queue
     // ------
@@ -107,14 +215,56 @@ impl DbService {
         Ok(actions::queue_by_id(conn, queue_id)?)
     }
 
+    #[allow(dead_code)]
     pub fn queues_with_member(&self, user_id: &Uuid) -> Result<Vec<QueueDao>> {
         let conn = &*self.conn()?;
         Ok(actions::queues_with_member(conn, user_id)?)
     }
 
-    pub fn available_queues(&self, user_id: &Uuid) -> Result<Vec<QueueDao>> {
+    /// Pending join requests are approved when the approval is turned off.
+    pub fn update_queue_settings(&self, queue_id: &Uuid, changes: &QueueSettingsChangeset) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::update_queue_settings(conn, queue_id, changes)?;
+            if changes.require_approval != Some(false) {
+                return Ok(());
+            }
+            let mut requests = actions::take_join_requests(conn, queue_id)?;
+            requests.sort_by_key(|request| request.requested_at);
+            for request in requests {
+                // Added by the organizer meanwhile
+                if actions::has_entry(conn, queue_id, &request.user_id)? {
+                    continue;
+                }
+                let entry = QueueEntryToAdd {
+                    queue_id: request.queue_id,
+                    user_id: request.user_id,
+                    has_priority: false,
+                    joined_at: request.requested_at,
+                };
+                actions::add_entry_in_time(conn, &entry)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn organized_queues(&self, user_id: &Uuid) -> Result<Vec<QueueDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::organized_queues(conn, user_id)?)
+    }
+
+    pub fn available_queues(&self, listing: &QueueListing) -> Result<Vec<AvailableQueue>> {
         let conn = &*self.conn()?;
-        Ok(actions::available_queues(conn, user_id)?)
+        Ok(actions::available_queues(conn, listing)?)
+    }
+
+    pub fn search_public_queues(&self, search: &QueueSearch) -> Result<Vec<(QueueDao, f32)>> {
+        let conn = &*self.conn()?;
+        Ok(actions::search_public_queues(conn, search)?)
     }
 
     // ------------
@@ -127,14 +277,381 @@ impl DbService {
         Ok(())
     }
 
-    pub fn delete_entry(&self, queue_id: &Uuid, user_id: &Uuid) -> Result<()> {
+    pub fn delete_entry(&self, queue_id: &Uuid, user_id: &Uuid) -> Result<bool> {
         let conn = &*self.conn()?;
-        actions::delete_entry(conn, queue_id, user_id)?;
-        Ok(())
+        let deleted = actions::delete_entry(conn, queue_id, user_id)?;
+        Ok(deleted > 0)
+    }
+
+    pub fn memberships(&self, user_id: &Uuid) -> Result<Vec<(QueueEntryDao, QueueDao)>> {
+        let conn = &*self.conn()?;
+        Ok(actions::memberships(conn, user_id)?)
     }
 
-    pub fn entries_ordered(&self, queue_id: &Uuid) -> Result<Vec<QueueEntryDao>> {
+    pub fn entries_ordered(&self, queue_id: &Uuid) -> Result<Vec<(QueueEntryDao, Option<GuestDao>)>> {
         let conn = &*self.conn()?;
         Ok(actions::entries_ordered(conn, queue_id)?)
     }
+
+    pub fn entries_page(
+        &self,
+        queue_id: &Uuid,
+        after: Option<&EntryAfter>,
+        limit: i64,
+    ) -> Result<Vec<(QueueEntryDao, Option<GuestDao>)>> {
+        let conn = &*self.conn()?;
+        Ok(actions::entries_page(conn, queue_id, after, limit)?)
+    }
+
+    pub fn add_guest_entry(&self, guest: &GuestDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::add_guest_entry(conn, guest)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // ---------
+    // JoinCodes
+    // ---------
+
+    pub fn has_join_code(&self, code: &str) -> Result<bool> {
+        let conn = &*self.conn()?;
+        Ok(actions::has_join_code(conn, code)?)
+    }
+
+    pub fn active_join_code(&self, queue_id: &Uuid, now: NaiveDateTime) -> Result<Option<JoinCodeDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::active_join_code(conn, queue_id, now)?)
+    }
+
+    /// Revokes the codes of the queue and adds a new one.
+    pub fn replace_join_code(&self, join_code: &JoinCodeDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::revoke_join_codes(conn, &join_code.queue_id, join_code.created_at)?;
+            actions::add_join_code(conn, join_code)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn revoke_join_codes(&self, queue_id: &Uuid, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let revoked = actions::revoke_join_codes(conn, queue_id, now)?;
+        Ok(revoked > 0)
+    }
+
+    pub fn use_join_code(&self, code: &str, now: NaiveDateTime) -> Result<Option<Uuid>> {
+        let conn = &*self.conn()?;
+        Ok(actions::use_join_code(conn, code, now)?)
+    }
+
+    pub fn release_join_code(&self, code: &str) -> Result<()> {
+        let conn = &*self.conn()?;
+        actions::release_join_code(conn, code)?;
+        Ok(())
+    }
+
+    // ------------
+    // JoinRequests
+    // ------------
+
+    /// Adds the request unless the user is already a member, returns
+    /// whether it was added.
+    pub fn add_join_request(&self, request: &JoinRequestDao) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let added = conn.transaction::<_, diesel::result::Error, _>(|| {
+            if actions::has_entry(conn, &request.queue_id, &request.user_id)? {
+                return Ok(false);
+            }
+            actions::add_join_request(conn, request)?;
+            Ok(true)
+        })?;
+        Ok(added)
+    }
+
+    pub fn join_requests_page(
+        &self,
+        queue_id: &Uuid,
+        after: Option<(NaiveDateTime, Uuid)>,
+        limit: i64,
+    ) -> Result<Vec<(JoinRequestDao, String)>> {
+        let conn = &*self.conn()?;
+        Ok(actions::join_requests_page(conn, queue_id, after, limit)?)
+    }
+
+    /// Moves the request into the queue at the time it was made, or only
+    /// drops it if the user has joined since. Returns `None` if there is no
+    /// such request.
+    pub fn approve_join_request(
+        &self,
+        queue_id: &Uuid,
+        request_id: &Uuid,
+    ) -> Result<Option<JoinRequestDao>> {
+        let conn = &*self.conn()?;
+        let request = conn.transaction::<_, diesel::result::Error, _>(|| {
+            let request = match actions::take_join_request(conn, queue_id, request_id)? {
+                Some(request) => request,
+                None => return Ok(None),
+            };
+            if actions::has_entry(conn, &request.queue_id, &request.user_id)? {
+                return Ok(Some(request));
+            }
+            let entry = QueueEntryToAdd {
+                queue_id: request.queue_id,
+                user_id: request.user_id,
+                has_priority: false,
+                joined_at: request.requested_at,
+            };
+            actions::add_entry_in_time(conn, &entry)?;
+            Ok(Some(request))
+        })?;
+        Ok(request)
+    }
+
+    pub fn join_requests_of_user(&self, user_id: &Uuid) -> Result<Vec<(JoinRequestDao, QueueDao)>> {
+        let conn = &*self.conn()?;
+        Ok(actions::join_requests_of_user(conn, user_id)?)
+    }
+
+    pub fn reject_join_request(
+        &self,
+        queue_id: &Uuid,
+        request_id: &Uuid,
+    ) -> Result<Option<JoinRequestDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::take_join_request(conn, queue_id, request_id)?)
+    }
+
+    pub fn delete_join_request_of(&self, queue_id: &Uuid, user_id: &Uuid) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let deleted = actions::delete_join_request_of(conn, queue_id, user_id)?;
+        Ok(deleted > 0)
+    }
+
+    // -------------
+    // RefreshTokens
+    // -------------
+
+    pub fn refresh_token_by_hash(&self, hash: &str) -> Result<Option<RefreshTokenDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::refresh_token_by_hash(conn, hash)?)
+    }
+
+    /// Replaces `old` with `new` in one transaction.
+    /// Returns `false` if `old` has already been rotated.
+    pub fn rotate_refresh_token(&self, old: &RefreshTokenDao, new: &RefreshTokenDao, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let rotated = conn.transaction::<_, diesel::result::Error, _>(|| {
+            if actions::use_refresh_token(conn, &old.id, now)? == 0 {
+                return Ok(false);
+            }
+            actions::add_refresh_token(conn, new)?;
+            actions::touch_session(conn, &new.session_id, now)?;
+            Ok(true)
+        })?;
+        Ok(rotated)
+    }
+
+    // --------
+    // Sessions
+    // --------
+
+    /// Stores a new session together with its first refresh token.
+    pub fn start_session(&self, session: &SessionDao, token: &RefreshTokenDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::add_session(conn, session)?;
+            actions::add_refresh_token(conn, token)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn session_by_id(&self, session_id: &Uuid) -> Result<Option<SessionDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::session_by_id(conn, session_id)?)
+    }
+
+    pub fn active_sessions(&self, user_id: &Uuid) -> Result<Vec<SessionDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::active_sessions(conn, user_id)?)
+    }
+
+    pub fn all_sessions(&self, user_id: &Uuid) -> Result<Vec<SessionDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::all_sessions(conn, user_id)?)
+    }
+
+    pub fn revoke_session(&self, user_id: &Uuid, session_id: &Uuid, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let revoked = actions::revoke_sessions(conn, user_id, Some(&[*session_id]), now)?;
+        Ok(!revoked.is_empty())
+    }
+
+    pub fn revoke_all_sessions(&self, user_id: &Uuid, now: NaiveDateTime) -> Result<Vec<Uuid>> {
+        let conn = &*self.conn()?;
+        Ok(actions::revoke_sessions(conn, user_id, None, now)?)
+    }
+
+    // ---------
+    // ApiTokens
+    // ---------
+
+    pub fn add_api_token(&self, token: &ApiTokenDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        actions::add_api_token(conn, token)?;
+        Ok(())
+    }
+
+    pub fn api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiTokenDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::api_token_by_hash(conn, token_hash)?)
+    }
+
+    pub fn active_api_tokens(&self, user_id: &Uuid, now: NaiveDateTime) -> Result<Vec<ApiTokenDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::active_api_tokens(conn, user_id, now)?)
+    }
+
+    pub fn touch_api_token(&self, token_id: &Uuid, now: NaiveDateTime) -> Result<()> {
+        let conn = &*self.conn()?;
+        actions::touch_api_token(conn, token_id, now)?;
+        Ok(())
+    }
+
+    pub fn revoke_api_token(&self, user_id: &Uuid, token_id: &Uuid, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let revoked = actions::revoke_api_token(conn, user_id, token_id, now)?;
+        Ok(revoked > 0)
+    }
+
+    // --------------
+    // UserIdentities
+    // --------------
+
+    pub fn user_by_identity(&self, provider: &str, subject: &str) -> Result<Option<UserDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::user_by_identity(conn, provider, subject)?)
+    }
+
+    pub fn add_user_identity(&self, identity: &UserIdentityDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        actions::add_user_identity(conn, identity)?;
+        Ok(())
+    }
+
+    /// Creates an account for the first login with a provider.
+    pub fn add_user_with_identity(&self, user: &UserDao, identity: &UserIdentityDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::add_user(conn, user)?;
+            actions::add_user_identity(conn, identity)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // -----------------------
+    // EmailVerificationTokens
+    // -----------------------
+
+    /// Stores a new verification token. Tokens issued before stop working.
+    pub fn add_email_verification_token(&self, token: &EmailVerificationTokenDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::discard_email_verification_tokens(conn, &token.user_id, token.created_at)?;
+            actions::add_email_verification_token(conn, token)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Uses the token to mark the email as verified. Returns `false` if the
+    /// token is unknown, used, expired or issued for a previous email.
+    pub fn verify_email(&self, token_hash: &str, now: NaiveDateTime) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let verified = conn.transaction::<_, diesel::result::Error, _>(|| {
+            match actions::use_email_verification_token(conn, token_hash, now)? {
+                Some((user_id, email)) => Ok(actions::set_email_verified(conn, &user_id, &email, now)? > 0),
+                None => Ok(false),
+            }
+        })?;
+        Ok(verified)
+    }
+
+    // -------------------
+    // PasswordResetTokens
+    // -------------------
+
+    /// Stores a new reset token. Tokens issued before stop working.
+    pub fn add_password_reset_token(&self, token: &PasswordResetTokenDao) -> Result<()> {
+        let conn = &*self.conn()?;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::discard_password_reset_tokens(conn, &token.user_id, token.created_at)?;
+            actions::add_password_reset_token(conn, token)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Uses the token to set a new password and revokes all sessions of the
+    /// user. Returns `None` if the token is unknown, used or expired,
+    /// otherwise the ids of the revoked sessions.
+    pub fn reset_password(&self, token_hash: &str, pwhash: &str, now: NaiveDateTime) -> Result<Option<Vec<Uuid>>> {
+        let conn = &*self.conn()?;
+        let revoked = conn.transaction::<_, diesel::result::Error, _>(|| {
+            let user_id = match actions::use_password_reset_token(conn, token_hash, now)? {
+                Some(user_id) => user_id,
+                None => return Ok(None),
+            };
+            actions::set_user_pwhash(conn, &user_id, pwhash)?;
+            actions::discard_password_reset_tokens(conn, &user_id, now)?;
+            let revoked = actions::revoke_sessions(conn, &user_id, None, now)?;
+            Ok(Some(revoked))
+        })?;
+        Ok(revoked)
+    }
+
+    // -------
+    // JwtKeys
+    // -------
+
+    pub fn jwt_keys_valid_at(&self, now: NaiveDateTime) -> Result<Vec<JwtKeyDao>> {
+        let conn = &*self.conn()?;
+        Ok(actions::jwt_keys_valid_at(conn, now)?)
+    }
+
+    /// Adds a new signing key and retires the previous ones at `retire_at`.
+    /// With `unless_created_after` the key is not added when another
+    /// instance has added one since then. Instances take turns.
+    pub fn add_jwt_key(
+        &self,
+        key: &JwtKeyDao,
+        retire_at: NaiveDateTime,
+        unless_created_after: Option<NaiveDateTime>,
+    ) -> Result<bool> {
+        let conn = &*self.conn()?;
+        let is_added = conn.transaction::<_, diesel::result::Error, _>(|| {
+            actions::lock_jwt_keys(conn)?;
+            if let Some(after) = unless_created_after {
+                let newest = actions::newest_signing_jwt_key(conn)?;
+                if newest.is_some_and(|k| k.created_at > after) {
+                    return Ok(false);
+                }
+            }
+            actions::retire_jwt_keys(conn, retire_at)?;
+            actions::add_jwt_key(conn, key)?;
+            Ok(true)
+        })?;
+        Ok(is_added)
+    }
+
+    pub fn delete_expired_jwt_keys(&self, now: NaiveDateTime) -> Result<()> {
+        let conn = &*self.conn()?;
+        actions::delete_expired_jwt_keys(conn, now)?;
+        Ok(())
+    }
 }
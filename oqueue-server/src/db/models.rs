@@ -1,5 +1,4 @@
 use chrono::NaiveDateTime;
-use diesel::prelude::*;
 use uuid::Uuid;
 
 use crate::db::schema::*;
@@ -11,6 +10,9 @@ pub struct UserDao {
     pub name: String,
     pub email: String,
     pub pwhash: String,
+    pub locale: Option<String>,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub username: Option<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Queryable, Insertable)]
@@ -22,15 +24,168 @@ pub struct QueueDao {
     pub organizer_id: Uuid,
     pub created_at: NaiveDateTime,
     pub exists_before: NaiveDateTime,
+    pub require_verified_email: bool,
+    pub allow_guests: bool,
+    pub is_public: bool,
+    /// Set by triggers on changes of the queue and of its entries.
+    pub updated_at: NaiveDateTime,
+    /// Joins wait for the organizer in `join_requests`.
+    pub require_approval: bool,
 }
 
+/// Queue settings to change, `None` fields are kept.
+#[derive(Clone, Debug, Default, AsChangeset)]
+#[table_name = "queues"]
+pub struct QueueSettingsChangeset {
+    pub require_verified_email: Option<bool>,
+    pub allow_guests: Option<bool>,
+    pub is_public: Option<bool>,
+    pub require_approval: Option<bool>,
+}
+
+impl QueueSettingsChangeset {
+    pub fn is_empty(&self) -> bool {
+        self.require_verified_email.is_none()
+            && self.allow_guests.is_none()
+            && self.is_public.is_none()
+            && self.require_approval.is_none()
+    }
+}
+
+/// Entry of a user or a guest, exactly one of `user_id` and `guest_id` is
+/// set.
 #[derive(Clone, Debug, Queryable, Insertable)]
 #[table_name = "queue_entries"]
 pub struct QueueEntryDao {
     pub queue_id: Uuid,
-    pub user_id: Uuid,
+    pub user_id: Option<Uuid>,
     pub order: i32,
     pub has_priority: bool,
     pub is_held: bool,
     pub joined_at: NaiveDateTime,
+    pub id: Uuid,
+    pub guest_id: Option<Uuid>,
+}
+
+/// Person in a queue without an account, known by name only.
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "guests"]
+pub struct GuestDao {
+    pub id: Uuid,
+    pub queue_id: Uuid,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Wish to join a queue that requires approval by the organizer.
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "join_requests"]
+pub struct JoinRequestDao {
+    pub id: Uuid,
+    pub queue_id: Uuid,
+    pub user_id: Uuid,
+    /// Becomes `joined_at` of the entry on approval.
+    pub requested_at: NaiveDateTime,
+}
+
+/// Short code to join a queue with, shown to users as `K7F-29Q`.
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "join_codes"]
+pub struct JoinCodeDao {
+    pub id: Uuid,
+    pub queue_id: Uuid,
+    /// Stored without the dash.
+    pub code: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "refresh_tokens"]
+pub struct RefreshTokenDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "email_verification_tokens"]
+pub struct EmailVerificationTokenDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// Address the token confirms, it may change before the link is used.
+    pub email: String,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "password_reset_tokens"]
+pub struct PasswordResetTokenDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "sessions"]
+pub struct SessionDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "api_tokens"]
+pub struct ApiTokenDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// Space separated list of [`Scope`](crate::auth::scope::Scope)s.
+    pub scopes: String,
+}
+
+/// Account of the user at an OpenID Connect provider.
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "user_identities"]
+pub struct UserIdentityDao {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub provider: String,
+    /// `sub` claim, unique per provider.
+    pub subject: String,
+    /// Email claimed by the provider when the identity was added.
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Queryable, Insertable)]
+#[table_name = "jwt_keys"]
+pub struct JwtKeyDao {
+    pub kid: String,
+    pub algorithm: String,
+    pub encrypted_secret: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
 }
@@ -1,11 +1,91 @@
 table! {
-    queue_entries (queue_id, user_id) {
+    api_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        token_hash -> Bpchar,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        scopes -> Varchar,
+    }
+}
+
+table! {
+    email_verification_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        email -> Varchar,
+        token_hash -> Bpchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    guests (id) {
+        id -> Uuid,
+        queue_id -> Uuid,
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    join_codes (id) {
+        id -> Uuid,
+        queue_id -> Uuid,
+        code -> Bpchar,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        max_uses -> Nullable<Int4>,
+        uses -> Int4,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    join_requests (id) {
+        id -> Uuid,
         queue_id -> Uuid,
         user_id -> Uuid,
+        requested_at -> Timestamp,
+    }
+}
+
+table! {
+    jwt_keys (kid) {
+        kid -> Varchar,
+        algorithm -> Varchar,
+        encrypted_secret -> Text,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_reset_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Bpchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    queue_entries (id) {
+        queue_id -> Uuid,
+        user_id -> Nullable<Uuid>,
         order -> Int4,
         has_priority -> Bool,
         is_held -> Bool,
         joined_at -> Timestamp,
+        id -> Uuid,
+        guest_id -> Nullable<Uuid>,
     }
 }
 
@@ -17,6 +97,46 @@ table! {
         organizer_id -> Uuid,
         created_at -> Timestamp,
         exists_before -> Timestamp,
+        require_verified_email -> Bool,
+        allow_guests -> Bool,
+        is_public -> Bool,
+        updated_at -> Timestamp,
+        require_approval -> Bool,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        session_id -> Uuid,
+        token_hash -> Bpchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        created_at -> Timestamp,
+        last_used_at -> Timestamp,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
     }
 }
 
@@ -25,15 +145,40 @@ table! {
         id -> Uuid,
         name -> Varchar,
         email -> Varchar,
-        pwhash -> Bpchar,
+        pwhash -> Varchar,
+        locale -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+        username -> Nullable<Varchar>,
     }
 }
 
+joinable!(api_tokens -> users (user_id));
+joinable!(email_verification_tokens -> users (user_id));
+joinable!(guests -> queues (queue_id));
+joinable!(join_codes -> queues (queue_id));
+joinable!(join_requests -> queues (queue_id));
+joinable!(join_requests -> users (user_id));
+joinable!(password_reset_tokens -> users (user_id));
+joinable!(queue_entries -> guests (guest_id));
 joinable!(queue_entries -> queues (queue_id));
 joinable!(queue_entries -> users (user_id));
+joinable!(refresh_tokens -> sessions (session_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(sessions -> users (user_id));
+joinable!(user_identities -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    email_verification_tokens,
+    guests,
+    join_codes,
+    join_requests,
+    jwt_keys,
+    password_reset_tokens,
     queue_entries,
     queues,
+    refresh_tokens,
+    sessions,
+    user_identities,
     users,
 );
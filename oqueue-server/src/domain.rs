@@ -2,12 +2,16 @@ use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::auth::scope::Scope;
+
+#[allow(dead_code)]
 pub type Id = Uuid;
 
 // ---------
 // UserId
 // ---------
 
+#[allow(dead_code)]
 #[derive(Clone, Hash, Debug)]
 pub struct UserId(pub Id);
 
@@ -15,6 +19,7 @@ pub struct UserId(pub Id);
 // QueueId
 // ---------
 
+#[allow(dead_code)]
 #[derive(Clone, Hash, Debug)]
 pub struct QueueId(pub Id);
 
@@ -22,6 +27,7 @@ pub struct QueueId(pub Id);
 // User
 // --------
 
+#[allow(dead_code)]
 #[derive(Clone, Hash, Debug)]
 pub struct User {
     id: UserId,
@@ -38,17 +44,86 @@ pub struct User {
 pub struct UserInfo {
     pub id: Uuid,
     pub name: String,
+    pub username: Option<String>,
+}
+
+/// The signed in user as seen by themselves.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ProfileInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub username: Option<String>,
+    pub email: String,
+    pub email_verified: bool,
+    pub locale: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// The session of the token used for this request.
+    pub is_current: bool,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ApiTokenInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct KeyInfo {
+    pub kid: String,
+    pub algorithm: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub is_signing: bool,
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
 pub struct MemberInfo {
+    /// Id of the user, or of the guest for guests.
     pub id: Uuid,
+    /// Set for guests only.
+    pub guest_name: Option<String>,
     pub order: i32,
     pub has_priority: bool,
     pub is_held: bool,
     pub joined_at: NaiveDateTime,
 }
 
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct JoinCodeInfo {
+    /// As shown to users, e.g. `K7F-29Q`.
+    pub code: String,
+    /// Page of the client app that joins with the code.
+    pub url: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+}
+
+/// The entry of a guest as seen by themselves.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct GuestInfo {
+    pub id: Uuid,
+    pub queue_id: Uuid,
+    pub name: String,
+    /// Place in the queue starting from 1.
+    pub position: usize,
+    pub is_held: bool,
+    pub joined_at: NaiveDateTime,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
 pub struct QueueInfo {
     pub id: Uuid,
@@ -57,4 +132,110 @@ pub struct QueueInfo {
     pub organizer_id: Uuid,
     pub created_at: NaiveDateTime,
     pub exists_before: NaiveDateTime,
+    pub require_verified_email: bool,
+    /// Guests can join without an account.
+    pub allow_guests: bool,
+    /// Found by anyone in `/queues/search`.
+    pub is_public: bool,
+    /// Last change of the queue or of its members.
+    pub updated_at: NaiveDateTime,
+    /// Joins wait for the organizer's approval.
+    pub require_approval: bool,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueRole {
+    Organizer,
+    Member,
+}
+
+/// Queue in `/queues`, with the caller's part in it.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct AvailableQueueInfo {
+    #[serde(flatten)]
+    pub queue: QueueInfo,
+    /// `organizer` for own queues, even when also a member.
+    pub role: QueueRole,
+    /// Place of the caller counting from 1, `None` when not a member.
+    pub position: Option<usize>,
+    pub member_count: usize,
+}
+
+/// Join waiting for the organizer of a queue with `require_approval`.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct JoinRequestInfo {
+    pub id: Uuid,
+    pub queue_id: Uuid,
+    pub user_id: Uuid,
+    /// Not set in the response to the join itself.
+    pub user_name: Option<String>,
+    /// The user gets the place of this time on approval.
+    pub requested_at: NaiveDateTime,
+}
+
+/// Part of a list. `next_cursor` is passed as `after` to get the next part,
+/// it is `None` on the last one.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+// ------
+// Export
+// ------
+
+/// Everything stored about the user, returned by `/users/me/export`.
+///
+/// There is no history of past queue participation: an entry is deleted
+/// when the user leaves the queue or is removed, so only current
+/// memberships are exported.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct DataExport {
+    pub exported_at: NaiveDateTime,
+    pub profile: ExportProfile,
+    /// Queues the user is in now.
+    pub memberships: Vec<ExportMembership>,
+    /// Joins still waiting for approval.
+    pub join_requests: Vec<ExportJoinRequest>,
+    pub organized_queues: Vec<QueueInfo>,
+    /// Sign-in history including ended sessions.
+    pub sessions: Vec<ExportSession>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ExportProfile {
+    pub id: Uuid,
+    pub name: String,
+    pub username: Option<String>,
+    pub email: String,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub locale: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ExportMembership {
+    pub queue: QueueInfo,
+    pub order: i32,
+    pub has_priority: bool,
+    pub is_held: bool,
+    pub joined_at: NaiveDateTime,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ExportJoinRequest {
+    pub id: Uuid,
+    pub queue: QueueInfo,
+    pub requested_at: NaiveDateTime,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct ExportSession {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub revoked_at: Option<NaiveDateTime>,
 }
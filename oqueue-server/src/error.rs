@@ -0,0 +1,330 @@
+use std::fmt;
+
+use actix_web::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use diesel::result::{DatabaseErrorKind, Error as QueryError};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{self, Lang};
+
+// --------
+// ApiError
+// --------
+
+/// Error returned by every handler. It is rendered as a JSON [`ErrorBody`]
+/// with a stable machine-readable `code` and a message from the
+/// [`i18n`] catalog.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidRequest(String),
+    InvalidName { min: usize, max: usize },
+    InvalidUsername { min: usize, max: usize },
+    UnsupportedLocale,
+    InvalidEmail,
+    InvalidCredentials,
+    InvalidPassword,
+    ReauthenticationRequired,
+    PasswordTooShort { min: usize },
+    PasswordTooLong { max: usize },
+    PasswordTooCommon,
+    Unauthorized,
+    TooManyAttempts { retry_after: u64 },
+    RateLimited { retry_after: u64 },
+    InvalidToken,
+    TokenExpired,
+    InvalidRefreshToken,
+    RefreshTokenReused,
+    InsufficientScope,
+    InvalidResetToken,
+    InvalidVerificationToken,
+    EmailAlreadyVerified,
+    EmailNotVerified,
+    SessionRevoked,
+    SessionNotFound,
+    ApiTokenNotFound,
+    OidcProviderNotFound,
+    InvalidOidcState,
+    OidcLoginFailed,
+    KeyRotationUnsupported,
+    UserNotFound,
+    EmailTaken,
+    UsernameTaken,
+    QueueNotFound,
+    NotOrganizer,
+    GuestsNotAllowed,
+    JoinCodeNotFound,
+    JoinRequestNotFound,
+    JoinRequestPending,
+    AlreadyMember,
+    MemberNotFound,
+    NotFound,
+    Conflict,
+    Internal,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "INVALID_REQUEST",
+            ApiError::InvalidName { .. } => "INVALID_NAME",
+            ApiError::InvalidUsername { .. } => "INVALID_USERNAME",
+            ApiError::UnsupportedLocale => "UNSUPPORTED_LOCALE",
+            ApiError::InvalidEmail => "INVALID_EMAIL",
+            ApiError::InvalidCredentials => "INVALID_CREDENTIALS",
+            ApiError::InvalidPassword => "INVALID_PASSWORD",
+            ApiError::ReauthenticationRequired => "REAUTHENTICATION_REQUIRED",
+            ApiError::PasswordTooShort { .. } => "PASSWORD_TOO_SHORT",
+            ApiError::PasswordTooLong { .. } => "PASSWORD_TOO_LONG",
+            ApiError::PasswordTooCommon => "PASSWORD_TOO_COMMON",
+            ApiError::Unauthorized => "UNAUTHORIZED",
+            ApiError::TooManyAttempts { .. } => "TOO_MANY_ATTEMPTS",
+            ApiError::RateLimited { .. } => "RATE_LIMITED",
+            ApiError::InvalidToken => "INVALID_TOKEN",
+            ApiError::TokenExpired => "TOKEN_EXPIRED",
+            ApiError::InvalidRefreshToken => "INVALID_REFRESH_TOKEN",
+            ApiError::RefreshTokenReused => "REFRESH_TOKEN_REUSED",
+            ApiError::InsufficientScope => "INSUFFICIENT_SCOPE",
+            ApiError::InvalidResetToken => "INVALID_RESET_TOKEN",
+            ApiError::InvalidVerificationToken => "INVALID_VERIFICATION_TOKEN",
+            ApiError::EmailAlreadyVerified => "EMAIL_ALREADY_VERIFIED",
+            ApiError::EmailNotVerified => "EMAIL_NOT_VERIFIED",
+            ApiError::SessionRevoked => "SESSION_REVOKED",
+            ApiError::SessionNotFound => "SESSION_NOT_FOUND",
+            ApiError::ApiTokenNotFound => "API_TOKEN_NOT_FOUND",
+            ApiError::OidcProviderNotFound => "OIDC_PROVIDER_NOT_FOUND",
+            ApiError::InvalidOidcState => "INVALID_OIDC_STATE",
+            ApiError::OidcLoginFailed => "OIDC_LOGIN_FAILED",
+            ApiError::KeyRotationUnsupported => "KEY_ROTATION_UNSUPPORTED",
+            ApiError::UserNotFound => "USER_NOT_FOUND",
+            ApiError::EmailTaken => "EMAIL_TAKEN",
+            ApiError::UsernameTaken => "USERNAME_TAKEN",
+            ApiError::QueueNotFound => "QUEUE_NOT_FOUND",
+            ApiError::NotOrganizer => "NOT_ORGANIZER",
+            ApiError::GuestsNotAllowed => "GUESTS_NOT_ALLOWED",
+            ApiError::JoinCodeNotFound => "JOIN_CODE_NOT_FOUND",
+            ApiError::JoinRequestNotFound => "JOIN_REQUEST_NOT_FOUND",
+            ApiError::JoinRequestPending => "JOIN_REQUEST_PENDING",
+            ApiError::AlreadyMember => "ALREADY_MEMBER",
+            ApiError::MemberNotFound => "MEMBER_NOT_FOUND",
+            ApiError::NotFound => "NOT_FOUND",
+            ApiError::Conflict => "CONFLICT",
+            ApiError::Internal => "INTERNAL_ERROR",
+        }
+    }
+
+    /// Values substituted into the catalog message.
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ApiError::InvalidRequest(details) => vec![("details", details.clone())],
+            ApiError::InvalidName { min, max } | ApiError::InvalidUsername { min, max } => {
+                vec![("min", min.to_string()), ("max", max.to_string())]
+            }
+            ApiError::PasswordTooShort { min } => vec![("min", min.to_string())],
+            ApiError::PasswordTooLong { max } => vec![("max", max.to_string())],
+            ApiError::TooManyAttempts { retry_after } | ApiError::RateLimited { retry_after } => {
+                vec![("retry_after", retry_after.to_string())]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn body(&self, lang: Lang) -> ErrorBody {
+        ErrorBody {
+            code: self.code().to_string(),
+            message: i18n::message(lang, self),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&i18n::message(Lang::default(), self))
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_)
+            | ApiError::InvalidName { .. }
+            | ApiError::InvalidUsername { .. }
+            | ApiError::UnsupportedLocale
+            | ApiError::InvalidEmail
+            | ApiError::PasswordTooShort { .. }
+            | ApiError::PasswordTooLong { .. }
+            | ApiError::PasswordTooCommon
+            | ApiError::InvalidResetToken
+            | ApiError::InvalidVerificationToken
+            | ApiError::InvalidOidcState => StatusCode::BAD_REQUEST,
+            ApiError::InvalidCredentials
+            | ApiError::Unauthorized
+            | ApiError::InvalidToken
+            | ApiError::TokenExpired
+            | ApiError::InvalidRefreshToken
+            | ApiError::RefreshTokenReused
+            | ApiError::SessionRevoked
+            | ApiError::OidcLoginFailed => StatusCode::UNAUTHORIZED,
+            ApiError::InvalidPassword
+            | ApiError::ReauthenticationRequired
+            | ApiError::InsufficientScope
+            | ApiError::NotOrganizer
+            | ApiError::GuestsNotAllowed
+            | ApiError::EmailNotVerified => StatusCode::FORBIDDEN,
+            ApiError::UserNotFound
+            | ApiError::QueueNotFound
+            | ApiError::MemberNotFound
+            | ApiError::JoinCodeNotFound
+            | ApiError::JoinRequestNotFound
+            | ApiError::SessionNotFound
+            | ApiError::ApiTokenNotFound
+            | ApiError::OidcProviderNotFound
+            | ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::EmailTaken
+            | ApiError::UsernameTaken
+            | ApiError::AlreadyMember
+            | ApiError::JoinRequestPending
+            | ApiError::EmailAlreadyVerified
+            | ApiError::Conflict
+            | ApiError::KeyRotationUnsupported => StatusCode::CONFLICT,
+            ApiError::TooManyAttempts { .. } | ApiError::RateLimited { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Unauthorized => {
+                res.insert_header((WWW_AUTHENTICATE, "Bearer"));
+            }
+            ApiError::InvalidToken | ApiError::TokenExpired | ApiError::SessionRevoked => {
+                let challenge = format!(
+                    "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                    self
+                );
+                res.insert_header((WWW_AUTHENTICATE, challenge));
+            }
+            ApiError::InsufficientScope => {
+                res.insert_header((WWW_AUTHENTICATE, "Bearer error=\"insufficient_scope\""));
+            }
+            ApiError::TooManyAttempts { retry_after } | ApiError::RateLimited { retry_after } => {
+                res.insert_header((RETRY_AFTER, retry_after.to_string()));
+            }
+            _ => {}
+        }
+        res.json(self.body(Lang::default()))
+    }
+}
+
+// -----------
+// Conversions
+// -----------
+
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        error!("{}", e);
+        ApiError::Internal
+    }
+}
+
+impl From<crate::auth::keys::RotationError> for ApiError {
+    fn from(e: crate::auth::keys::RotationError) -> Self {
+        match e {
+            crate::auth::keys::RotationError::UnsupportedAlgorithm(alg) => {
+                error!("Can not generate JWT keys for {:?}", alg);
+                ApiError::KeyRotationUnsupported
+            }
+            crate::auth::keys::RotationError::NoEncryptionKey => {
+                error!("JWT_KEY_ENCRYPTION_KEY must be set to rotate JWT keys");
+                ApiError::KeyRotationUnsupported
+            }
+            crate::auth::keys::RotationError::Db(e) => e.into(),
+        }
+    }
+}
+
+impl From<crate::auth::password::PolicyViolation> for ApiError {
+    fn from(e: crate::auth::password::PolicyViolation) -> Self {
+        match e {
+            crate::auth::password::PolicyViolation::TooShort { min } => {
+                ApiError::PasswordTooShort { min }
+            }
+            crate::auth::password::PolicyViolation::TooLong { max } => {
+                ApiError::PasswordTooLong { max }
+            }
+            crate::auth::password::PolicyViolation::Blocked => ApiError::PasswordTooCommon,
+        }
+    }
+}
+
+impl From<crate::auth::password::HashError> for ApiError {
+    fn from(e: crate::auth::password::HashError) -> Self {
+        error!("{}", e);
+        ApiError::Internal
+    }
+}
+
+impl From<crate::auth::oidc::OidcError> for ApiError {
+    fn from(e: crate::auth::oidc::OidcError) -> Self {
+        match e {
+            crate::auth::oidc::OidcError::UnknownProvider => ApiError::OidcProviderNotFound,
+            crate::auth::oidc::OidcError::InvalidState => ApiError::InvalidOidcState,
+            crate::auth::oidc::OidcError::Provider(e) => {
+                warn!("OIDC login failed: {}", e);
+                ApiError::OidcLoginFailed
+            }
+        }
+    }
+}
+
+impl From<crate::db::Error> for ApiError {
+    fn from(e: crate::db::Error) -> Self {
+        match e {
+            crate::db::Error::Query(e) => e.into(),
+            crate::db::Error::R2D2(e) => {
+                error!("{:?}", e);
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(e: QueryError) -> Self {
+        match &e {
+            QueryError::NotFound => ApiError::NotFound,
+            QueryError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                match info.constraint_name() {
+                    Some("users_email_lower_unique") => ApiError::EmailTaken,
+                    Some("users_username_lower_unique") => ApiError::UsernameTaken,
+                    Some("queue_entries_queue_id_user_id_unique") => ApiError::AlreadyMember,
+                    Some("join_requests_queue_id_user_id_unique") => {
+                        ApiError::JoinRequestPending
+                    }
+                    _ => ApiError::Conflict,
+                }
+            }
+            QueryError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) => {
+                match info.constraint_name() {
+                    Some("fk_queue_id") => ApiError::QueueNotFound,
+                    Some("fk_user_id") => ApiError::UserNotFound,
+                    _ => ApiError::NotFound,
+                }
+            }
+            _ => {
+                error!("{:?}", e);
+                ApiError::Internal
+            }
+        }
+    }
+}
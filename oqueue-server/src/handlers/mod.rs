@@ -1,53 +1,321 @@
-use std::ops::{Add, Sub};
-use std::time::{Duration, UNIX_EPOCH};
-
-use actix_web::error::*;
-use actix_web::web::{Data, Json, Path};
-use actix_web::{HttpRequest, Responder};
-use chrono::{FixedOffset, NaiveDateTime, Utc};
-use log::{debug, error, info};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Add;
+use std::sync::Arc;
+
+use actix_web::http::header::{HeaderMap, CONTENT_DISPOSITION, LOCATION, USER_AGENT};
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use addr::email::Host;
+use chrono::{NaiveDateTime, Utc};
+use log::{error, warn};
 use uuid::Uuid;
 
-use crate::auth::{Auth, JwtConfig};
-use crate::db::actions as db_actions;
-use crate::db::actions::QueueEntryToAdd;
-use crate::db::models::{QueueDao, QueueEntryDao, UserDao};
-use crate::db::{DbConnection, DbPool, DbService};
-use crate::domain::{MemberInfo, QueueInfo, UserInfo};
+use crate::auth::api_token;
+use crate::auth::guest::GuestTicket;
+use crate::auth::jwk::JwkSet;
+use crate::auth::oidc::{Identity, OidcLogin};
+use crate::auth::password::{PasswordHasher, PasswordPolicy, Verified};
+use crate::auth::scope::Scope;
+use crate::auth::session::SessionCache;
+use crate::auth::throttle::SignInThrottle;
+use crate::auth::token::{generate_token, hash_token};
+use crate::auth::{Auth, EmailLinkConfig, JwtConfig};
+use crate::db::actions::{
+    AvailableQueue, EntryAfter, QueueAfter, QueueEntryToAdd, QueueListing, QueueOrder, QueueSearch,
+    SearchAfter,
+};
+use crate::db::models::{
+    ApiTokenDao, EmailVerificationTokenDao, GuestDao, JoinCodeDao, JoinRequestDao,
+    PasswordResetTokenDao, QueueDao, QueueEntryDao, QueueSettingsChangeset, RefreshTokenDao,
+    SessionDao, UserDao, UserIdentityDao,
+};
+use crate::db::DbService;
+use crate::domain::{
+    ApiTokenInfo, AvailableQueueInfo, DataExport, ExportJoinRequest, ExportMembership,
+    ExportProfile, ExportSession, GuestInfo, JoinCodeInfo, JoinRequestInfo, KeyInfo, MemberInfo,
+    ProfileInfo, QueueInfo, QueueRole, SessionInfo, UserInfo,
+};
+use crate::error::ApiError;
 use crate::handlers::req::*;
+use crate::i18n::{self, Lang};
+use crate::join_code;
+use crate::mail::{self, Mailer};
+use crate::qr;
 
+mod page;
 pub mod req;
+#[cfg(test)]
+mod tests;
 
-type Error = actix_web::Error;
-type RespResult<T> = std::result::Result<T, Error>;
-
-impl From<crate::db::Error> for Error {
-    fn from(e: crate::db::Error) -> Self {
-        // TODO: add error wrapper
-        error!("{:?}", &e);
-        ErrorInternalServerError("")
-    }
-}
+type RespResult<T> = std::result::Result<T, ApiError>;
 
 const MAX_NAME_LENGTH: usize = 35;
 const MIN_NAME_LENGTH: usize = 5;
 
-fn check_user_name(name: &str) -> Result<(), String> {
+fn check_user_name(name: &str) -> RespResult<()> {
     match name.chars().count() {
         MIN_NAME_LENGTH..=MAX_NAME_LENGTH => Ok(()),
-        _ => {
-            let msg = format!(
-                "Имя слишком короткое. Оно должно быть не менее {min} символов и не более {max}.",
-                min = MIN_NAME_LENGTH,
-                max = MAX_NAME_LENGTH,
-            );
-            Err(msg)
-        }
+        _ => Err(ApiError::InvalidName {
+            min: MIN_NAME_LENGTH,
+            max: MAX_NAME_LENGTH,
+        }),
+    }
+}
+
+const MAX_USERNAME_LENGTH: usize = 32;
+const MIN_USERNAME_LENGTH: usize = 3;
+
+/// Usernames can't contain `@`, so a login is never both a username and an
+/// email.
+fn check_username(username: &str) -> RespResult<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-';
+    match username.len() {
+        MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH if username.chars().all(allowed) => Ok(()),
+        _ => Err(ApiError::InvalidUsername {
+            min: MIN_USERNAME_LENGTH,
+            max: MAX_USERNAME_LENGTH,
+        }),
     }
 }
 
 fn normalize_email(email: &str) -> RespResult<String> {
-    Ok(email.to_string().to_lowercase())
+    let email = email.trim().to_lowercase();
+    match addr::parse_email_address(&email).map(|a| a.host()) {
+        Ok(Host::Domain(domain)) if domain.has_known_suffix() => Ok(email),
+        _ => Err(ApiError::InvalidEmail),
+    }
+}
+
+/// Checks a new password against the policy and hashes it.
+fn new_password_hash(policy: &PasswordPolicy, hasher: &PasswordHasher, password: &str) -> RespResult<String> {
+    policy.check(password)?;
+    Ok(hasher.hash(password)?)
+}
+
+fn verify_password(hasher: &PasswordHasher, password: &str, pwhash: &str) -> RespResult<bool> {
+    Ok(hasher.verify(password, pwhash)? != Verified::No)
+}
+
+/// How long after signing in an account without a password can make
+/// changes that need the password of others.
+const REAUTHENTICATION_WINDOW: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Confirms a sensitive change with the current password. Accounts created
+/// by single sign-on have none until they set one, their session must have
+/// started recently instead, so they sign in with the provider again.
+fn confirm_identity(
+    auth: &Auth,
+    hasher: &PasswordHasher,
+    db: &DbService,
+    user: &UserDao,
+    password: Option<&str>,
+) -> RespResult<()> {
+    if !user.pwhash.is_empty() {
+        return match password {
+            Some(password) if verify_password(hasher, password, &user.pwhash)? => Ok(()),
+            _ => Err(ApiError::InvalidPassword),
+        };
+    }
+    let now = Utc::now().naive_utc();
+    match db.session_by_id(&auth.jti)? {
+        Some(session) if now - session.created_at <= REAUTHENTICATION_WINDOW => Ok(()),
+        _ => Err(ApiError::ReauthenticationRequired),
+    }
+}
+
+/// Addresses of the reverse proxies whose `X-Forwarded-For` is believed.
+#[derive(Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// Address of the client without the port.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let proxies = req.app_data::<Data<TrustedProxies>>().map(|p| p.get_ref());
+    forwarded_client_ip(req.peer_addr(), req.headers(), proxies)
+}
+
+/// Forwarding headers can be set by anyone, so they are only read when the
+/// peer is a trusted proxy, and then the last address not added by a
+/// trusted proxy is taken.
+pub fn forwarded_client_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    proxies: Option<&TrustedProxies>,
+) -> Option<String> {
+    let peer = peer?.ip();
+    let is_trusted = |ip: &IpAddr| proxies.is_some_and(|p| p.0.contains(ip));
+    if !is_trusted(&peer) {
+        return Some(peer.to_string());
+    }
+
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|addr| addr.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+    let client = forwarded
+        .iter()
+        .rev()
+        .take_while(|addr| addr.is_ok())
+        .flatten()
+        .find(|ip| !is_trusted(ip));
+    Some(client.unwrap_or(&peer).to_string())
+}
+
+/// End of a lifetime given in seconds by the client.
+fn expires_at(now: NaiveDateTime, expires_in: Option<u64>) -> RespResult<Option<NaiveDateTime>> {
+    expires_in
+        .map(|secs| {
+            chrono::Duration::from_std(std::time::Duration::from_secs(secs))
+                .ok()
+                .and_then(|ttl| now.checked_add_signed(ttl))
+                .ok_or_else(|| ApiError::InvalidRequest("expires_in is too large".to_string()))
+        })
+        .transpose()
+}
+
+fn access_token(user_id: Uuid, session_id: Uuid, jwt_config: &JwtConfig) -> RespResult<String> {
+    let auth = Auth::new(user_id, session_id, jwt_config.access_token_ttl);
+    crate::auth::encode_token(&auth, jwt_config).map_err(|e| {
+        error!("{:?}", e);
+        ApiError::Internal
+    })
+}
+
+/// Returns the plain token for the client and the row to store.
+fn new_refresh_token(
+    user_id: Uuid,
+    session_id: Uuid,
+    now: NaiveDateTime,
+    jwt_config: &JwtConfig,
+) -> (String, RefreshTokenDao) {
+    let token = generate_token();
+    let ttl = chrono::Duration::from_std(jwt_config.refresh_token_ttl).unwrap();
+    let dao = RefreshTokenDao {
+        id: Uuid::new_v4(),
+        user_id,
+        session_id,
+        token_hash: hash_token(&token),
+        created_at: now,
+        expires_at: now + ttl,
+        used_at: None,
+    };
+    (token, dao)
+}
+
+/// Signs the user in on a new session.
+fn start_session(
+    req: &HttpRequest,
+    jwt_config: &JwtConfig,
+    db: &DbService,
+    user_id: Uuid,
+) -> RespResult<SignInResponse> {
+    let now = Utc::now().naive_utc();
+    let session = SessionDao {
+        id: Uuid::new_v4(),
+        user_id,
+        created_at: now,
+        last_used_at: now,
+        user_agent: req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from),
+        ip: client_ip(req),
+        revoked_at: None,
+    };
+    let (refresh_token, refresh_dao) = new_refresh_token(user_id, session.id, now, jwt_config);
+    db.start_session(&session, &refresh_dao)?;
+
+    let token = access_token(user_id, session.id, jwt_config)?;
+    Ok(SignInResponse {
+        token,
+        expires_in: jwt_config.access_token_ttl.as_secs(),
+        refresh_token,
+    })
+}
+
+/// Language of mails to the user: their preference, then `Accept-Language`.
+fn mail_lang(req: &HttpRequest, user: &UserDao) -> Lang {
+    user.locale
+        .as_deref()
+        .and_then(Lang::from_tag)
+        .or_else(|| i18n::accept_language(req.headers()))
+        .unwrap_or_default()
+}
+
+fn send_verification_mail(
+    req: &HttpRequest,
+    config: &EmailLinkConfig,
+    mailer: &Data<dyn Mailer>,
+    db: &DbService,
+    user: &UserDao,
+) -> RespResult<()> {
+    let now = Utc::now().naive_utc();
+    let token = generate_token();
+    let dao = EmailVerificationTokenDao {
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        email: user.email.clone(),
+        token_hash: hash_token(&token),
+        created_at: now,
+        expires_at: now + chrono::Duration::from_std(config.email_verification_ttl).unwrap(),
+        used_at: None,
+    };
+    db.add_email_verification_token(&dao)?;
+
+    let mail = mail::email_verification(
+        mail_lang(req, user),
+        &user.email,
+        &user.name,
+        &config.email_verification_link(&token),
+        (config.email_verification_ttl.as_secs() / 3600).max(1),
+    );
+    mail::send_later(Arc::clone(mailer), mail);
+    Ok(())
+}
+
+fn queue_info(dao: QueueDao) -> QueueInfo {
+    let QueueDao {
+        id,
+        name,
+        description,
+        organizer_id,
+        created_at,
+        exists_before,
+        require_verified_email,
+        allow_guests,
+        is_public,
+        updated_at,
+        require_approval,
+    } = dao;
+    QueueInfo {
+        id,
+        name,
+        description,
+        organizer_id,
+        created_at,
+        exists_before,
+        require_verified_email,
+        allow_guests,
+        is_public,
+        updated_at,
+        require_approval,
+    }
+}
+
+fn available_queue_info(row: AvailableQueue, user_id: &Uuid) -> AvailableQueueInfo {
+    let role = if row.queue.organizer_id == *user_id {
+        QueueRole::Organizer
+    } else {
+        QueueRole::Member
+    };
+    AvailableQueueInfo {
+        queue: queue_info(row.queue),
+        role,
+        position: row.position.map(|position| position as usize),
+        member_count: row.member_count as usize,
+    }
 }
 
 // --------
@@ -55,102 +323,733 @@ fn normalize_email(email: &str) -> RespResult<String> {
 // --------
 
 pub async fn ping() -> impl Responder {
-    format!("Pong!")
+    "Pong!"
 }
 
-pub async fn sign_up(db: Data<DbService>, data: Json<SignUp>) -> RespResult<impl Responder> {
+pub async fn sign_up(
+    req: HttpRequest,
+    config: Data<EmailLinkConfig>,
+    mailer: Data<dyn Mailer>,
+    policy: Data<PasswordPolicy>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<SignUp>,
+) -> RespResult<impl Responder> {
     let SignUp {
         email,
         name,
         password,
+        username,
     } = data.0;
 
-    check_user_name(&name).map_err(|e| ErrorBadRequest(e))?;
+    check_user_name(&name)?;
     let email = normalize_email(&email)?;
+    if let Some(username) = &username {
+        check_username(username)?;
+    }
 
     // Проверяем наличие такого же пользователя
     let is_exist = db.has_user_with_email(&email)?;
 
     if is_exist {
-        return Err(ErrorBadRequest(format!(
-            "User with this email {} already registered.",
-            &email
-        )));
+        return Err(ApiError::EmailTaken);
+    }
+    if let Some(username) = &username {
+        if db.has_user_with_username(username)? {
+            return Err(ApiError::UsernameTaken);
+        }
+    }
+
+    // Создаем и добавляем нового пользователя
+    let user_uuid = Uuid::new_v4();
+    let pwhash = new_password_hash(&policy, &hasher, &password)?;
+
+    let user = UserDao {
+        id: user_uuid,
+        name,
+        email,
+        pwhash,
+        locale: None,
+        email_verified_at: None,
+        username,
+    };
+
+    db.add_user(&user)?;
+    send_verification_mail(&req, &config, &mailer, &db, &user)?;
+
+    Ok("")
+}
+
+pub async fn sign_in(
+    req: HttpRequest,
+    jwt_config: Data<JwtConfig>,
+    throttle: Data<SignInThrottle>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<SignIn>,
+) -> RespResult<Json<SignInResponse>> {
+    let SignIn { login, password } = data.0;
+    let login = login.trim();
+
+    let user = if login.contains('@') {
+        match normalize_email(login) {
+            Ok(email) => db.user_by_email(&email)?,
+            Err(_) => None,
+        }
+    } else {
+        db.user_by_username(login)?
+    };
+    // Attempts are counted per account, whichever identifier is used.
+    let account = match &user {
+        Some(user) => user.id.to_string(),
+        None => login.to_string(),
+    };
+
+    // Reserved before hashing, so blocked clients don't cost CPU time either.
+    // The attempt counts as failed unless the password is right.
+    let ip = client_ip(&req);
+    if let Err(wait) = throttle.reserve(&account, ip.as_deref(), Utc::now().naive_utc()) {
+        return Err(ApiError::TooManyAttempts {
+            retry_after: wait.as_secs_f64().ceil() as u64,
+        });
+    }
+
+    let user = match user {
+        Some(user) => user,
+        None => return Err(ApiError::InvalidCredentials),
+    };
+
+    let verified = hasher.verify(&password, &user.pwhash)?;
+
+    if verified != Verified::No {
+        throttle.succeeded(&account, ip.as_deref());
+        if verified == Verified::NeedsRehash {
+            // Upgrades old hashes while the plain password is at hand
+            match hasher.hash(&password) {
+                Ok(pwhash) => {
+                    db.set_user_pwhash(&user.id, &pwhash)?;
+                }
+                Err(e) => error!("Can not rehash password: {}", e),
+            }
+        }
+        Ok(Json(start_session(&req, &jwt_config, &db, user.id)?))
+    } else {
+        Err(ApiError::InvalidCredentials)
+    }
+}
+
+/// Redirects to the sign in page of the provider.
+pub async fn oidc_start(oidc: Data<OidcLogin>, provider: Path<String>) -> RespResult<HttpResponse> {
+    let url = oidc.start(&provider).await?;
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, url.as_str()))
+        .finish())
+}
+
+/// Completes the login with the code the provider redirected back with.
+/// Known identities sign in to their account, new ones are linked to the
+/// account with the same email or get a new one.
+pub async fn oidc_callback(
+    req: HttpRequest,
+    jwt_config: Data<JwtConfig>,
+    oidc: Data<OidcLogin>,
+    db: Data<DbService>,
+    provider: Path<String>,
+    data: Json<OidcCallback>,
+) -> RespResult<Json<SignInResponse>> {
+    let OidcCallback { code, state } = data.0;
+    let identity = oidc.finish(&provider, code, &state).await?;
+
+    let user = match db.user_by_identity(&provider, &identity.subject)? {
+        Some(user) => user,
+        None => add_oidc_identity(&db, &provider, identity)?,
+    };
+    Ok(Json(start_session(&req, &jwt_config, &db, user.id)?))
+}
+
+fn add_oidc_identity(db: &DbService, provider: &str, identity: Identity) -> RespResult<UserDao> {
+    let email = match identity.email.as_deref().map(normalize_email) {
+        Some(Ok(email)) => email,
+        _ => {
+            warn!("OIDC provider {} has no valid email for {}", provider, identity.subject);
+            return Err(ApiError::OidcLoginFailed);
+        }
+    };
+    let now = Utc::now().naive_utc();
+    let mut identity_dao = UserIdentityDao {
+        id: Uuid::new_v4(),
+        user_id: Uuid::new_v4(),
+        provider: provider.to_string(),
+        subject: identity.subject,
+        email: Some(email.clone()),
+        created_at: now,
+    };
+
+    if let Some(user) = db.user_by_email(&email)? {
+        // Both sides have to prove the address, otherwise whoever registered
+        // it first could take over the other's account.
+        if !identity.email_verified || user.email_verified_at.is_none() {
+            return Err(ApiError::EmailTaken);
+        }
+        identity_dao.user_id = user.id;
+        db.add_user_identity(&identity_dao)?;
+        return Ok(user);
+    }
+
+    let user = UserDao {
+        id: identity_dao.user_id,
+        name: oidc_user_name(identity.name.as_deref(), &email),
+        email,
+        pwhash: String::new(),
+        locale: None,
+        email_verified_at: if identity.email_verified { Some(now) } else { None },
+        username: None,
+    };
+    db.add_user_with_identity(&user, &identity_dao)?;
+    Ok(user)
+}
+
+/// Name from the provider cut to the allowed length, or the email when it
+/// is too short.
+fn oidc_user_name(name: Option<&str>, email: &str) -> String {
+    let fit = |name: &str| name.trim().chars().take(MAX_NAME_LENGTH).collect::<String>();
+    name.map(fit)
+        .filter(|name| name.chars().count() >= MIN_NAME_LENGTH)
+        .unwrap_or_else(|| fit(email))
+}
+
+pub async fn refresh(
+    jwt_config: Data<JwtConfig>,
+    sessions: Data<SessionCache>,
+    db: Data<DbService>,
+    data: Json<Refresh>,
+) -> RespResult<Json<SignInResponse>> {
+    let Refresh { refresh_token } = data.0;
+    let now = Utc::now().naive_utc();
+
+    let old = db
+        .refresh_token_by_hash(&hash_token(&refresh_token))?
+        .ok_or(ApiError::InvalidRefreshToken)?;
+
+    let session = db
+        .session_by_id(&old.session_id)?
+        .ok_or(ApiError::InvalidRefreshToken)?;
+    if session.revoked_at.is_some() || old.expires_at <= now {
+        return Err(ApiError::InvalidRefreshToken);
+    }
+
+    // A rotated token is presented again: either the client or an attacker
+    // holds a stolen copy, so the whole session is revoked.
+    let reuse_detected = |db: &DbService| -> RespResult<Json<SignInResponse>> {
+        warn!("Refresh token reuse detected for user {}", old.user_id);
+        db.revoke_session(&old.user_id, &old.session_id, now)?;
+        sessions.revoke(&old.session_id);
+        Err(ApiError::RefreshTokenReused)
+    };
+    if old.used_at.is_some() {
+        return reuse_detected(&db);
+    }
+
+    let (refresh_token, new) = new_refresh_token(old.user_id, old.session_id, now, &jwt_config);
+    if !db.rotate_refresh_token(&old, &new, now)? {
+        return reuse_detected(&db);
+    }
+
+    let token = access_token(old.user_id, old.session_id, &jwt_config)?;
+    Ok(Json(SignInResponse {
+        token,
+        expires_in: jwt_config.access_token_ttl.as_secs(),
+        refresh_token,
+    }))
+}
+
+pub async fn password_forgot(
+    req: HttpRequest,
+    config: Data<EmailLinkConfig>,
+    mailer: Data<dyn Mailer>,
+    db: Data<DbService>,
+    data: Json<ForgotPassword>,
+) -> RespResult<&'static str> {
+    let email = normalize_email(&data.0.email)?;
+
+    // Unknown emails get the same answer, so the endpoint doesn't reveal
+    // who is registered.
+    let user = match db.user_by_email(&email)? {
+        Some(user) => user,
+        None => return Ok(""),
+    };
+
+    let now = Utc::now().naive_utc();
+    let token = generate_token();
+    let dao = PasswordResetTokenDao {
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        token_hash: hash_token(&token),
+        created_at: now,
+        expires_at: now + chrono::Duration::from_std(config.password_reset_ttl).unwrap(),
+        used_at: None,
+    };
+    db.add_password_reset_token(&dao)?;
+
+    let mail = mail::password_reset(
+        mail_lang(&req, &user),
+        &user.email,
+        &user.name,
+        &config.password_reset_link(&token),
+        config.password_reset_ttl.as_secs() / 60,
+    );
+    mail::send_later(Arc::clone(&mailer), mail);
+    Ok("")
+}
+
+/// Sets a new password and signs the user out everywhere.
+pub async fn password_reset(
+    sessions: Data<SessionCache>,
+    policy: Data<PasswordPolicy>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<ResetPassword>,
+) -> RespResult<&'static str> {
+    let ResetPassword { token, password } = data.0;
+    let pwhash = new_password_hash(&policy, &hasher, &password)?;
+    let now = Utc::now().naive_utc();
+
+    let revoked = db
+        .reset_password(&hash_token(&token), &pwhash, now)?
+        .ok_or(ApiError::InvalidResetToken)?;
+    for session_id in revoked {
+        sessions.revoke(&session_id);
+    }
+    Ok("")
+}
+
+pub async fn email_verify(db: Data<DbService>, data: Json<VerifyEmail>) -> RespResult<&'static str> {
+    let now = Utc::now().naive_utc();
+    if !db.verify_email(&hash_token(&data.0.token), now)? {
+        return Err(ApiError::InvalidVerificationToken);
+    }
+    Ok("")
+}
+
+pub async fn me(auth: Auth, db: Data<DbService>) -> RespResult<Json<ProfileInfo>> {
+    db.user_by_id(&auth.id)?
+        .ok_or(ApiError::UserNotFound)
+        .map(|dao| {
+            let UserDao {
+                id,
+                name,
+                email,
+                locale,
+                email_verified_at,
+                username,
+                ..
+            } = dao;
+            ProfileInfo {
+                id,
+                name,
+                username,
+                email,
+                email_verified: email_verified_at.is_some(),
+                locale,
+            }
+        })
+        .map(Json)
+}
+
+/// Deletes the account after confirming the identity.
+pub async fn me_delete(
+    auth: Auth,
+    sessions: Data<SessionCache>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<DeleteAccount>,
+) -> RespResult<&'static str> {
+    let DeleteAccount {
+        password,
+        organized_queues,
+    } = data.0;
+
+    let user = db.user_by_id(&auth.id)?.ok_or(ApiError::UserNotFound)?;
+    confirm_identity(&auth, &hasher, &db, &user, password.as_deref())?;
+
+    let transfer_queues = organized_queues == Some(OrganizedQueues::Transfer);
+    let now = Utc::now().naive_utc();
+    for session_id in db.delete_user(&auth.id, transfer_queues, now)? {
+        sessions.revoke(&session_id);
+    }
+    Ok("")
+}
+
+pub async fn me_export(auth: Auth, db: Data<DbService>) -> RespResult<HttpResponse> {
+    let user = db.user_by_id(&auth.id)?.ok_or(ApiError::UserNotFound)?;
+
+    let memberships = db
+        .memberships(&auth.id)?
+        .into_iter()
+        .map(|(entry, queue)| ExportMembership {
+            queue: queue_info(queue),
+            order: entry.order,
+            has_priority: entry.has_priority,
+            is_held: entry.is_held,
+            joined_at: entry.joined_at,
+        })
+        .collect();
+    let join_requests = db
+        .join_requests_of_user(&auth.id)?
+        .into_iter()
+        .map(|(request, queue)| ExportJoinRequest {
+            id: request.id,
+            queue: queue_info(queue),
+            requested_at: request.requested_at,
+        })
+        .collect();
+    let organized_queues = db
+        .organized_queues(&auth.id)?
+        .into_iter()
+        .map(queue_info)
+        .collect();
+    let sessions = db
+        .all_sessions(&auth.id)?
+        .into_iter()
+        .map(|dao| {
+            let SessionDao {
+                id,
+                created_at,
+                last_used_at,
+                user_agent,
+                ip,
+                revoked_at,
+                ..
+            } = dao;
+            ExportSession {
+                id,
+                created_at,
+                last_used_at,
+                user_agent,
+                ip,
+                revoked_at,
+            }
+        })
+        .collect();
+
+    let UserDao {
+        id,
+        name,
+        email,
+        locale,
+        email_verified_at,
+        username,
+        ..
+    } = user;
+    let export = DataExport {
+        exported_at: Utc::now().naive_utc(),
+        profile: ExportProfile {
+            id,
+            name,
+            username,
+            email,
+            email_verified_at,
+            locale,
+        },
+        memberships,
+        join_requests,
+        organized_queues,
+        sessions,
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"oqueue-export.json\"",
+        ))
+        .json(export))
+}
+
+pub async fn me_set_name(
+    auth: Auth,
+    db: Data<DbService>,
+    data: Json<SetName>,
+) -> RespResult<&'static str> {
+    let SetName { name } = data.0;
+    check_user_name(&name)?;
+
+    if !db.set_user_name(&auth.id, &name)? {
+        return Err(ApiError::UserNotFound);
+    }
+    Ok("")
+}
+
+pub async fn me_set_username(
+    auth: Auth,
+    db: Data<DbService>,
+    data: Json<SetUsername>,
+) -> RespResult<&'static str> {
+    let SetUsername { username } = data.0;
+    if let Some(username) = &username {
+        check_username(username)?;
+    }
+
+    if !db.set_user_username(&auth.id, username.as_deref())? {
+        return Err(ApiError::UserNotFound);
+    }
+    Ok("")
+}
+
+/// Changes the password and signs out all other sessions.
+pub async fn me_set_password(
+    auth: Auth,
+    sessions: Data<SessionCache>,
+    policy: Data<PasswordPolicy>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<SetPassword>,
+) -> RespResult<&'static str> {
+    let SetPassword {
+        current_password,
+        new_password,
+    } = data.0;
+
+    let user = db.user_by_id(&auth.id)?.ok_or(ApiError::UserNotFound)?;
+    confirm_identity(&auth, &hasher, &db, &user, current_password.as_deref())?;
+
+    let pwhash = new_password_hash(&policy, &hasher, &new_password)?;
+    let now = Utc::now().naive_utc();
+    for session_id in db.change_password(&auth.id, &pwhash, &auth.jti, now)? {
+        sessions.revoke(&session_id);
+    }
+    Ok("")
+}
+
+/// Changes the email and sends a verification link to the new address.
+pub async fn me_set_email(
+    req: HttpRequest,
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    mailer: Data<dyn Mailer>,
+    hasher: Data<PasswordHasher>,
+    db: Data<DbService>,
+    data: Json<SetEmail>,
+) -> RespResult<&'static str> {
+    let SetEmail { email, password } = data.0;
+    let email = normalize_email(&email)?;
+
+    let user = db.user_by_id(&auth.id)?.ok_or(ApiError::UserNotFound)?;
+    confirm_identity(&auth, &hasher, &db, &user, password.as_deref())?;
+    if user.email == email {
+        return Ok("");
+    }
+
+    let now = Utc::now().naive_utc();
+    if !db.change_email(&auth.id, &email, now)? {
+        return Err(ApiError::UserNotFound);
+    }
+    let user = UserDao {
+        email,
+        email_verified_at: None,
+        ..user
+    };
+    send_verification_mail(&req, &config, &mailer, &db, &user)?;
+    Ok("")
+}
+
+pub async fn me_email_verification(
+    req: HttpRequest,
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    mailer: Data<dyn Mailer>,
+    db: Data<DbService>,
+) -> RespResult<&'static str> {
+    let user = db.user_by_id(&auth.id)?.ok_or(ApiError::UserNotFound)?;
+    if user.email_verified_at.is_some() {
+        return Err(ApiError::EmailAlreadyVerified);
+    }
+    send_verification_mail(&req, &config, &mailer, &db, &user)?;
+    Ok("")
+}
+
+pub async fn me_set_locale(
+    auth: Auth,
+    db: Data<DbService>,
+    data: Json<SetLocale>,
+) -> RespResult<&'static str> {
+    let locale = match data.0.locale {
+        Some(tag) => Some(Lang::from_tag(&tag).ok_or(ApiError::UnsupportedLocale)?),
+        None => None,
+    };
+
+    let is_updated = db.set_user_locale(&auth.id, locale.map(|l| l.tag()))?;
+    if !is_updated {
+        return Err(ApiError::UserNotFound);
+    }
+    Ok("")
+}
+
+pub async fn sessions(auth: Auth, db: Data<DbService>) -> RespResult<Json<Vec<SessionInfo>>> {
+    let sessions = db
+        .active_sessions(&auth.id)?
+        .into_iter()
+        .map(|dao| {
+            let SessionDao {
+                id,
+                created_at,
+                last_used_at,
+                user_agent,
+                ip,
+                ..
+            } = dao;
+            SessionInfo {
+                id,
+                created_at,
+                last_used_at,
+                user_agent,
+                ip,
+                is_current: id == auth.jti,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(sessions))
+}
+
+fn session_revoke_inner(
+    auth: &Auth,
+    db: &DbService,
+    sessions: &SessionCache,
+    session_id: Uuid,
+) -> RespResult<&'static str> {
+    let now = Utc::now().naive_utc();
+    if !db.revoke_session(&auth.id, &session_id, now)? {
+        return Err(ApiError::SessionNotFound);
+    }
+    sessions.revoke(&session_id);
+    Ok("")
+}
+
+pub async fn session_revoke(
+    auth: Auth,
+    db: Data<DbService>,
+    sessions: Data<SessionCache>,
+    session_id: Path<Uuid>,
+) -> RespResult<&'static str> {
+    session_revoke_inner(&auth, &db, &sessions, session_id.into_inner())
+}
+
+pub async fn session_revoke_current(
+    auth: Auth,
+    db: Data<DbService>,
+    sessions: Data<SessionCache>,
+) -> RespResult<&'static str> {
+    session_revoke_inner(&auth, &db, &sessions, auth.jti)
+}
+
+pub async fn sessions_revoke_all(
+    auth: Auth,
+    db: Data<DbService>,
+    sessions: Data<SessionCache>,
+) -> RespResult<&'static str> {
+    let now = Utc::now().naive_utc();
+    for session_id in db.revoke_all_sessions(&auth.id, now)? {
+        sessions.revoke(&session_id);
+    }
+    Ok("")
+}
+
+const MAX_API_TOKEN_NAME_LENGTH: usize = 64;
+
+pub async fn api_tokens(auth: Auth, db: Data<DbService>) -> RespResult<Json<Vec<ApiTokenInfo>>> {
+    let tokens = db
+        .active_api_tokens(&auth.id, Utc::now().naive_utc())?
+        .into_iter()
+        .map(|dao| {
+            let ApiTokenDao {
+                id,
+                name,
+                created_at,
+                last_used_at,
+                expires_at,
+                scopes,
+                ..
+            } = dao;
+            ApiTokenInfo {
+                id,
+                name,
+                scopes: Scope::parse_list(&scopes),
+                created_at,
+                last_used_at,
+                expires_at,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(tokens))
+}
+
+pub async fn api_token_create(
+    auth: Auth,
+    db: Data<DbService>,
+    data: Json<CreateApiToken>,
+) -> RespResult<Json<CreateApiTokenResponse>> {
+    let CreateApiToken {
+        name,
+        scopes,
+        expires_in,
+    } = data.0;
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LENGTH {
+        return Err(ApiError::InvalidName {
+            min: 1,
+            max: MAX_API_TOKEN_NAME_LENGTH,
+        });
+    }
+    if scopes.is_empty() {
+        return Err(ApiError::InvalidRequest("scopes must not be empty".to_string()));
+    }
+    if scopes.contains(&Scope::Account) {
+        return Err(ApiError::InvalidRequest(
+            "account scope can't be granted to API tokens".to_string(),
+        ));
     }
 
-    // Создаем и добавляем нового пользователя
-    let user_uuid = Uuid::new_v4();
-    let pwhash =
-        bcrypt::hash(&password, bcrypt::DEFAULT_COST).map_err(|_| ErrorInternalServerError(""))?;
-
-    let user = UserDao {
-        id: user_uuid,
+    let now = Utc::now().naive_utc();
+    let expires_at = expires_at(now, expires_in)?;
+    let (token, token_hash) = api_token::generate();
+    let dao = ApiTokenDao {
+        id: Uuid::new_v4(),
+        user_id: auth.id,
         name,
-        email,
-        pwhash,
+        token_hash,
+        created_at: now,
+        last_used_at: None,
+        expires_at,
+        revoked_at: None,
+        scopes: Scope::join_list(&scopes),
     };
+    db.add_api_token(&dao)?;
 
-    db.add_user(&user)?;
-
-    Ok("")
+    Ok(Json(CreateApiTokenResponse { id: dao.id, token }))
 }
 
-pub async fn sign_in(
-    req: HttpRequest,
+pub async fn api_token_revoke(
+    auth: Auth,
     db: Data<DbService>,
-    data: Json<SignIn>,
-) -> RespResult<Json<SignInResponse>> {
-    let SignIn { login, password } = data.0;
-
-    const ILLEGAL_LOGIN_INFO_MSG: &str = "Illegal login or password.";
-    let user = db
-        .user_by_email(&login)?
-        .ok_or_else(|| ErrorBadRequest(ILLEGAL_LOGIN_INFO_MSG))?;
-
-    let pass_is_correct = bcrypt::verify(password, &user.pwhash).map_err(|e| {
-        error!("{:?}", e);
-        ErrorInternalServerError("")
-    })?;
-
-    if pass_is_correct {
-        let exp = std::time::SystemTime::now()
-            .add(Duration::from_secs(60 * 60 * 24))
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let auth = Auth {
-            id: user.id.clone(),
-            exp,
-        };
-        let jwt_config = req.app_data::<Data<JwtConfig>>().unwrap().get_ref();
-        let token = crate::auth::encode_token(&auth, jwt_config)
-            .map_err(|_e| ErrorInternalServerError(""))?;
-        Ok(Json(SignInResponse { token }))
-    } else {
-        Err(ErrorBadRequest(ILLEGAL_LOGIN_INFO_MSG))
+    token_id: Path<Uuid>,
+) -> RespResult<&'static str> {
+    let now = Utc::now().naive_utc();
+    if !db.revoke_api_token(&auth.id, &token_id, now)? {
+        return Err(ApiError::ApiTokenNotFound);
     }
+    Ok("")
 }
 
-pub async fn me(auth: Auth, db: Data<DbService>) -> impl Responder {
-    db.user_by_id(&auth.id)?
-        .ok_or(ErrorBadRequest("User with this id is not found"))
-        .map(|dao| {
-            let UserDao { id, name, .. } = dao;
-            UserInfo { id, name }
-        })
-        .map(|x| Json(x))
-}
-
-pub async fn user(user_id: Path<Uuid>, db: Data<DbService>) -> impl Responder {
+pub async fn user(user_id: Path<Uuid>, db: Data<DbService>) -> RespResult<Json<UserInfo>> {
     let user_id = user_id.as_ref();
     db.user_by_id(user_id)?
-        .ok_or(ErrorBadRequest("User with this id is not found"))
+        .ok_or(ApiError::UserNotFound)
         .map(|dao| {
-            let UserDao { id, name, .. } = dao;
-            UserInfo { id, name }
+            let UserDao {
+                id, name, username, ..
+            } = dao;
+            UserInfo { id, name, username }
         })
-        .map(|x| Json(x))
+        .map(Json)
 }
 
 pub async fn queue_create(
@@ -171,9 +1070,14 @@ pub async fn queue_create(
         id: queue_id,
         name,
         description,
-        organizer_id: auth.id.clone(),
+        organizer_id: auth.id,
         created_at: now,
         exists_before: Utc::now().add(chrono::Duration::days(365 * 2)).naive_utc(),
+        require_verified_email: false,
+        allow_guests: false,
+        is_public: false,
+        updated_at: now,
+        require_approval: false,
     };
 
     db.add_queue(&queue)?;
@@ -185,16 +1089,16 @@ pub async fn queue_delete(
     auth: Auth,
     queue_id: Path<Uuid>,
     db: Data<DbService>,
-) -> impl Responder {
+) -> RespResult<&'static str> {
     let queue_id = queue_id.into_inner();
 
     let queue = match db.queue_by_id(&queue_id)? {
         Some(q) => q,
-        None => return Err(ErrorBadRequest("Queue is not exist")),
+        None => return Err(ApiError::QueueNotFound),
     };
 
     if queue.organizer_id != auth.id {
-        return Err(ErrorBadRequest("You is not queue organiser."));
+        return Err(ApiError::NotOrganizer);
     }
 
     db.delete_queue(&queue_id)?;
@@ -210,120 +1114,293 @@ pub async fn queue_get_info(
 
     let queue = db
         .queue_by_id(&queue_id)?
-        .ok_or(ErrorBadRequest("Queue is not exist"))?;
+        .ok_or(ApiError::QueueNotFound)?;
 
-    let QueueDao {
-        id,
-        name,
-        description,
-        organizer_id,
-        created_at,
-        exists_before,
-    } = queue;
+    Ok(Json(queue_info(queue)))
+}
 
-    Ok(Json(QueueInfo {
-        id,
-        name,
-        description,
-        organizer_id,
-        created_at,
-        exists_before,
-    }))
+pub async fn queue_update_settings(
+    auth: Auth,
+    queue_id: Path<Uuid>,
+    db: Data<DbService>,
+    data: Json<QueueSettings>,
+) -> RespResult<Json<QueueInfo>> {
+    let queue_id = queue_id.into_inner();
+    let QueueSettings {
+        require_verified_email,
+        allow_guests,
+        is_public,
+        require_approval,
+    } = data.0;
+
+    let queue = db
+        .queue_by_id(&queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    if queue.organizer_id != auth.id {
+        return Err(ApiError::NotOrganizer);
+    }
+
+    let changes = QueueSettingsChangeset {
+        require_verified_email,
+        allow_guests,
+        is_public,
+        require_approval,
+    };
+    db.update_queue_settings(&queue_id, &changes)?;
+
+    let queue = db
+        .queue_by_id(&queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    Ok(Json(queue_info(queue)))
 }
 
-pub async fn queues(auth: Auth, db: Data<DbService>) -> RespResult<Json<Vec<QueueInfo>>> {
-    let queue_infos = db
-        .available_queues(&auth.id)?
-        .into_iter()
-        .map(|dao| {
-            let QueueDao {
-                id,
-                name,
-                description,
-                organizer_id,
-                created_at,
-                exists_before,
-            } = dao;
-            QueueInfo {
-                id,
-                name,
-                description,
-                organizer_id,
-                created_at,
-                exists_before,
+pub async fn queues(
+    req: HttpRequest,
+    auth: Auth,
+    db: Data<DbService>,
+    query: Query<ListQueues>,
+) -> RespResult<HttpResponse> {
+    let ListQueues {
+        limit,
+        after,
+        sort,
+        role,
+        state,
+        created_after,
+        created_before,
+    } = query.into_inner();
+    let limit = page::limit(limit)?;
+    let after = match after {
+        Some(after) => {
+            let cursor = page::decode_cursor::<page::QueueCursor>(&after)?;
+            if cursor.sort != sort {
+                return Err(ApiError::InvalidRequest("cursor is of another sort".to_string()));
             }
-        })
-        .collect::<Vec<_>>();
-    Ok(Json(queue_infos))
+            Some(QueueAfter {
+                created_at: cursor.created_at,
+                name: cursor.name,
+                id: cursor.id,
+            })
+        }
+        None => None,
+    };
+    let (order, descending) = match sort {
+        QueueSort::CreatedAt => (QueueOrder::CreatedAt, false),
+        QueueSort::CreatedAtDesc => (QueueOrder::CreatedAt, true),
+        QueueSort::Name => (QueueOrder::Name, false),
+        QueueSort::NameDesc => (QueueOrder::Name, true),
+    };
+
+    let listing = QueueListing {
+        user_id: auth.id,
+        organized: role.map(|role| role == QueueRole::Organizer),
+        open: state.map(|state| state == QueueState::Open),
+        now: Utc::now().naive_utc(),
+        created_after,
+        created_before,
+        order,
+        descending,
+        after,
+        limit: limit as i64 + 1,
+    };
+    let rows = db.available_queues(&listing)?;
+    let page = page::page(
+        rows,
+        limit,
+        |row| page::QueueCursor {
+            sort,
+            created_at: row.queue.created_at,
+            name: row.queue.name.clone(),
+            id: row.queue.id,
+        },
+        |row| available_queue_info(row, &auth.id),
+    );
+    Ok(page::respond(&req, page))
+}
+
+pub async fn queues_search(
+    req: HttpRequest,
+    _auth: Auth,
+    db: Data<DbService>,
+    query: Query<SearchQueues>,
+) -> RespResult<HttpResponse> {
+    let SearchQueues {
+        q,
+        organizer_id,
+        state,
+        limit,
+        after,
+    } = query.into_inner();
+    let limit = page::limit(limit)?;
+    let after = match after {
+        Some(after) => {
+            let cursor = page::decode_cursor::<page::SearchCursor>(&after)?;
+            Some(SearchAfter {
+                rank: cursor.rank,
+                created_at: cursor.created_at,
+                id: cursor.id,
+            })
+        }
+        None => None,
+    };
+
+    let search = QueueSearch {
+        text: q.as_deref().map(str::trim).filter(|q| !q.is_empty()),
+        organizer_id,
+        open: state.map(|state| state == QueueState::Open),
+        now: Utc::now().naive_utc(),
+        after,
+        limit: limit as i64 + 1,
+    };
+    let rows = db.search_public_queues(&search)?;
+    let page = page::page(
+        rows,
+        limit,
+        |(queue, rank)| page::SearchCursor {
+            rank: *rank,
+            created_at: queue.created_at,
+            id: queue.id,
+        },
+        |(queue, _)| queue_info(queue),
+    );
+    Ok(page::respond(&req, page))
 }
 
 pub async fn queue_members(
+    req: HttpRequest,
     _auth: Auth,
     queue_id: Path<Uuid>,
     db: Data<DbService>,
-) -> RespResult<Json<Vec<MemberInfo>>> {
+    query: Query<ListMembers>,
+) -> RespResult<HttpResponse> {
     let queue_id = queue_id.into_inner();
+    let ListMembers { limit, after } = query.into_inner();
+    let limit = page::limit(limit)?;
+    let after = match after {
+        Some(after) => {
+            let cursor = page::decode_cursor::<page::MemberCursor>(&after)?;
+            Some(EntryAfter {
+                is_held: cursor.is_held,
+                order: cursor.order,
+                id: cursor.id,
+            })
+        }
+        None => None,
+    };
 
-    let entries = db.entries_ordered(&queue_id)?;
-
-    let entries = entries
-        .into_iter()
-        .map(|entry| {
+    let rows = db.entries_page(&queue_id, after.as_ref(), limit as i64 + 1)?;
+    let page = page::page(
+        rows,
+        limit,
+        |(entry, _)| page::MemberCursor {
+            is_held: entry.is_held,
+            order: entry.order,
+            id: entry.id,
+        },
+        |(entry, guest)| {
             let QueueEntryDao {
                 user_id,
                 order,
                 has_priority,
                 is_held,
                 joined_at,
+                guest_id,
                 ..
             } = entry;
 
             MemberInfo {
-                id: user_id,
+                // One of them is always set
+                id: user_id.or(guest_id).unwrap_or_default(),
+                guest_name: guest.map(|g| g.name),
                 order,
                 has_priority,
                 is_held,
                 joined_at,
             }
-        })
-        .collect::<Vec<_>>();
-
-    Ok(Json(entries))
+        },
+    );
+    Ok(page::respond(&req, page))
 }
 
-async fn queue_join_inner(db: Data<DbService>, queue_id: Uuid, user_id: Uuid) -> RespResult<&'static str> {
+/// Adds the user to the queue on behalf of `by`, or asks to when the queue
+/// requires approval and `by` is not the organizer. Returns the request in
+/// the latter case.
+async fn queue_join_inner(
+    db: Data<DbService>,
+    queue_id: Uuid,
+    user_id: Uuid,
+    by: Uuid,
+) -> RespResult<Option<JoinRequestInfo>> {
+    let queue = db
+        .queue_by_id(&queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    if queue.require_verified_email {
+        let user = db.user_by_id(&user_id)?.ok_or(ApiError::UserNotFound)?;
+        if user.email_verified_at.is_none() {
+            return Err(ApiError::EmailNotVerified);
+        }
+    }
+
+    let now = Utc::now().naive_utc();
+    if queue.require_approval && queue.organizer_id != by {
+        let request = JoinRequestDao {
+            id: Uuid::new_v4(),
+            queue_id,
+            user_id,
+            requested_at: now,
+        };
+        if !db.add_join_request(&request)? {
+            return Err(ApiError::AlreadyMember);
+        }
+        return Ok(Some(join_request_info(request, None)));
+    }
+
     let entry = QueueEntryToAdd {
         queue_id,
         user_id,
         has_priority: false,
-        joined_at: Utc::now().naive_utc(),
+        joined_at: now,
     };
 
     db.add_entry(&entry)?;
-    Ok("")
+    // A request made before is answered by the organizer adding the user
+    db.delete_join_request_of(&queue_id, &user_id)?;
+    Ok(None)
 }
 
 pub async fn queue_add_member(
-    _me: Auth,
+    me: Auth,
     db: Data<DbService>,
     in_path: Path<(Uuid, Uuid)>,
-) -> RespResult<&'static str> {
+) -> RespResult<HttpResponse> {
     let (queue_id, user_id) = in_path.into_inner();
-    queue_join_inner(db, queue_id, user_id).await
+    let queue = organized_queue(&db, &queue_id, &me)?;
+    join_response(queue_join_inner(db, queue.id, user_id, me.id).await?)
 }
 
 pub async fn queue_add_member_me(
     me: Auth,
     db: Data<DbService>,
     in_path: Path<Uuid>,
-) -> RespResult<&'static str> {
+) -> RespResult<HttpResponse> {
     let queue_id = in_path.into_inner();
-    queue_join_inner(db, queue_id, me.id).await
+    join_response(queue_join_inner(db, queue_id, me.id, me.id).await?)
 }
 
+/// `202` with the request when the join waits for approval.
+fn join_response(request: Option<JoinRequestInfo>) -> RespResult<HttpResponse> {
+    let response = match request {
+        Some(request) => HttpResponse::Accepted().json(request),
+        None => HttpResponse::Ok().finish(),
+    };
+    Ok(response)
+}
 
 async fn queue_remove_member_inner(db: Data<DbService>, queue_id: Uuid, user_id: Uuid) -> RespResult<&'static str> {
-    db.delete_entry(&queue_id, &user_id)?;
+    let is_deleted = db.delete_entry(&queue_id, &user_id)?;
+    if !is_deleted {
+        return Err(ApiError::MemberNotFound);
+    }
     Ok("")
 }
 
@@ -333,16 +1410,382 @@ pub async fn queue_remove_member_me(
     queue_id: Path<Uuid>,
 ) -> RespResult<&'static str> {
     let queue_id = queue_id.into_inner();
+    // Leaving before the approval withdraws the request
+    if db.delete_join_request_of(&queue_id, &me.id)? {
+        return Ok("");
+    }
     queue_remove_member_inner(db, queue_id, me.id).await
 }
 
 pub async fn queue_remove_member(
-    _me: Auth,
+    me: Auth,
     db: Data<DbService>,
     in_path: Path<(Uuid, Uuid)>,
 ) -> RespResult<&'static str> {
     let (queue_id, user_id) = in_path.into_inner();
-    queue_remove_member_inner(db, queue_id, user_id).await
+    let queue = organized_queue(&db, &queue_id, &me)?;
+    queue_remove_member_inner(db, queue.id, user_id).await
+}
+
+// ----------
+// Join codes
+// ----------
+
+/// Attempts to find a code that is not taken before giving up.
+const JOIN_CODE_ATTEMPTS: usize = 5;
+
+fn join_code_info(dao: JoinCodeDao, config: &EmailLinkConfig) -> JoinCodeInfo {
+    let code = join_code::display(&dao.code);
+    JoinCodeInfo {
+        url: config.join_link(&code),
+        code,
+        created_at: dao.created_at,
+        expires_at: dao.expires_at,
+        max_uses: dao.max_uses,
+        uses: dao.uses,
+    }
+}
+
+fn organized_queue(db: &DbService, queue_id: &Uuid, auth: &Auth) -> RespResult<QueueDao> {
+    let queue = db
+        .queue_by_id(queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    if queue.organizer_id != auth.id {
+        return Err(ApiError::NotOrganizer);
+    }
+    Ok(queue)
+}
+
+pub async fn queue_join_code(
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+) -> RespResult<Json<JoinCodeInfo>> {
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+    let dao = db
+        .active_join_code(&queue.id, Utc::now().naive_utc())?
+        .ok_or(ApiError::JoinCodeNotFound)?;
+    Ok(Json(join_code_info(dao, &config)))
+}
+
+/// Creates a new code for the queue, previous codes stop working.
+pub async fn queue_join_code_create(
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+    data: Json<CreateJoinCode>,
+) -> RespResult<Json<JoinCodeInfo>> {
+    let CreateJoinCode {
+        expires_in,
+        max_uses,
+    } = data.0;
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+    let max_uses = match max_uses {
+        Some(0) => return Err(ApiError::InvalidRequest("max_uses must be positive".to_string())),
+        Some(max_uses) => Some(max_uses.min(i32::MAX as u32) as i32),
+        None => None,
+    };
+
+    let now = Utc::now().naive_utc();
+    let mut code = None;
+    for _ in 0..JOIN_CODE_ATTEMPTS {
+        let candidate = join_code::generate();
+        if !db.has_join_code(&candidate)? {
+            code = Some(candidate);
+            break;
+        }
+    }
+    let code = code.ok_or_else(|| {
+        error!("No free join code after {} attempts", JOIN_CODE_ATTEMPTS);
+        ApiError::Internal
+    })?;
+
+    let dao = JoinCodeDao {
+        id: Uuid::new_v4(),
+        queue_id: queue.id,
+        code,
+        created_at: now,
+        expires_at: expires_at(now, expires_in)?,
+        max_uses,
+        uses: 0,
+        revoked_at: None,
+    };
+    db.replace_join_code(&dao)?;
+    Ok(Json(join_code_info(dao, &config)))
+}
+
+pub async fn queue_join_code_delete(
+    auth: Auth,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+) -> RespResult<&'static str> {
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+    if !db.revoke_join_codes(&queue.id, Utc::now().naive_utc())? {
+        return Err(ApiError::JoinCodeNotFound);
+    }
+    Ok("")
+}
+
+/// Join link of the active code, the QR code is gone when the code is.
+fn qr_link(
+    db: &DbService,
+    config: &EmailLinkConfig,
+    queue_id: &Uuid,
+    auth: &Auth,
+    options: &QrOptions,
+) -> RespResult<(String, u32)> {
+    let size = options.size.unwrap_or(qr::DEFAULT_SIZE);
+    if !(qr::MIN_SIZE..=qr::MAX_SIZE).contains(&size) {
+        return Err(ApiError::InvalidRequest(format!(
+            "size must be from {} to {}",
+            qr::MIN_SIZE,
+            qr::MAX_SIZE
+        )));
+    }
+    let queue = organized_queue(db, queue_id, auth)?;
+    let dao = db
+        .active_join_code(&queue.id, Utc::now().naive_utc())?
+        .ok_or(ApiError::JoinCodeNotFound)?;
+    Ok((config.join_link(&join_code::display(&dao.code)), size))
+}
+
+fn qr_error(e: qr::RenderError) -> ApiError {
+    error!("Can not render QR code: {}", e);
+    ApiError::Internal
+}
+
+pub async fn queue_qr_png(
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+    options: Query<QrOptions>,
+) -> RespResult<HttpResponse> {
+    let (link, size) = qr_link(&db, &config, &queue_id, &auth, &options)?;
+    let png = qr::png(&link, size, options.ec).map_err(qr_error)?;
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+pub async fn queue_qr_svg(
+    auth: Auth,
+    config: Data<EmailLinkConfig>,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+    options: Query<QrOptions>,
+) -> RespResult<HttpResponse> {
+    let (link, size) = qr_link(&db, &config, &queue_id, &auth, &options)?;
+    let svg = qr::svg(&link, size, options.ec).map_err(qr_error)?;
+    Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+}
+
+/// Joins the queue of the code. A failed join doesn't count as a use.
+/// Responds `202` with the request like `POST /queues/{queue_id}/members/me`
+/// when the join waits for approval. The request uses up the code even if
+/// it is rejected later.
+pub async fn join_by_code(
+    me: Auth,
+    db: Data<DbService>,
+    code: Path<String>,
+) -> RespResult<HttpResponse> {
+    let code = join_code::normalize(&code).ok_or(ApiError::JoinCodeNotFound)?;
+    let queue_id = db
+        .use_join_code(&code, Utc::now().naive_utc())?
+        .ok_or(ApiError::JoinCodeNotFound)?;
+
+    let request = match queue_join_inner(db.clone(), queue_id, me.id, me.id).await {
+        Ok(request) => request,
+        Err(e) => {
+            db.release_join_code(&code)?;
+            return Err(e);
+        }
+    };
+    if let Some(request) = request {
+        return Ok(HttpResponse::Accepted().json(request));
+    }
+    let queue = db
+        .queue_by_id(&queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    Ok(HttpResponse::Ok().json(queue_info(queue)))
+}
+
+// -------------
+// Join requests
+// -------------
+
+fn join_request_info(dao: JoinRequestDao, user_name: Option<String>) -> JoinRequestInfo {
+    JoinRequestInfo {
+        id: dao.id,
+        queue_id: dao.queue_id,
+        user_id: dao.user_id,
+        user_name,
+        requested_at: dao.requested_at,
+    }
+}
+
+pub async fn join_requests(
+    req: HttpRequest,
+    auth: Auth,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+    query: Query<ListJoinRequests>,
+) -> RespResult<HttpResponse> {
+    let ListJoinRequests { limit, after } = query.into_inner();
+    let limit = page::limit(limit)?;
+    let after = match after {
+        Some(after) => {
+            let cursor = page::decode_cursor::<page::JoinRequestCursor>(&after)?;
+            Some((cursor.requested_at, cursor.id))
+        }
+        None => None,
+    };
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+
+    let rows = db.join_requests_page(&queue.id, after, limit as i64 + 1)?;
+    let page = page::page(
+        rows,
+        limit,
+        |(request, _)| page::JoinRequestCursor {
+            requested_at: request.requested_at,
+            id: request.id,
+        },
+        |(request, name)| join_request_info(request, Some(name)),
+    );
+    Ok(page::respond(&req, page))
+}
+
+/// Adds the user to the queue in the place of the time of the request.
+pub async fn join_request_approve(
+    auth: Auth,
+    db: Data<DbService>,
+    in_path: Path<(Uuid, Uuid)>,
+) -> RespResult<&'static str> {
+    let (queue_id, request_id) = in_path.into_inner();
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+    db.approve_join_request(&queue.id, &request_id)?
+        .ok_or(ApiError::JoinRequestNotFound)?;
+    Ok("")
+}
+
+pub async fn join_request_reject(
+    auth: Auth,
+    db: Data<DbService>,
+    in_path: Path<(Uuid, Uuid)>,
+) -> RespResult<&'static str> {
+    let (queue_id, request_id) = in_path.into_inner();
+    let queue = organized_queue(&db, &queue_id, &auth)?;
+    db.reject_join_request(&queue.id, &request_id)?
+        .ok_or(ApiError::JoinRequestNotFound)?;
+    Ok("")
+}
+
+// ------
+// Guests
+// ------
+
+/// Lets a guest join a queue that allows guests. The returned ticket
+/// identifies them in this queue only.
+pub async fn guest_join(
+    jwt_config: Data<JwtConfig>,
+    db: Data<DbService>,
+    queue_id: Path<Uuid>,
+    data: Json<GuestJoin>,
+) -> RespResult<Json<GuestJoinResponse>> {
+    let queue_id = queue_id.into_inner();
+    let name = data.0.name.trim().to_string();
+    if !(1..=MAX_NAME_LENGTH).contains(&name.chars().count()) {
+        return Err(ApiError::InvalidName {
+            min: 1,
+            max: MAX_NAME_LENGTH,
+        });
+    }
+
+    let queue = db
+        .queue_by_id(&queue_id)?
+        .ok_or(ApiError::QueueNotFound)?;
+    // Guests can't be told apart for approval
+    if !queue.allow_guests || queue.require_approval {
+        return Err(ApiError::GuestsNotAllowed);
+    }
+
+    let guest = GuestDao {
+        id: Uuid::new_v4(),
+        queue_id,
+        name,
+        created_at: Utc::now().naive_utc(),
+    };
+    db.add_guest_entry(&guest)?;
+
+    let ticket = GuestTicket::new(guest.id, queue_id, jwt_config.guest_ticket_ttl);
+    let ticket = crate::auth::encode_token(&ticket, &jwt_config).map_err(|e| {
+        error!("{:?}", e);
+        ApiError::Internal
+    })?;
+    Ok(Json(GuestJoinResponse {
+        id: guest.id,
+        ticket,
+        expires_in: jwt_config.guest_ticket_ttl.as_secs(),
+    }))
+}
+
+pub async fn guest_me(ticket: GuestTicket, db: Data<DbService>) -> RespResult<Json<GuestInfo>> {
+    let (position, (entry, guest)) = db
+        .entries_ordered(&ticket.queue)?
+        .into_iter()
+        .enumerate()
+        .find(|(_, (entry, _))| entry.guest_id == Some(ticket.guest))
+        .ok_or(ApiError::MemberNotFound)?;
+    let guest = guest.ok_or(ApiError::MemberNotFound)?;
+
+    Ok(Json(GuestInfo {
+        id: guest.id,
+        queue_id: guest.queue_id,
+        name: guest.name,
+        position: position + 1,
+        is_held: entry.is_held,
+        joined_at: entry.joined_at,
+    }))
+}
+
+pub async fn guest_leave(ticket: GuestTicket, db: Data<DbService>) -> RespResult<&'static str> {
+    queue_remove_member_inner(db, ticket.queue, ticket.guest).await
+}
+
+// -----
+// Admin
+// -----
+
+pub async fn jwks(jwt_config: Data<JwtConfig>) -> Json<JwkSet> {
+    Json(jwt_config.keyring.jwks())
+}
+
+fn key_infos(jwt_config: &JwtConfig) -> Vec<KeyInfo> {
+    let signing_kid = jwt_config.keyring.signing_key().kid;
+    jwt_config
+        .keyring
+        .keys()
+        .into_iter()
+        .map(|key| KeyInfo {
+            is_signing: key.kid == signing_kid,
+            algorithm: format!("{:?}", key.algorithm),
+            kid: key.kid,
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+        })
+        .collect()
 }
 
+pub async fn admin_keys(jwt_config: Data<JwtConfig>) -> RespResult<Json<Vec<KeyInfo>>> {
+    Ok(Json(key_infos(&jwt_config)))
+}
 
+pub async fn admin_keys_rotate(
+    jwt_config: Data<JwtConfig>,
+    db: Data<DbService>,
+) -> RespResult<Json<Vec<KeyInfo>>> {
+    jwt_config
+        .keyring
+        .rotate(&db, jwt_config.algorithm, jwt_config.key_grace_period())?;
+    Ok(Json(key_infos(&jwt_config)))
+}
@@ -0,0 +1,220 @@
+use actix_web::http::header::LINK;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::NaiveDateTime;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::domain::Page;
+use crate::error::ApiError;
+use crate::handlers::req::QueueSort;
+
+/// Page size when `limit` is not given and its upper bound.
+pub const DEFAULT_LIMIT: u32 = 50;
+pub const MAX_LIMIT: u32 = 100;
+
+pub fn limit(limit: Option<u32>) -> Result<u32, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::InvalidRequest(format!(
+            "limit must be from 1 to {}",
+            MAX_LIMIT
+        )));
+    }
+    Ok(limit)
+}
+
+/// Position in `/queues`, with both keys so it is checked against the sort
+/// only.
+#[derive(Serialize, Deserialize)]
+pub struct QueueCursor {
+    pub sort: QueueSort,
+    pub created_at: NaiveDateTime,
+    pub name: String,
+    pub id: Uuid,
+}
+
+/// Position in `/queues/{queue_id}/members`, `id` is of the entry.
+#[derive(Serialize, Deserialize)]
+pub struct MemberCursor {
+    pub is_held: bool,
+    pub order: i32,
+    pub id: Uuid,
+}
+
+/// Position in `/queues/{queue_id}/join-requests`.
+#[derive(Serialize, Deserialize)]
+pub struct JoinRequestCursor {
+    pub requested_at: NaiveDateTime,
+    pub id: Uuid,
+}
+
+/// Position in `/queues/search`, by the rank of the match first.
+#[derive(Serialize, Deserialize)]
+pub struct SearchCursor {
+    pub rank: f32,
+    pub created_at: NaiveDateTime,
+    pub id: Uuid,
+}
+
+/// Cursors are opaque to clients: the keys of the last item as JSON in
+/// URL safe base64.
+pub fn encode_cursor<C: Serialize>(cursor: &C) -> String {
+    let json = serde_json::to_vec(cursor).expect("Can not serialize cursor");
+    base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_cursor<C: DeserializeOwned>(cursor: &str) -> Result<C, ApiError> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| ApiError::InvalidRequest("invalid cursor".to_string()))
+}
+
+/// Makes a page of `rows` loaded with `limit + 1`, the extra row only tells
+/// that there is a next page.
+pub fn page<R, T, C>(
+    mut rows: Vec<R>,
+    limit: u32,
+    cursor: impl Fn(&R) -> C,
+    item: impl FnMut(R) -> T,
+) -> Page<T>
+where
+    C: Serialize,
+{
+    let has_next = rows.len() > limit as usize;
+    rows.truncate(limit as usize);
+    let next_cursor = match rows.last() {
+        Some(last) if has_next => Some(encode_cursor(&cursor(last))),
+        _ => None,
+    };
+    Page {
+        items: rows.into_iter().map(item).collect(),
+        next_cursor,
+    }
+}
+
+/// Responds with the page and a `Link` to the next one, which is the same
+/// request with `after` set.
+pub fn respond<T: Serialize>(req: &HttpRequest, page: Page<T>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(cursor) = &page.next_cursor {
+        let mut query = req
+            .query_string()
+            .split('&')
+            .filter(|pair| !pair.is_empty() && !pair.starts_with("after="))
+            .collect::<Vec<_>>();
+        let after = format!("after={}", cursor);
+        query.push(&after);
+        let link = format!("<{}?{}>; rel=\"next\"", req.path(), query.join("&"));
+        response.insert_header((LINK, link));
+    }
+    response.json(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::test::TestRequest;
+    use chrono::Utc;
+
+    use super::*;
+
+    fn cursor() -> QueueCursor {
+        QueueCursor {
+            sort: QueueSort::Name,
+            created_at: Utc::now().naive_utc(),
+            name: "Ann's queue / №1".to_string(),
+            id: Uuid::new_v4(),
+        }
+    }
+
+    #[test]
+    fn limit_is_checked() {
+        assert_eq!(limit(None).unwrap(), DEFAULT_LIMIT);
+        assert_eq!(limit(Some(MAX_LIMIT)).unwrap(), MAX_LIMIT);
+        assert!(matches!(limit(Some(0)), Err(ApiError::InvalidRequest(_))));
+        assert!(matches!(limit(Some(MAX_LIMIT + 1)), Err(ApiError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = cursor();
+        let encoded = encode_cursor(&cursor);
+        assert!(encoded
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+        let decoded = decode_cursor::<QueueCursor>(&encoded).unwrap();
+        assert_eq!(decoded.sort, cursor.sort);
+        assert_eq!(decoded.created_at, cursor.created_at);
+        assert_eq!(decoded.name, cursor.name);
+        assert_eq!(decoded.id, cursor.id);
+    }
+
+    #[test]
+    fn garbled_cursor_is_invalid_request() {
+        let encoded = encode_cursor(&cursor());
+        let garbled = [
+            "",
+            "!!!",
+            &encoded[..encoded.len() / 2],
+            &encode_cursor(&"not a cursor"),
+            // A cursor of another list
+            &encode_cursor(&MemberCursor {
+                is_held: false,
+                order: 1,
+                id: Uuid::new_v4(),
+            }),
+        ];
+        for cursor in garbled {
+            let decoded = decode_cursor::<QueueCursor>(cursor);
+            assert!(matches!(decoded, Err(ApiError::InvalidRequest(_))), "{:?}", cursor);
+        }
+    }
+
+    fn rows(count: i32) -> Vec<i32> {
+        (1..=count).collect()
+    }
+
+    fn page_of(rows: Vec<i32>, limit: u32) -> Page<i32> {
+        page(rows, limit, |row| *row, |row| row)
+    }
+
+    #[test]
+    fn extra_row_makes_next_cursor() {
+        let page = page(rows(3), 2, |row| *row, |row| row * 10);
+        assert_eq!(page.items, vec![10, 20]);
+        assert_eq!(decode_cursor::<i32>(&page.next_cursor.unwrap()).unwrap(), 2);
+
+        let last = page_of(rows(2), 2);
+        assert_eq!(last.items, vec![1, 2]);
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(page_of(Vec::new(), 2).next_cursor, None);
+    }
+
+    #[actix_rt::test]
+    async fn link_replaces_after() {
+        let req = TestRequest::get()
+            .uri("/api/queues?sort=name&after=old&limit=2")
+            .to_http_request();
+        let res = respond(&req, page_of(rows(3), 2));
+
+        let link = res.headers().get(LINK).unwrap().to_str().unwrap();
+        let next = encode_cursor(&2);
+        assert_eq!(
+            link,
+            format!("</api/queues?sort=name&limit=2&after={}>; rel=\"next\"", next)
+        );
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let body: Page<i32> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.next_cursor, Some(next));
+    }
+
+    #[test]
+    fn last_page_has_no_link() {
+        let req = TestRequest::get().uri("/api/queues").to_http_request();
+        let res = respond(&req, page_of(rows(2), 2));
+        assert!(res.headers().get(LINK).is_none());
+    }
+}
@@ -2,17 +2,22 @@ use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::domain::Id;
+use crate::auth::scope::Scope;
+use crate::domain::QueueRole;
+use crate::qr::ErrorCorrection;
 
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SignUp {
     pub email: String,
     pub name: String,
     pub password: String,
+    #[serde(default)]
+    pub username: Option<String>,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SignIn {
+    /// Email or username.
     pub login: String,
     pub password: String,
 }
@@ -20,6 +25,85 @@ pub struct SignIn {
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct SignInResponse {
     pub token: String,
+    /// Lifetime of `token` in seconds.
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+/// Parameters of the redirect from the OpenID Connect provider.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SetLocale {
+    pub locale: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SetName {
+    pub name: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SetUsername {
+    /// `null` removes the username.
+    pub username: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SetPassword {
+    /// Not asked from accounts without a password, see `SetEmail`.
+    pub current_password: Option<String>,
+    pub new_password: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SetEmail {
+    pub email: String,
+    /// Current password, a stolen access token must not be enough to take
+    /// over the account through password reset. Accounts created by single
+    /// sign-on have none, they have to have signed in recently instead.
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrganizedQueues {
+    /// Queues are closed together with the account.
+    Delete,
+    /// Each queue is handed to its first member, or closed if it has none.
+    Transfer,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DeleteAccount {
+    /// Not asked from accounts without a password, see `SetEmail`.
+    pub password: Option<String>,
+    pub organized_queues: Option<OrganizedQueues>,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
@@ -28,6 +112,151 @@ pub struct CreateQueue {
     pub description: String,
 }
 
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CreateJoinCode {
+    /// Lifetime in seconds, the code doesn't expire when not set.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// Number of joins, unlimited when not set. A join request counts as a
+    /// join even if it is rejected.
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueState {
+    /// The queue exists.
+    Open,
+    /// `exists_before` has passed.
+    Expired,
+}
+
+/// Order of `/queues`, `-` is descending.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum QueueSort {
+    #[serde(rename = "created_at")]
+    CreatedAt,
+    #[serde(rename = "-created_at")]
+    #[default]
+    CreatedAtDesc,
+    #[serde(rename = "name")]
+    Name,
+    #[serde(rename = "-name")]
+    NameDesc,
+}
+
+/// Query of `/queues`.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ListQueues {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub after: Option<String>,
+    #[serde(default)]
+    pub sort: QueueSort,
+    #[serde(default)]
+    pub role: Option<QueueRole>,
+    #[serde(default)]
+    pub state: Option<QueueState>,
+    /// Inclusive.
+    #[serde(default)]
+    pub created_after: Option<NaiveDateTime>,
+    /// Exclusive.
+    #[serde(default)]
+    pub created_before: Option<NaiveDateTime>,
+}
+
+/// Query of `/queues/{queue_id}/members`.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ListMembers {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+/// Query of `/queues/{queue_id}/join-requests`.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ListJoinRequests {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+/// Query of `/queues/search`.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SearchQueues {
+    /// Words in the name or the description. Quoted phrases, `or` and `-`
+    /// for exclusion are understood.
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub organizer_id: Option<Uuid>,
+    #[serde(default)]
+    pub state: Option<QueueState>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+/// Query of the join link QR code.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct QrOptions {
+    /// Largest width and height in pixels.
+    #[serde(default)]
+    pub size: Option<u32>,
+    #[serde(default)]
+    pub ec: ErrorCorrection,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GuestJoin {
+    /// Name shown to the organizer.
+    pub name: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GuestJoinResponse {
+    pub id: Uuid,
+    /// Bearer token for `/guest/me`.
+    pub ticket: String,
+    /// Lifetime of `ticket` in seconds.
+    pub expires_in: u64,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct QueueSettings {
+    pub require_verified_email: Option<bool>,
+    pub allow_guests: Option<bool>,
+    pub is_public: Option<bool>,
+    /// Turning it off lets the pending requests in.
+    pub require_approval: Option<bool>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Lifetime in seconds, the token doesn't expire when not set.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CreateApiTokenResponse {
+    pub id: Uuid,
+    /// Shown only once, only its hash is stored.
+    pub token: String,
+}
+
+#[allow(dead_code)]
 pub mod values {
     pub const fn true_value() -> bool {
         true
@@ -0,0 +1,629 @@
+use std::sync::Once;
+
+use actix_service::Service;
+use actix_web::http::header::{ACCEPT_LANGUAGE, AUTHORIZATION};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpMessage};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use chrono::Utc;
+use diesel::r2d2::ConnectionManager;
+use jsonwebtoken::Algorithm;
+use uuid::Uuid;
+
+use super::*;
+use crate::auth::keys::{Keyring, SecretCipher};
+use crate::auth::oidc::mock::{MockIdp, MockUser};
+use crate::auth::password::HashScheme;
+use crate::auth::scope::scoped;
+use crate::auth::throttle::MemoryAttemptStore;
+use crate::db::DbPool;
+
+/// Database of `TEST_DATABASE_URL` with the migrations applied. Tests that
+/// need it are skipped when it is not set.
+fn test_db() -> Option<Data<DbService>> {
+    let url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL is not set, skipped");
+            return None;
+        }
+    };
+    let pool = DbPool::builder()
+        .max_size(2)
+        .build(ConnectionManager::new(url))
+        .unwrap();
+    static MIGRATIONS: Once = Once::new();
+    MIGRATIONS.call_once(|| crate::embedded_migrations::run(&pool.get().unwrap()).unwrap());
+    Some(Data::new(DbService::new(pool)))
+}
+
+/// Provider account with an unused subject and email.
+fn idp_user() -> MockUser {
+    let id = Uuid::new_v4();
+    MockUser {
+        subject: id.to_string(),
+        email: format!("{}@example.com", id),
+        email_verified: true,
+        name: "Ann Smith".to_string(),
+    }
+}
+
+/// Goes through `/auth/oidc/idp/callback` as `user`.
+async fn sso_sign_in(idp: &MockIdp, db: &Data<DbService>, user: &MockUser) -> StatusCode {
+    let oidc = Data::new(idp.login(&["idp"]));
+    let url = oidc.start("idp").await.unwrap();
+    let (code, state) = idp.authorize(&url, user, None);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(crate::auth::tests::config()))
+            .app_data(oidc)
+            .app_data(db.clone())
+            .route("/auth/oidc/{provider}/callback", web::post().to(oidc_callback)),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/auth/oidc/idp/callback")
+        .set_json(&OidcCallback { code, state })
+        .to_request();
+    test::call_service(&app, req).await.status()
+}
+
+#[actix_rt::test]
+async fn sso_first_login_creates_user() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let idp = MockIdp::start().await;
+    let user = idp_user();
+
+    assert_eq!(sso_sign_in(&idp, &db, &user).await, StatusCode::OK);
+
+    let account = db.user_by_identity("idp", &user.subject).unwrap().unwrap();
+    assert_eq!(account.email, user.email);
+    assert_eq!(account.name, user.name);
+    assert!(account.pwhash.is_empty());
+    assert!(account.email_verified_at.is_some());
+}
+
+#[actix_rt::test]
+async fn sso_second_login_maps_by_subject() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let idp = MockIdp::start().await;
+    let user = idp_user();
+    assert_eq!(sso_sign_in(&idp, &db, &user).await, StatusCode::OK);
+    let first = db.user_by_identity("idp", &user.subject).unwrap().unwrap();
+
+    // The email changed at the provider, the subject is what counts
+    let renamed = MockUser {
+        email: format!("new-{}", user.email),
+        ..user.clone()
+    };
+    assert_eq!(sso_sign_in(&idp, &db, &renamed).await, StatusCode::OK);
+
+    let second = db.user_by_identity("idp", &user.subject).unwrap().unwrap();
+    assert_eq!(second.id, first.id);
+    assert!(db.user_by_email(&renamed.email).unwrap().is_none());
+}
+
+#[actix_rt::test]
+async fn sso_links_account_with_verified_email() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let idp = MockIdp::start().await;
+    let user = idp_user();
+    let account = UserDao {
+        id: Uuid::new_v4(),
+        name: "Ann Local".to_string(),
+        email: user.email.clone(),
+        pwhash: "hash".to_string(),
+        locale: None,
+        email_verified_at: Some(Utc::now().naive_utc()),
+        username: None,
+    };
+    db.add_user(&account).unwrap();
+
+    assert_eq!(sso_sign_in(&idp, &db, &user).await, StatusCode::OK);
+
+    let linked = db.user_by_identity("idp", &user.subject).unwrap().unwrap();
+    assert_eq!(linked.id, account.id);
+    assert_eq!(linked.pwhash, "hash");
+}
+
+#[actix_rt::test]
+async fn sso_does_not_link_unverified_email() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let idp = MockIdp::start().await;
+    let user = idp_user();
+    let account = UserDao {
+        id: Uuid::new_v4(),
+        name: "Ann Local".to_string(),
+        email: user.email.clone(),
+        pwhash: "hash".to_string(),
+        locale: None,
+        email_verified_at: None,
+        username: None,
+    };
+    db.add_user(&account).unwrap();
+
+    assert_eq!(sso_sign_in(&idp, &db, &user).await, StatusCode::CONFLICT);
+    assert!(db.user_by_identity("idp", &user.subject).unwrap().is_none());
+}
+
+/// User with an unused email.
+fn add_user(db: &DbService) -> Uuid {
+    let id = Uuid::new_v4();
+    let user = UserDao {
+        id,
+        name: "Ann Smith".to_string(),
+        email: format!("{}@example.com", id),
+        pwhash: "hash".to_string(),
+        locale: None,
+        email_verified_at: None,
+        username: None,
+    };
+    db.add_user(&user).unwrap();
+    id
+}
+
+fn add_queue(db: &DbService, organizer_id: Uuid, require_approval: bool) -> QueueDao {
+    let now = Utc::now().naive_utc();
+    let queue = QueueDao {
+        id: Uuid::new_v4(),
+        name: "Queue".to_string(),
+        description: String::new(),
+        organizer_id,
+        created_at: now,
+        exists_before: now + chrono::Duration::days(1),
+        require_verified_email: false,
+        allow_guests: false,
+        is_public: false,
+        updated_at: now,
+        require_approval,
+    };
+    db.add_queue(&queue).unwrap();
+    queue
+}
+
+fn auth(user_id: Uuid) -> Auth {
+    Auth::new(user_id, Uuid::new_v4(), std::time::Duration::from_secs(60))
+}
+
+#[actix_rt::test]
+async fn only_organizer_manages_members() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let stranger = add_user(&db);
+    let member = add_user(&db);
+    let queue = add_queue(&db, organizer, true);
+
+    let added = queue_add_member(auth(stranger), db.clone(), Path::from((queue.id, member))).await;
+    assert!(matches!(added, Err(ApiError::NotOrganizer)));
+    assert!(db.join_requests_of_user(&member).unwrap().is_empty());
+
+    queue_add_member(auth(organizer), db.clone(), Path::from((queue.id, member)))
+        .await
+        .unwrap();
+    let removed = queue_remove_member(auth(stranger), db.clone(), Path::from((queue.id, member))).await;
+    assert!(matches!(removed, Err(ApiError::NotOrganizer)));
+    assert_eq!(db.memberships(&member).unwrap().len(), 1);
+
+    queue_remove_member(auth(organizer), db.clone(), Path::from((queue.id, member)))
+        .await
+        .unwrap();
+    assert!(db.memberships(&member).unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn read_only_api_token_cannot_write() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let user = add_user(&db);
+    let create = CreateApiToken {
+        name: "dashboard".to_string(),
+        scopes: vec![Scope::QueuesRead],
+        expires_in: None,
+    };
+    let token = api_token_create(auth(user), db.clone(), Json(create))
+        .await
+        .unwrap()
+        .0
+        .token;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(crate::auth::tests::config()))
+            .app_data(db.clone())
+            .wrap(HttpAuthentication::bearer(crate::auth::bearer_validator))
+            .route("/queues", scoped(web::get(), Scope::QueuesRead, queues))
+            .route("/queues", scoped(web::post(), Scope::QueuesWrite, queue_create)),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/queues")
+        .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::post()
+        .uri("/queues")
+        .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+        .set_json(&CreateQueue {
+            name: "Queue".to_string(),
+            description: String::new(),
+        })
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    assert!(db.organized_queues(&user).unwrap().is_empty());
+}
+
+/// Users of the queue in order.
+fn members(db: &DbService, queue_id: &Uuid) -> Vec<Uuid> {
+    db.entries_ordered(queue_id)
+        .unwrap()
+        .into_iter()
+        .filter_map(|(entry, _)| entry.user_id)
+        .collect()
+}
+
+/// Asks to join the moderated queue, returns the stored request.
+async fn request_join(db: &Data<DbService>, queue_id: Uuid, user_id: Uuid) -> JoinRequestDao {
+    let res = queue_add_member_me(auth(user_id), db.clone(), Path::from(queue_id))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    db.join_requests_of_user(&user_id)
+        .unwrap()
+        .into_iter()
+        .map(|(request, _)| request)
+        .find(|request| request.queue_id == queue_id)
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn approved_request_joins_at_request_time() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let (early, late) = (add_user(&db), add_user(&db));
+    let queue = add_queue(&db, organizer, true);
+
+    let request = request_join(&db, queue.id, early).await;
+    queue_add_member(auth(organizer), db.clone(), Path::from((queue.id, late)))
+        .await
+        .unwrap();
+    assert_eq!(members(&db, &queue.id), vec![late]);
+
+    join_request_approve(auth(organizer), db.clone(), Path::from((queue.id, request.id)))
+        .await
+        .unwrap();
+
+    assert_eq!(members(&db, &queue.id), vec![early, late]);
+    let (entry, _) = db.memberships(&early).unwrap().pop().unwrap();
+    assert_eq!(entry.joined_at, request.requested_at);
+    assert!(db.join_requests_of_user(&early).unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn approving_request_of_member_drops_it() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let user = add_user(&db);
+    let queue = add_queue(&db, organizer, true);
+    let request = request_join(&db, queue.id, user).await;
+    // Joined another way while the request was pending
+    let entry = QueueEntryToAdd {
+        queue_id: queue.id,
+        user_id: user,
+        has_priority: false,
+        joined_at: Utc::now().naive_utc(),
+    };
+    db.add_entry(&entry).unwrap();
+
+    join_request_approve(auth(organizer), db.clone(), Path::from((queue.id, request.id)))
+        .await
+        .unwrap();
+
+    assert_eq!(members(&db, &queue.id), vec![user]);
+    assert!(db.join_requests_of_user(&user).unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn adding_requesting_user_drops_request() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let user = add_user(&db);
+    let queue = add_queue(&db, organizer, true);
+    request_join(&db, queue.id, user).await;
+
+    queue_add_member(auth(organizer), db.clone(), Path::from((queue.id, user)))
+        .await
+        .unwrap();
+
+    assert_eq!(members(&db, &queue.id), vec![user]);
+    assert!(db.join_requests_of_user(&user).unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn rejected_request_is_dropped() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let user = add_user(&db);
+    let queue = add_queue(&db, organizer, true);
+    let request = request_join(&db, queue.id, user).await;
+
+    join_request_reject(auth(organizer), db.clone(), Path::from((queue.id, request.id)))
+        .await
+        .unwrap();
+
+    assert!(members(&db, &queue.id).is_empty());
+    assert!(db.join_requests_of_user(&user).unwrap().is_empty());
+    let again = join_request_reject(auth(organizer), db.clone(), Path::from((queue.id, request.id))).await;
+    assert!(matches!(again, Err(ApiError::JoinRequestNotFound)));
+}
+
+#[actix_rt::test]
+async fn leaving_withdraws_request() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let user = add_user(&db);
+    let queue = add_queue(&db, organizer, true);
+    request_join(&db, queue.id, user).await;
+
+    queue_remove_member_me(auth(user), db.clone(), Path::from(queue.id))
+        .await
+        .unwrap();
+
+    assert!(db.join_requests_of_user(&user).unwrap().is_empty());
+    let again = queue_remove_member_me(auth(user), db.clone(), Path::from(queue.id)).await;
+    assert!(matches!(again, Err(ApiError::MemberNotFound)));
+}
+
+#[actix_rt::test]
+async fn turning_approval_off_approves_requests() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let organizer = add_user(&db);
+    let (first, second) = (add_user(&db), add_user(&db));
+    let queue = add_queue(&db, organizer, true);
+    request_join(&db, queue.id, first).await;
+    request_join(&db, queue.id, second).await;
+
+    let settings = QueueSettings {
+        require_verified_email: None,
+        allow_guests: None,
+        is_public: None,
+        require_approval: Some(false),
+    };
+    let info = queue_update_settings(auth(organizer), Path::from(queue.id), db.clone(), Json(settings))
+        .await
+        .unwrap();
+
+    assert!(!info.require_approval);
+    assert_eq!(members(&db, &queue.id), vec![first, second]);
+    assert!(db.join_requests_of_user(&first).unwrap().is_empty());
+    assert!(db.join_requests_of_user(&second).unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn sign_in_rehashes_bcrypt_password() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let user_id = add_user(&db);
+    let bcrypt = PasswordHasher::new(HashScheme::Bcrypt { cost: 4 });
+    db.set_user_pwhash(&user_id, &bcrypt.hash("Zq8!mvT3kpL").unwrap())
+        .unwrap();
+    let email = db.user_by_id(&user_id).unwrap().unwrap().email;
+    let hasher = Data::new(PasswordHasher::new(HashScheme::Argon2id {
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    }));
+    let throttle = Data::new(SignInThrottle::new(
+        Box::new(MemoryAttemptStore::default()),
+        5,
+        20,
+        std::time::Duration::from_secs(60),
+    ));
+
+    let data = SignIn {
+        login: email,
+        password: "Zq8!mvT3kpL".to_string(),
+    };
+    sign_in(
+        test::TestRequest::default().to_http_request(),
+        Data::new(crate::auth::tests::config()),
+        throttle,
+        hasher.clone(),
+        db.clone(),
+        Json(data),
+    )
+    .await
+    .unwrap();
+
+    let pwhash = db.user_by_id(&user_id).unwrap().unwrap().pwhash;
+    assert!(pwhash.starts_with("$argon2id$"));
+    assert_eq!(hasher.verify("Zq8!mvT3kpL", &pwhash).unwrap(), Verified::Yes);
+}
+
+fn forwarded(peer: &str, xff: &[&str], proxies: &[&str]) -> Option<String> {
+    let mut headers = HeaderMap::new();
+    for value in xff {
+        headers.append(
+            "x-forwarded-for".parse().unwrap(),
+            value.parse().unwrap(),
+        );
+    }
+    let proxies = TrustedProxies(proxies.iter().map(|p| p.parse().unwrap()).collect());
+    forwarded_client_ip(Some(peer.parse().unwrap()), &headers, Some(&proxies))
+}
+
+#[test]
+fn forwarded_for_is_ignored_from_untrusted_peer() {
+    let ip = forwarded("203.0.113.9:4000", &["198.51.100.1"], &[]);
+    assert_eq!(ip.as_deref(), Some("203.0.113.9"));
+    let ip = forwarded("203.0.113.9:4000", &["198.51.100.1"], &["10.0.0.1"]);
+    assert_eq!(ip.as_deref(), Some("203.0.113.9"));
+    let headers = HeaderMap::new();
+    assert_eq!(forwarded_client_ip(None, &headers, None), None);
+}
+
+#[test]
+fn forwarded_for_is_read_from_the_right() {
+    let proxies = ["10.0.0.1", "10.0.0.2"];
+    // The client can prepend anything, only the address added by the proxy counts
+    let ip = forwarded("10.0.0.1:4000", &["1.1.1.1, 198.51.100.1"], &proxies);
+    assert_eq!(ip.as_deref(), Some("198.51.100.1"));
+    // Chained proxies, in several headers
+    let ip = forwarded("10.0.0.1:4000", &["198.51.100.1", "10.0.0.2"], &proxies);
+    assert_eq!(ip.as_deref(), Some("198.51.100.1"));
+    // Garbage before a trusted address stops the search
+    let ip = forwarded("10.0.0.1:4000", &["198.51.100.1, junk, 10.0.0.2"], &proxies);
+    assert_eq!(ip.as_deref(), Some("10.0.0.1"));
+    let ip = forwarded("10.0.0.1:4000", &[], &proxies);
+    assert_eq!(ip.as_deref(), Some("10.0.0.1"));
+}
+
+#[test]
+fn rotated_key_is_loaded_for_unknown_kid() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let cipher_key = base64::encode([7u8; 32]);
+    let instance = |cipher_key: &str| JwtConfig {
+        keyring: Keyring::new(
+            crate::auth::tests::config().keyring.signing_key(),
+            SecretCipher::from_base64(cipher_key),
+        ),
+        ..crate::auth::tests::config()
+    };
+    let (rotating, other) = (instance(&cipher_key), instance(&cipher_key));
+    let kid = rotating
+        .keyring
+        .rotate(&db, Algorithm::HS256, std::time::Duration::from_secs(60))
+        .unwrap();
+    let token = crate::auth::encode_token(&auth(Uuid::new_v4()), &rotating).unwrap();
+    assert!(crate::auth::decode_token(&token, &other).is_err());
+
+    other.keyring.reload_for_unknown_kid(&kid, &db);
+    assert!(crate::auth::decode_token(&token, &other).is_ok());
+
+    // Stored secrets can't be used without the encryption key
+    let wrong = instance(&base64::encode([8u8; 32]));
+    wrong.keyring.reload_for_unknown_kid(&kid, &db);
+    assert!(crate::auth::decode_token(&token, &wrong).is_err());
+}
+
+#[actix_rt::test]
+async fn refresh_token_reuse_revokes_session() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let config = Data::new(crate::auth::tests::config());
+    let sessions = Data::new(SessionCache::new(std::time::Duration::from_secs(60)));
+    let user_id = add_user(&db);
+    let req = test::TestRequest::default().to_http_request();
+    let first = start_session(&req, &config, &db, user_id).unwrap();
+    let refresh_with = |refresh_token: &str| {
+        let data = Refresh {
+            refresh_token: refresh_token.to_string(),
+        };
+        refresh(config.clone(), sessions.clone(), db.clone(), Json(data))
+    };
+
+    // Each refresh token is replaced by a new one
+    let second = refresh_with(&first.refresh_token).await.unwrap().0;
+    let third = refresh_with(&second.refresh_token).await.unwrap().0;
+    assert_ne!(second.refresh_token, third.refresh_token);
+    let session_id = crate::auth::decode_token(&third.token, &config).unwrap().jti;
+    assert!(db.session_by_id(&session_id).unwrap().unwrap().revoked_at.is_none());
+
+    // An old one presented again revokes the session with all its tokens
+    let reused = refresh_with(&first.refresh_token).await;
+    assert!(matches!(reused, Err(ApiError::RefreshTokenReused)));
+    assert!(db.session_by_id(&session_id).unwrap().unwrap().revoked_at.is_some());
+    let latest = refresh_with(&third.refresh_token).await;
+    assert!(matches!(latest, Err(ApiError::InvalidRefreshToken)));
+    let is_active = sessions
+        .is_active(&session_id, || async { Ok::<_, ApiError>(true) })
+        .await
+        .unwrap();
+    assert!(!is_active);
+}
+
+#[actix_rt::test]
+async fn errors_are_localized_for_user() {
+    let db = match test_db() {
+        Some(db) => db,
+        None => return,
+    };
+    let user_id = add_user(&db);
+    db.set_user_locale(&user_id, Some("ru")).unwrap();
+
+    // Errors both as responses and as errors of middleware further in
+    let app = test::init_service(
+        App::new()
+            .app_data(db.clone())
+            .wrap_fn(move |req, _srv| {
+                let fails = req.path() == "/error";
+                req.extensions_mut().insert(auth(user_id));
+                async move {
+                    if fails {
+                        return Err(ApiError::NotOrganizer.into());
+                    }
+                    Ok(req.error_response(ApiError::NotOrganizer))
+                }
+            })
+            .wrap_fn(crate::i18n::localize_errors)
+            .route("/{tail:.*}", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let expected = crate::i18n::message(Lang::Ru, &ApiError::NotOrganizer);
+    for path in ["/response", "/error"] {
+        let req = test::TestRequest::get()
+            .uri(path)
+            .insert_header((ACCEPT_LANGUAGE, "en"))
+            .to_request();
+        let body: crate::error::ErrorBody = match app.call(req).await {
+            Ok(res) => test::read_body_json(res).await,
+            Err(e) => {
+                let body = actix_web::body::to_bytes(e.error_response().into_body()).await.unwrap();
+                serde_json::from_slice(&body).unwrap()
+            }
+        };
+        assert_eq!(body.message, expected, "{}", path);
+    }
+}
@@ -0,0 +1,476 @@
+use std::future::Future;
+
+use actix_web::body::AnyBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, ACCEPT_LANGUAGE};
+use actix_web::web::{self, Data};
+use actix_web::{Error, HttpRequest};
+use log::error;
+
+use crate::auth::Auth;
+use crate::db::DbService;
+use crate::error::ApiError;
+
+// ----
+// Lang
+// ----
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Lang {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Ru => "ru",
+        }
+    }
+
+    /// Parses a language tag such as `ru`, `ru-RU` or `en_US`.
+    pub fn from_tag(tag: &str) -> Option<Lang> {
+        let primary = tag
+            .trim()
+            .split(['-', '_'])
+            .next()?
+            .to_lowercase();
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            "ru" => Some(Lang::Ru),
+            _ => None,
+        }
+    }
+
+    /// Picks the supported language with the highest weight from an
+    /// `Accept-Language` header value.
+    pub fn from_accept_language(header: &str) -> Option<Lang> {
+        let mut best: Option<(Lang, f32)> = None;
+        for item in header.split(',') {
+            let mut parts = item.split(';');
+            let lang = match parts.next().and_then(Lang::from_tag) {
+                Some(lang) => lang,
+                None => continue,
+            };
+            let weight = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight > 0.0 && best.is_none_or(|(_, w)| weight > w) {
+                best = Some((lang, weight));
+            }
+        }
+        best.map(|(lang, _)| lang)
+    }
+}
+
+// -------
+// Catalog
+// -------
+
+/// Messages keyed by error code: `(code, en, ru)`.
+/// `{name}` placeholders are filled from [`ApiError::params`].
+const CATALOG: &[(&str, &str, &str)] = &[
+    (
+        "INVALID_REQUEST",
+        "Invalid request: {details}",
+        "Некорректный запрос: {details}",
+    ),
+    (
+        "INVALID_NAME",
+        "Name must be at least {min} and at most {max} characters long.",
+        "Имя должно быть не менее {min} символов и не более {max}.",
+    ),
+    (
+        "INVALID_USERNAME",
+        "Username must be {min} to {max} characters long and contain only latin letters, digits, '_', '.' and '-'.",
+        "Имя пользователя должно содержать от {min} до {max} символов: латинские буквы, цифры, '_', '.' и '-'.",
+    ),
+    (
+        "UNSUPPORTED_LOCALE",
+        "Locale is not supported.",
+        "Язык не поддерживается.",
+    ),
+    (
+        "INVALID_EMAIL",
+        "Email address is invalid.",
+        "Некорректный адрес электронной почты.",
+    ),
+    (
+        "INVALID_CREDENTIALS",
+        "Illegal login or password.",
+        "Неверный логин или пароль.",
+    ),
+    (
+        "INVALID_PASSWORD",
+        "Current password is incorrect.",
+        "Текущий пароль указан неверно.",
+    ),
+    (
+        "REAUTHENTICATION_REQUIRED",
+        "Sign in again to confirm this change.",
+        "Войдите снова, чтобы подтвердить это изменение.",
+    ),
+    (
+        "PASSWORD_TOO_SHORT",
+        "Password must be at least {min} characters long.",
+        "Пароль должен содержать не менее {min} символов.",
+    ),
+    (
+        "PASSWORD_TOO_LONG",
+        "Password must be at most {max} characters long.",
+        "Пароль должен содержать не более {max} символов.",
+    ),
+    (
+        "PASSWORD_TOO_COMMON",
+        "This password is too common, choose another one.",
+        "Этот пароль слишком распространён, выберите другой.",
+    ),
+    (
+        "UNAUTHORIZED",
+        "Authorization is required.",
+        "Требуется авторизация.",
+    ),
+    (
+        "TOO_MANY_ATTEMPTS",
+        "Too many sign-in attempts. Try again in {retry_after} s.",
+        "Слишком много попыток входа. Повторите через {retry_after} с.",
+    ),
+    (
+        "RATE_LIMITED",
+        "Too many requests. Try again in {retry_after} s.",
+        "Слишком много запросов. Повторите через {retry_after} с.",
+    ),
+    (
+        "INVALID_TOKEN",
+        "Access token is invalid.",
+        "Токен доступа недействителен.",
+    ),
+    (
+        "TOKEN_EXPIRED",
+        "Access token has expired.",
+        "Срок действия токена доступа истёк.",
+    ),
+    (
+        "INVALID_REFRESH_TOKEN",
+        "Refresh token is invalid or expired.",
+        "Токен обновления недействителен или просрочен.",
+    ),
+    (
+        "REFRESH_TOKEN_REUSED",
+        "Refresh token has already been used. Please sign in again.",
+        "Токен обновления уже был использован. Войдите заново.",
+    ),
+    (
+        "INSUFFICIENT_SCOPE",
+        "The token is not allowed to do this.",
+        "Этот токен не позволяет выполнить действие.",
+    ),
+    (
+        "INVALID_RESET_TOKEN",
+        "Password reset link is invalid or expired.",
+        "Ссылка для сброса пароля недействительна или устарела.",
+    ),
+    (
+        "INVALID_VERIFICATION_TOKEN",
+        "Email verification link is invalid or expired.",
+        "Ссылка для подтверждения почты недействительна или устарела.",
+    ),
+    (
+        "SESSION_REVOKED",
+        "Session has been signed out.",
+        "Сеанс был завершён.",
+    ),
+    (
+        "SESSION_NOT_FOUND",
+        "Session is not found.",
+        "Сеанс не найден.",
+    ),
+    (
+        "API_TOKEN_NOT_FOUND",
+        "API token is not found.",
+        "API-токен не найден.",
+    ),
+    (
+        "OIDC_PROVIDER_NOT_FOUND",
+        "Sign in provider is not found.",
+        "Провайдер входа не найден.",
+    ),
+    (
+        "INVALID_OIDC_STATE",
+        "Sign in has expired or was already completed, please start again.",
+        "Вход устарел или уже завершён, начните заново.",
+    ),
+    (
+        "OIDC_LOGIN_FAILED",
+        "Sign in with the provider has failed.",
+        "Не удалось войти через провайдера.",
+    ),
+    (
+        "KEY_ROTATION_UNSUPPORTED",
+        "Signing keys of this algorithm can not be generated.",
+        "Ключи подписи для этого алгоритма не могут быть сгенерированы.",
+    ),
+    (
+        "USER_NOT_FOUND",
+        "User with this id is not found.",
+        "Пользователь с таким идентификатором не найден.",
+    ),
+    (
+        "EMAIL_TAKEN",
+        "User with this email is already registered.",
+        "Пользователь с такой почтой уже зарегистрирован.",
+    ),
+    (
+        "USERNAME_TAKEN",
+        "This username is already taken.",
+        "Это имя пользователя уже занято.",
+    ),
+    (
+        "EMAIL_ALREADY_VERIFIED",
+        "Email is already verified.",
+        "Почта уже подтверждена.",
+    ),
+    (
+        "EMAIL_NOT_VERIFIED",
+        "The queue requires a verified email.",
+        "Для этой очереди нужна подтверждённая почта.",
+    ),
+    (
+        "QUEUE_NOT_FOUND",
+        "Queue does not exist.",
+        "Очередь не существует.",
+    ),
+    (
+        "NOT_ORGANIZER",
+        "You are not the queue organizer.",
+        "Вы не являетесь организатором очереди.",
+    ),
+    (
+        "GUESTS_NOT_ALLOWED",
+        "The queue can't be joined without an account.",
+        "В эту очередь нельзя встать без учётной записи.",
+    ),
+    (
+        "JOIN_CODE_NOT_FOUND",
+        "Join code is not found or is no longer valid.",
+        "Код для входа в очередь не найден или больше не действует.",
+    ),
+    (
+        "JOIN_REQUEST_NOT_FOUND",
+        "Join request is not found.",
+        "Заявка на вход в очередь не найдена.",
+    ),
+    (
+        "JOIN_REQUEST_PENDING",
+        "Join request is already waiting for approval.",
+        "Заявка на вход в очередь уже ожидает одобрения.",
+    ),
+    (
+        "ALREADY_MEMBER",
+        "User is already a member of this queue.",
+        "Пользователь уже состоит в этой очереди.",
+    ),
+    (
+        "MEMBER_NOT_FOUND",
+        "User is not a member of this queue.",
+        "Пользователь не состоит в этой очереди.",
+    ),
+    (
+        "NOT_FOUND",
+        "Referenced resource does not exist.",
+        "Запрошенный ресурс не существует.",
+    ),
+    (
+        "CONFLICT",
+        "Resource already exists.",
+        "Ресурс уже существует.",
+    ),
+    (
+        "INTERNAL_ERROR",
+        "Internal server error.",
+        "Внутренняя ошибка сервера.",
+    ),
+];
+
+pub fn message(lang: Lang, err: &ApiError) -> String {
+    let code = err.code();
+    let template = CATALOG
+        .iter()
+        .find(|(c, ..)| *c == code)
+        .map(|(_, en, ru)| match lang {
+            Lang::En => *en,
+            Lang::Ru => *ru,
+        })
+        .unwrap_or(code);
+
+    err.params()
+        .into_iter()
+        .fold(template.to_string(), |msg, (name, value)| {
+            msg.replace(&format!("{{{}}}", name), &value)
+        })
+}
+
+// ----------
+// Middleware
+// ----------
+
+pub fn accept_language(headers: &HeaderMap) -> Option<Lang> {
+    headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(Lang::from_accept_language)
+}
+
+/// The locale is read on the blocking pool, the middleware runs on the
+/// executor.
+async fn user_lang(req: &HttpRequest) -> Option<Lang> {
+    let user_id = req.extensions().get::<Auth>()?.id;
+    let db = req.app_data::<Data<DbService>>()?.clone();
+    match web::block(move || db.user_locale(&user_id)).await {
+        Ok(Ok(locale)) => locale.as_deref().and_then(Lang::from_tag),
+        Ok(Err(e)) => {
+            error!("{:?}", e);
+            None
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// The signed in user's preference if there is one, then `Accept-Language`,
+/// then the default.
+async fn request_lang(req: &HttpRequest) -> Lang {
+    match user_lang(req).await {
+        Some(lang) => lang,
+        None => accept_language(req.headers()).unwrap_or_default(),
+    }
+}
+
+fn localized_body(err: &ApiError, lang: Lang) -> Option<String> {
+    serde_json::to_string(&err.body(lang))
+        .map_err(|e| error!("{:?}", e))
+        .ok()
+}
+
+/// Re-renders [`ApiError`] responses in the language of the request, see
+/// [`request_lang`]. Errors returned by middleware instead of responses
+/// are localized the same way.
+pub fn localize_errors<S>(
+    mut req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    // Shares the extensions, so a user authenticated further in is seen
+    let http_req = req.parts_mut().0.clone();
+    let fut = srv.call(req);
+    async move {
+        match fut.await {
+            Ok(res) => {
+                let is_api_error = res
+                    .response()
+                    .error()
+                    .and_then(|e| e.as_error::<ApiError>())
+                    .is_some();
+                if !is_api_error {
+                    return Ok(res);
+                }
+                let lang = request_lang(res.request()).await;
+                let body = res
+                    .response()
+                    .error()
+                    .and_then(|e| e.as_error::<ApiError>())
+                    .and_then(|e| localized_body(e, lang));
+                match body {
+                    Some(body) => Ok(res.map_body(|_, _| AnyBody::from(body))),
+                    None => Ok(res),
+                }
+            }
+            Err(e) => {
+                let api_error = match e.as_error::<ApiError>() {
+                    Some(api_error) => api_error,
+                    None => return Err(e),
+                };
+                let lang = request_lang(&http_req).await;
+                let localized = localized_body(api_error, lang).map(|body| {
+                    let res = e.error_response().map_body(|_, _| AnyBody::from(body));
+                    InternalError::from_response(api_error.to_string(), res).into()
+                });
+                Err(localized.unwrap_or(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(Lang::from_tag("ru"), Some(Lang::Ru));
+        assert_eq!(Lang::from_tag(" RU-ru "), Some(Lang::Ru));
+        assert_eq!(Lang::from_tag("en_US"), Some(Lang::En));
+        assert_eq!(Lang::from_tag("de"), None);
+        assert_eq!(Lang::from_tag(""), None);
+    }
+
+    #[test]
+    fn accept_language_prefers_highest_weight() {
+        assert_eq!(Lang::from_accept_language("ru"), Some(Lang::Ru));
+        assert_eq!(Lang::from_accept_language("en;q=0.5, ru;q=0.9"), Some(Lang::Ru));
+        assert_eq!(Lang::from_accept_language("ru;q=0.4,en-GB;q=0.8"), Some(Lang::En));
+        // Without `q` the weight is 1, the first one wins a tie
+        assert_eq!(Lang::from_accept_language("ru-RU, en;q=1"), Some(Lang::Ru));
+        assert_eq!(Lang::from_accept_language("de, ru;q=0.1"), Some(Lang::Ru));
+        assert_eq!(Lang::from_accept_language("ru;q=0, en;q=junk"), Some(Lang::En));
+    }
+
+    #[test]
+    fn accept_language_falls_back_to_default() {
+        assert_eq!(Lang::from_accept_language("de, fr;q=0.8"), None);
+        assert_eq!(Lang::from_accept_language("ru;q=0"), None);
+        assert_eq!(Lang::from_accept_language(""), None);
+        assert_eq!(Lang::default(), Lang::En);
+    }
+
+    #[test]
+    fn catalog_has_both_languages() {
+        let mut codes = HashSet::new();
+        for (code, en, ru) in CATALOG {
+            assert!(codes.insert(*code), "{} is duplicated", code);
+            assert!(!en.is_empty() && en.is_ascii(), "{} en", code);
+            assert!(ru.chars().any(|c| ('а'..='я').contains(&c)), "{} ru", code);
+            // The same placeholders in both
+            let placeholders = |msg: &str| {
+                let mut names = msg
+                    .split('{')
+                    .skip(1)
+                    .filter_map(|rest| rest.split('}').next())
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            };
+            assert_eq!(placeholders(en), placeholders(ru), "{}", code);
+        }
+    }
+
+    #[test]
+    fn messages_fill_params() {
+        let err = ApiError::PasswordTooShort { min: 10 };
+        assert!(message(Lang::En, &err).contains("10"));
+        assert!(message(Lang::Ru, &err).contains("10"));
+        assert!(!message(Lang::Ru, &err).contains('{'));
+        assert_ne!(message(Lang::En, &ApiError::Internal), "INTERNAL_ERROR");
+    }
+}
@@ -0,0 +1,76 @@
+use rand::Rng;
+
+/// Characters of join codes, without the easily confused `0`, `O`, `1`, `I`
+/// and `L`.
+const ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// Length of a code without the dash.
+pub const LENGTH: usize = 6;
+
+/// Generates a code in the stored form, e.g. `K7F29Q`.
+pub fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    (0..LENGTH)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Reads a code as typed by a user: case, spaces and dashes don't matter.
+/// Returns `None` if it can't be a code.
+pub fn normalize(input: &str) -> Option<String> {
+    let code = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+    let is_valid = code.len() == LENGTH && code.bytes().all(|b| ALPHABET.contains(&b));
+    if is_valid {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// Splits a stored code in halves for reading, e.g. `K7F-29Q`.
+pub fn display(code: &str) -> String {
+    let (left, right) = code.split_at(code.len() / 2);
+    format!("{}-{}", left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_codes_are_normalized() {
+        for _ in 0..100 {
+            let code = generate();
+            assert_eq!(code.len(), LENGTH);
+            assert_eq!(normalize(&code).as_deref(), Some(code.as_str()));
+            assert_eq!(normalize(&display(&code)).as_deref(), Some(code.as_str()));
+        }
+    }
+
+    #[test]
+    fn normalize_ignores_case_and_separators() {
+        for input in ["K7F29Q", "k7f-29q", " K7F 29Q ", "k-7-f-2-9-q", "K7F\t29Q\n"] {
+            assert_eq!(normalize(input).as_deref(), Some("K7F29Q"), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn normalize_rejects_non_codes() {
+        // Ambiguous characters are never generated
+        for input in ["K7F290", "K7F29O", "K7F291", "K7F29I", "K7F29L", "k7f29l"] {
+            assert_eq!(normalize(input), None, "{:?}", input);
+        }
+        for input in ["", "K7F29", "K7F29QQ", "K7F_29Q", "К7F29Q"] {
+            assert_eq!(normalize(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn display_splits_in_halves() {
+        assert_eq!(display("K7F29Q"), "K7F-29Q");
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use log::info;
+use uuid::Uuid;
+
+use crate::mail::{Mail, MailError, Mailer};
+
+/// Mailer for local development. Writes every mail into its own `.eml` file
+/// in `dir`, or to the log when no directory is set.
+pub struct FileMailer {
+    from: String,
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(from: String, dir: Option<PathBuf>) -> Self {
+        FileMailer { from, dir }
+    }
+
+    fn render(&self, mail: &Mail) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}",
+            self.from,
+            mail.to,
+            mail.subject,
+            Utc::now().to_rfc2822(),
+            mail.body
+        )
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let text = self.render(mail);
+        match &self.dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                let name = format!(
+                    "{}-{}.eml",
+                    Utc::now().format("%Y%m%d%H%M%S"),
+                    Uuid::new_v4().to_simple()
+                );
+                fs::write(dir.join(name), text)?;
+            }
+            None => info!("Mail:\n{}", text),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::sync::Arc;
+
+use log::error;
+
+use crate::i18n::Lang;
+
+pub mod file;
+pub mod smtp;
+
+// ----
+// Mail
+// ----
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    InvalidAddress(String),
+    Transport(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::InvalidAddress(addr) => write!(f, "invalid address {}", addr),
+            MailError::Transport(e) => write!(f, "transport error: {}", e),
+            MailError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(e: std::io::Error) -> Self {
+        MailError::Io(e)
+    }
+}
+
+/// Delivers mails to users. Implementations may block, use [`send_later`]
+/// from handlers.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Sends the mail on the blocking thread pool. Failures are only logged, so
+/// the response doesn't depend on whether the mail was delivered.
+pub fn send_later(mailer: Arc<dyn Mailer>, mail: Mail) {
+    actix_rt::task::spawn_blocking(move || {
+        if let Err(e) = mailer.send(&mail) {
+            error!("Can not send mail to {}: {}", mail.to, e);
+        }
+    });
+}
+
+// ---------
+// Templates
+// ---------
+
+pub fn email_verification(lang: Lang, to: &str, name: &str, link: &str, valid_hours: u64) -> Mail {
+    let (subject, body) = match lang {
+        Lang::En => (
+            "Confirm your OQueue email".to_string(),
+            format!(
+                "Hello, {}!\n\n\
+                 Please confirm that this address belongs to your OQueue account:\n\n{}\n\n\
+                 The link is valid for {} hours.\n\
+                 If you didn't sign up for OQueue, just ignore this mail.\n",
+                name, link, valid_hours
+            ),
+        ),
+        Lang::Ru => (
+            "Подтвердите почту для OQueue".to_string(),
+            format!(
+                "Здравствуйте, {}!\n\n\
+                 Подтвердите, что этот адрес принадлежит вашей учётной записи OQueue:\n\n{}\n\n\
+                 Ссылка действительна {} ч.\n\
+                 Если вы не регистрировались в OQueue, просто проигнорируйте это письмо.\n",
+                name, link, valid_hours
+            ),
+        ),
+    };
+    Mail {
+        to: to.to_string(),
+        subject,
+        body,
+    }
+}
+
+pub fn password_reset(lang: Lang, to: &str, name: &str, link: &str, valid_minutes: u64) -> Mail {
+    let (subject, body) = match lang {
+        Lang::En => (
+            "OQueue password reset".to_string(),
+            format!(
+                "Hello, {}!\n\n\
+                 Someone asked to reset the password of your OQueue account.\n\
+                 Follow the link to set a new one:\n\n{}\n\n\
+                 The link is valid for {} minutes and can be used once.\n\
+                 If it wasn't you, just ignore this mail.\n",
+                name, link, valid_minutes
+            ),
+        ),
+        Lang::Ru => (
+            "Сброс пароля OQueue".to_string(),
+            format!(
+                "Здравствуйте, {}!\n\n\
+                 Кто-то запросил сброс пароля вашей учётной записи OQueue.\n\
+                 Чтобы задать новый пароль, перейдите по ссылке:\n\n{}\n\n\
+                 Ссылка действительна {} минут и может быть использована один раз.\n\
+                 Если это были не вы, просто проигнорируйте это письмо.\n",
+                name, link, valid_minutes
+            ),
+        ),
+    };
+    Mail {
+        to: to.to_string(),
+        subject,
+        body,
+    }
+}
@@ -0,0 +1,79 @@
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use crate::mail::{Mail, MailError, Mailer};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SmtpSecurity {
+    /// Implicit TLS, usually port 465.
+    Tls,
+    /// Plain connection upgraded with `STARTTLS`, usually port 587.
+    StartTls,
+    /// No encryption, only for local relays.
+    None,
+}
+
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, MailError> {
+        let from = config
+            .from
+            .parse::<Mailbox>()
+            .map_err(|_| MailError::InvalidAddress(config.from.clone()))?;
+
+        let mut builder = match config.security {
+            SmtpSecurity::Tls => SmtpTransport::relay(&config.host),
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&config.host),
+            SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&config.host)),
+        }
+        .map_err(|e| MailError::Transport(e.to_string()))?;
+
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (config.username, config.password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let to = mail
+            .to
+            .parse::<Mailbox>()
+            .map_err(|_| MailError::InvalidAddress(mail.to.clone()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())
+            .map_err(|e| MailError::Transport(e.to_string()))?;
+
+        self.transport
+            .send(&message)
+            .map_err(|e| MailError::Transport(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -7,17 +7,29 @@ use actix_web::{App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use diesel::r2d2::ConnectionManager;
 
+use std::time::Duration;
+
+use crate::auth::scope::{scoped, Scope};
+use crate::auth::JwtConfig;
 use crate::db::{DbPool, DbService};
+use crate::error::ApiError;
+use crate::mail::Mailer;
+use crate::rate_limit::RouteGroup;
 
 mod auth;
 mod configuration;
 mod db;
 mod domain;
+mod error;
 mod handlers;
+mod i18n;
+mod join_code;
+mod mail;
+mod qr;
+mod rate_limit;
 
 #[macro_use]
 extern crate diesel_migrations;
-use diesel_migrations::{embed_migrations, EmbedMigrations};
 embed_migrations!();
 
 #[actix_web::main]
@@ -28,64 +40,246 @@ async fn main() -> std::io::Result<()> {
     let database_url = configuration::env_database_url();
     let host_url = configuration::env_host().unwrap_or_else(|| "127.0.0.1:8080".to_string());
     let jwt_config_data = Data::new(configuration::load_jwt_config());
+    let session_cache_data = Data::new(configuration::load_session_cache());
+    let signin_throttle_data = Data::new(configuration::load_signin_throttle());
+    let trusted_proxies_data = Data::new(configuration::load_trusted_proxies());
+    let rate_limiter_data = Data::new(configuration::load_rate_limiter());
+    let password_policy_data = Data::new(configuration::load_password_policy());
+    let password_hasher_data = Data::new(configuration::load_password_hasher());
+    let admin_config_data = Data::new(configuration::load_admin_config());
+    let email_link_config_data = Data::new(configuration::load_email_link_config());
+    let oidc_login_data = Data::new(configuration::load_oidc_login());
+    let mailer_data: Data<dyn Mailer> = Data::from(configuration::load_mailer());
 
     let db_pool = DbPool::new(ConnectionManager::new(database_url)).unwrap();
     // Apply migrations
     println!("Running migration...");
-    embedded_migrations::run_with_output(&db_pool.get().unwrap(), &mut std::io::stdout());
+    embedded_migrations::run_with_output(&db_pool.get().unwrap(), &mut std::io::stdout())
+        .expect("Failed to run migrations");
+
+    let db_pool_data = Data::new(db_pool.clone());
+    let db_service_data = Data::new(DbService::new(db_pool));
 
-    let db_service = DbService::new(db_pool.clone());
+    jwt_config_data
+        .keyring
+        .reload(&db_service_data)
+        .expect("Failed to load JWT keys");
+    if let Some(interval) = jwt_config_data.rotation_interval {
+        spawn_key_rotation(jwt_config_data.clone(), db_service_data.clone(), interval);
+    }
 
     HttpServer::new(move || {
         App::new()
+            .wrap_fn(i18n::localize_errors)
             .wrap(actix_web::middleware::Logger::default())
             // data
             .app_data(jwt_config_data.clone())
-            .data(db_pool.clone())
-            .data(db_service.clone())
+            .app_data(session_cache_data.clone())
+            .app_data(signin_throttle_data.clone())
+            .app_data(trusted_proxies_data.clone())
+            .app_data(rate_limiter_data.clone())
+            .app_data(password_policy_data.clone())
+            .app_data(password_hasher_data.clone())
+            .app_data(admin_config_data.clone())
+            .app_data(email_link_config_data.clone())
+            .app_data(oidc_login_data.clone())
+            .app_data(mailer_data.clone())
+            .app_data(db_pool_data.clone())
+            .app_data(db_service_data.clone())
+            .app_data(web::JsonConfig::default().error_handler(|e, _req| {
+                ApiError::InvalidRequest(e.to_string()).into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|e, _req| {
+                ApiError::InvalidRequest(e.to_string()).into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|e, _req| {
+                ApiError::InvalidRequest(e.to_string()).into()
+            }))
             // routes
             .configure(configure_routes)
             .route("/ping", web::get().to(handlers::ping))
+            .route("/.well-known/jwks.json", web::get().to(handlers::jwks))
     })
     .bind(&host_url)?
     .run()
     .await
 }
 
+fn spawn_key_rotation(jwt_config: Data<JwtConfig>, db: Data<DbService>, interval: Duration) {
+    let check_every = interval.min(Duration::from_secs(60));
+    actix_rt::spawn(async move {
+        let mut timer = actix_rt::time::interval(check_every);
+        loop {
+            timer.tick().await;
+            let result = jwt_config.keyring.maintain(
+                &db,
+                jwt_config.algorithm,
+                interval,
+                jwt_config.key_grace_period(),
+            );
+            if let Err(e) = result {
+                log::error!("JWT key rotation failed: {:?}", e);
+            }
+        }
+    });
+}
+
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
+        web::scope("/admin")
+            .wrap(HttpAuthentication::bearer(crate::auth::admin_validator))
+            .route("/keys", web::get().to(handlers::admin_keys))
+            .route("/keys/rotate", web::post().to(handlers::admin_keys_rotate)),
+    )
+    .service(
         web::scope("/auth")
+            .wrap_fn(|req, srv| rate_limit::limit_requests(RouteGroup::Auth, req, srv))
             .route("/signup", web::post().to(handlers::sign_up))
-            .route("/signin", web::post().to(handlers::sign_in)),
+            .route("/signin", web::post().to(handlers::sign_in))
+            .route("/refresh", web::post().to(handlers::refresh))
+            .route("/password/forgot", web::post().to(handlers::password_forgot))
+            .route("/password/reset", web::post().to(handlers::password_reset))
+            .route("/email/verify", web::post().to(handlers::email_verify))
+            .route("/oidc/{provider}", web::get().to(handlers::oidc_start))
+            .route("/oidc/{provider}/callback", web::post().to(handlers::oidc_callback)),
+    )
+    .service(
+        web::scope("/guest")
+            .wrap_fn(|req, srv| {
+                let group = RouteGroup::api(req.method());
+                rate_limit::limit_requests(group, req, srv)
+            })
+            .route("/queues/{queue_id}", web::post().to(handlers::guest_join))
+            .service(
+                web::scope("/me")
+                    .wrap(HttpAuthentication::bearer(crate::auth::guest::ticket_validator))
+                    .route("", web::get().to(handlers::guest_me))
+                    .route("", web::delete().to(handlers::guest_leave)),
+            ),
     )
     .service(
         web::scope("/api")
+            // Runs after authentication to limit users rather than addresses
+            .wrap_fn(|req, srv| {
+                let group = RouteGroup::api(req.method());
+                rate_limit::limit_requests(group, req, srv)
+            })
             .wrap(HttpAuthentication::bearer(crate::auth::bearer_validator))
-            .route("/users/me", web::get().to(handlers::me))
-            .route("/users/{user_id}", web::get().to(handlers::user))
-            .route("/queues", web::post().to(handlers::queue_create))
-            .route("/queues", web::get().to(handlers::queues))
-            .route("/queues/{queue_id}", web::delete().to(handlers::queue_delete))
-            .route("/queues/{queue_id}", web::get().to(handlers::queue_get_info))
+            .route("/users/me", scoped(web::get(), Scope::ProfileRead, handlers::me))
+            .route("/users/me", scoped(web::delete(), Scope::Account, handlers::me_delete))
+            .route("/users/me/export", scoped(web::get(), Scope::Account, handlers::me_export))
+            .route(
+                "/users/me/locale",
+                scoped(web::put(), Scope::ProfileWrite, handlers::me_set_locale),
+            )
+            .route("/users/me/name", scoped(web::put(), Scope::ProfileWrite, handlers::me_set_name))
+            .route(
+                "/users/me/username",
+                scoped(web::put(), Scope::ProfileWrite, handlers::me_set_username),
+            )
+            .route(
+                "/users/me/password",
+                scoped(web::put(), Scope::Account, handlers::me_set_password),
+            )
+            .route("/users/me/email", scoped(web::put(), Scope::Account, handlers::me_set_email))
+            .route(
+                "/users/me/email/verification", // Send the link again
+                scoped(web::post(), Scope::Account, handlers::me_email_verification),
+            )
+            .route("/users/me/sessions", scoped(web::get(), Scope::Account, handlers::sessions))
+            .route(
+                "/users/me/sessions",
+                scoped(web::delete(), Scope::Account, handlers::sessions_revoke_all),
+            )
+            .route(
+                "/users/me/sessions/current", // Sign out
+                scoped(web::delete(), Scope::Account, handlers::session_revoke_current),
+            )
+            .route(
+                "/users/me/sessions/{session_id}",
+                scoped(web::delete(), Scope::Account, handlers::session_revoke),
+            )
+            .route("/users/me/tokens", scoped(web::get(), Scope::Account, handlers::api_tokens))
+            .route(
+                "/users/me/tokens",
+                scoped(web::post(), Scope::Account, handlers::api_token_create),
+            )
+            .route(
+                "/users/me/tokens/{token_id}",
+                scoped(web::delete(), Scope::Account, handlers::api_token_revoke),
+            )
+            .route("/users/{user_id}", scoped(web::get(), Scope::QueuesRead, handlers::user))
+            .route("/queues", scoped(web::post(), Scope::QueuesWrite, handlers::queue_create))
+            .route("/queues", scoped(web::get(), Scope::QueuesRead, handlers::queues))
+            // before `/queues/{queue_id}`, which would take `search` for an id
+            .route(
+                "/queues/search",
+                scoped(web::get(), Scope::QueuesRead, handlers::queues_search),
+            )
+            .route(
+                "/queues/{queue_id}",
+                scoped(web::delete(), Scope::QueuesWrite, handlers::queue_delete),
+            )
+            .route(
+                "/queues/{queue_id}",
+                scoped(web::get(), Scope::QueuesRead, handlers::queue_get_info),
+            )
+            .route(
+                "/queues/{queue_id}",
+                scoped(web::patch(), Scope::QueuesWrite, handlers::queue_update_settings),
+            )
+            .route(
+                "/queues/{queue_id}/join-code",
+                scoped(web::get(), Scope::QueuesWrite, handlers::queue_join_code),
+            )
+            .route(
+                "/queues/{queue_id}/join-code",
+                scoped(web::post(), Scope::QueuesWrite, handlers::queue_join_code_create),
+            )
+            .route(
+                "/queues/{queue_id}/join-code",
+                scoped(web::delete(), Scope::QueuesWrite, handlers::queue_join_code_delete),
+            )
+            .route(
+                "/queues/{queue_id}/qr.png",
+                scoped(web::get(), Scope::QueuesWrite, handlers::queue_qr_png),
+            )
+            .route(
+                "/queues/{queue_id}/qr.svg",
+                scoped(web::get(), Scope::QueuesWrite, handlers::queue_qr_svg),
+            )
+            .route(
+                "/queues/{queue_id}/join-requests",
+                scoped(web::get(), Scope::MembersManage, handlers::join_requests),
+            )
+            .route(
+                "/queues/{queue_id}/join-requests/{request_id}/approve",
+                scoped(web::post(), Scope::MembersManage, handlers::join_request_approve),
+            )
+            .route(
+                "/queues/{queue_id}/join-requests/{request_id}/reject",
+                scoped(web::post(), Scope::MembersManage, handlers::join_request_reject),
+            )
+            .route("/join/{code}", scoped(web::post(), Scope::QueuesWrite, handlers::join_by_code))
             .route(
                 "/queues/{queue_id}/members",
-                web::get().to(handlers::queue_members),
+                scoped(web::get(), Scope::QueuesRead, handlers::queue_members),
             )
             .route(
                 "/queues/{queue_id}/members/me", // Join ME
-                web::post().to(handlers::queue_add_member_me),
+                scoped(web::post(), Scope::QueuesWrite, handlers::queue_add_member_me),
             )
             .route(
                 "/queues/{queue_id}/members/{member_id}",
-                web::post().to(handlers::queue_add_member),
+                scoped(web::post(), Scope::MembersManage, handlers::queue_add_member),
             )
             .route(
                 "/queues/{queue_id}/members/me", // Remove ME
-                web::delete().to(handlers::queue_remove_member_me),
+                scoped(web::delete(), Scope::QueuesWrite, handlers::queue_remove_member_me),
             )
             .route(
                 "/queues/{queue_id}/members/{member_id}",
-                web::delete().to(handlers::queue_remove_member),
+                scoped(web::delete(), Scope::MembersManage, handlers::queue_remove_member),
             ),
     );
 }
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat, Luma};
+use qrcode::render::svg;
+use qrcode::types::QrError;
+use qrcode::{EcLevel, QrCode};
+use serde::{Deserialize, Serialize};
+
+/// Sizes of an image in pixels, including the quiet zone. The image is at
+/// most as large as asked, modules are whole pixels.
+pub const DEFAULT_SIZE: u32 = 256;
+pub const MIN_SIZE: u32 = 64;
+pub const MAX_SIZE: u32 = 2048;
+
+/// Share of the code that can be damaged and still be read.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum ErrorCorrection {
+    /// 7%
+    L,
+    /// 15%
+    #[default]
+    M,
+    /// 25%
+    Q,
+    /// 30%, leaves room for a logo over the middle.
+    H,
+}
+
+impl From<ErrorCorrection> for EcLevel {
+    fn from(ec: ErrorCorrection) -> Self {
+        match ec {
+            ErrorCorrection::L => EcLevel::L,
+            ErrorCorrection::M => EcLevel::M,
+            ErrorCorrection::Q => EcLevel::Q,
+            ErrorCorrection::H => EcLevel::H,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Encode(QrError),
+    Image(image::ImageError),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Encode(e) => write!(f, "encode: {}", e),
+            RenderError::Image(e) => write!(f, "image: {}", e),
+        }
+    }
+}
+
+fn encode(data: &str, ec: ErrorCorrection) -> Result<QrCode, RenderError> {
+    QrCode::with_error_correction_level(data, ec.into()).map_err(RenderError::Encode)
+}
+
+/// Grayscale PNG of `data`.
+pub fn png(data: &str, size: u32, ec: ErrorCorrection) -> Result<Vec<u8>, RenderError> {
+    let image = encode(data, ec)?
+        .render::<Luma<u8>>()
+        .max_dimensions(size, size)
+        .build();
+    let mut bytes = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(RenderError::Image)?;
+    Ok(bytes)
+}
+
+/// SVG document of `data`.
+pub fn svg(data: &str, size: u32, ec: ErrorCorrection) -> Result<String, RenderError> {
+    let image = encode(data, ec)?
+        .render::<svg::Color>()
+        .max_dimensions(size, size)
+        .build();
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GrayImage;
+
+    use super::*;
+
+    const LINK: &str = "https://oqueue.example.com/join/K7F-29Q";
+
+    fn read(image: GrayImage) -> String {
+        let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+            image.width() as usize,
+            image.height() as usize,
+            |x, y| image.get_pixel(x as u32, y as u32).0[0],
+        );
+        let grids = prepared.detect_grids();
+        assert_eq!(grids.len(), 1);
+        grids[0].decode().unwrap().1
+    }
+
+    /// Paints the rectangles of the SVG path, `M{left} {top}h{w}v{h}H{left}V{top}`.
+    fn rasterize(svg: &str, size: u32) -> GrayImage {
+        let mut image = GrayImage::from_pixel(size, size, Luma([255]));
+        let path = svg.split(" d=\"").nth(1).unwrap().split('"').next().unwrap();
+        for rect in path.split('M').filter(|r| !r.is_empty()) {
+            let numbers = rect
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.parse::<u32>().unwrap())
+                .collect::<Vec<_>>();
+            let (left, top, width, height) = (numbers[0], numbers[1], numbers[2], numbers[3]);
+            for y in top..top + height {
+                for x in left..left + width {
+                    image.put_pixel(x, y, Luma([0]));
+                }
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn png_encodes_data() {
+        for ec in [ErrorCorrection::L, ErrorCorrection::H] {
+            let png = png(LINK, DEFAULT_SIZE, ec).unwrap();
+            let image = image::load_from_memory(&png).unwrap().to_luma8();
+            assert!(image.width() <= DEFAULT_SIZE && image.width() == image.height());
+            assert_eq!(read(image), LINK);
+        }
+    }
+
+    #[test]
+    fn svg_encodes_data() {
+        let svg = svg(LINK, MIN_SIZE * 2, ErrorCorrection::M).unwrap();
+        let size = svg
+            .split("width=\"")
+            .nth(1)
+            .and_then(|s| s.split('"').next())
+            .unwrap()
+            .parse::<u32>()
+            .unwrap();
+        assert!(size <= MIN_SIZE * 2);
+        assert_eq!(read(rasterize(&svg, size)), LINK);
+    }
+}
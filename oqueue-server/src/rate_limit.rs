@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::web::Data;
+use actix_web::{Error, HttpMessage};
+
+use crate::auth::Auth;
+use crate::error::ApiError;
+use crate::handlers::{forwarded_client_ip, TrustedProxies};
+
+/// Upper bound of remembered buckets before refilled ones are swept.
+const MAX_ENTRIES: usize = 100_000;
+
+// ----------
+// RouteGroup
+// ----------
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum RouteGroup {
+    Auth,
+    ApiRead,
+    ApiWrite,
+}
+
+impl RouteGroup {
+    /// Requests to `/api` that don't change anything are limited separately.
+    pub fn api(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => RouteGroup::ApiRead,
+            _ => RouteGroup::ApiWrite,
+        }
+    }
+}
+
+// -----------
+// RateLimiter
+// -----------
+
+/// Token bucket: holds up to `burst` requests and refills completely in
+/// `period`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Limit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    fn per_sec(&self) -> f64 {
+        f64::from(self.burst) / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// State of a bucket after a request, rendered as `RateLimit-*` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub limit: Limit,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Set when the request was rejected.
+    pub retry_after: Option<Duration>,
+}
+
+/// Limits requests per client and route group, clients are users when the
+/// request is authenticated and addresses otherwise.
+pub struct RateLimiter {
+    limits: HashMap<RouteGroup, Limit>,
+    buckets: Mutex<HashMap<(RouteGroup, String), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Groups without a limit are not limited.
+    pub fn new(limits: HashMap<RouteGroup, Limit>) -> Self {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket of the group.
+    pub fn take(&self, group: RouteGroup, client: &str, now: Instant) -> Option<Decision> {
+        let limit = *self.limits.get(&group)?;
+        let per_sec = limit.per_sec();
+        let burst = f64::from(limit.burst);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_ENTRIES {
+            let limits = &self.limits;
+            buckets.retain(|(group, _), b| match limits.get(group) {
+                Some(limit) => now.duration_since(b.updated) < limit.period,
+                None => false,
+            });
+        }
+        let bucket = buckets
+            .entry((group, client.to_string()))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.updated = now;
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        };
+        Some(Decision {
+            limit,
+            remaining: bucket.tokens.floor() as u32,
+            reset: Duration::from_secs_f64((burst - bucket.tokens) / per_sec),
+            retry_after,
+        })
+    }
+}
+
+// ----------
+// Middleware
+// ----------
+
+/// Signed in users are limited by account, others by the peer address or
+/// the one forwarded by a trusted proxy.
+fn client_key(req: &ServiceRequest) -> Option<String> {
+    if let Some(auth) = req.extensions().get::<Auth>() {
+        return Some(format!("user:{}", auth.id));
+    }
+    let proxies = req.app_data::<Data<TrustedProxies>>().map(|p| p.get_ref());
+    let ip = forwarded_client_ip(req.peer_addr(), req.headers(), proxies)?;
+    Some(format!("ip:{}", ip))
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+fn insert_headers(res: &mut ServiceResponse, decision: &Decision) {
+    let headers = [
+        ("ratelimit-limit", decision.limit.burst.to_string()),
+        ("ratelimit-remaining", decision.remaining.to_string()),
+        ("ratelimit-reset", ceil_secs(decision.reset).to_string()),
+        (
+            "ratelimit-policy",
+            format!("{};w={}", decision.limit.burst, decision.limit.period.as_secs()),
+        ),
+    ];
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Rejects requests over the limit of `group` with `429 Too Many Requests`
+/// and adds `RateLimit-*` headers to every limited response. In scopes with
+/// authentication it has to be wrapped by it, so the user is known.
+pub fn limit_requests<S>(
+    group: RouteGroup,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let decision = match (req.app_data::<Data<RateLimiter>>(), client_key(&req)) {
+        (Some(limiter), Some(key)) => limiter.take(group, &key, Instant::now()),
+        _ => None,
+    };
+    let call = match decision.and_then(|d| d.retry_after) {
+        Some(wait) => Err((req, wait)),
+        None => Ok(srv.call(req)),
+    };
+    async move {
+        let mut res = match call {
+            Ok(fut) => fut.await?,
+            Err((req, wait)) => req.error_response(ApiError::RateLimited {
+                retry_after: ceil_secs(wait),
+            }),
+        };
+        if let Some(decision) = &decision {
+            insert_headers(&mut res, decision);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    /// One request a second, three at once.
+    const LIMIT: Limit = Limit {
+        burst: 3,
+        period: Duration::from_secs(3),
+    };
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(HashMap::from([(RouteGroup::ApiWrite, LIMIT)]))
+    }
+
+    #[test]
+    fn allows_burst_then_rejects() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for remaining in [2, 1, 0] {
+            let decision = limiter.take(RouteGroup::ApiWrite, "ip:a", now).unwrap();
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.retry_after, None);
+        }
+        let decision = limiter.take(RouteGroup::ApiWrite, "ip:a", now).unwrap();
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(decision.reset, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.take(RouteGroup::ApiWrite, "ip:a", now).unwrap();
+        }
+        let half = limiter
+            .take(RouteGroup::ApiWrite, "ip:a", now + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(half.retry_after, Some(Duration::from_millis(500)));
+        let refilled = limiter
+            .take(RouteGroup::ApiWrite, "ip:a", now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(refilled.retry_after, None);
+        // Never more than the burst, however long the client waited
+        let full = limiter
+            .take(RouteGroup::ApiWrite, "ip:a", now + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(full.remaining, 2);
+    }
+
+    #[test]
+    fn keeps_buckets_per_client_and_group() {
+        let limiter = RateLimiter::new(HashMap::from([
+            (RouteGroup::ApiWrite, LIMIT),
+            (RouteGroup::ApiRead, LIMIT),
+        ]));
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.take(RouteGroup::ApiWrite, "ip:a", now).unwrap();
+        }
+        let other_client = limiter.take(RouteGroup::ApiWrite, "ip:b", now).unwrap();
+        assert_eq!(other_client.retry_after, None);
+        let other_group = limiter.take(RouteGroup::ApiRead, "ip:a", now).unwrap();
+        assert_eq!(other_group.retry_after, None);
+        assert!(limiter.take(RouteGroup::Auth, "ip:a", now).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_with_retry_after() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(RateLimiter::new(HashMap::from([(
+                    RouteGroup::Auth,
+                    Limit {
+                        burst: 1,
+                        period: Duration::from_secs(10),
+                    },
+                )]))))
+                .wrap_fn(|req, srv| limit_requests(RouteGroup::Auth, req, srv))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::post()
+                .uri("/")
+                .peer_addr("192.0.2.1:4000".parse().unwrap())
+                .to_request()
+        };
+
+        let res = test::call_service(&app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+        assert_eq!(res.headers().get("ratelimit-policy").unwrap(), "1;w=10");
+
+        let res = test::call_service(&app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = res.headers().get(RETRY_AFTER).unwrap().to_str().unwrap();
+        assert!(matches!(retry_after, "9" | "10"), "{}", retry_after);
+    }
+}